@@ -10,11 +10,11 @@ macro_rules! alert {
 }
 
 pub(crate) fn alert(args: std::fmt::Arguments) {
-    let alert = unsafe { JsFn::new("alert(param_a);") };
+    let alert = JsFn::global("alert").unwrap();
 
     let message = JsString::new(&format!("{}", args));
 
     unsafe {
-        assert!(alert.call(Some(message.as_var()), None).is_none());
+        assert!(alert.call(&[message.as_var()]).unwrap().is_none());
     }
 }
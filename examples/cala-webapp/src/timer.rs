@@ -1,9 +1,9 @@
 use std::{pin::Pin, task::{Poll, Context}, future::Future};
-use cala_core::os::web::{JsFn, JsVar};
+use cala_core::os::web::{JsFn, JsPromise, JsVar};
 
 /// A JavaScript timer future that resolves to a predetermined value.
 pub(crate) struct JsTimer {
-    promise: JsVar,
+    promise: JsPromise<i32>,
 }
 
 impl JsTimer {
@@ -11,11 +11,11 @@ impl JsTimer {
         let promise = unsafe {
             // Create a function
             let timeout = 
-                JsFn::new("return new Promise(function(res, rej) {\
-                    setTimeout(function() { res(param_a); }, param_b);
+                JsFn::new(&["value", "ms"], "return new Promise(function(res, rej) {\
+                    setTimeout(function() { res(value); }, ms);
                 });");
             // Call the function
-            timeout.call(Some(&JsVar::from_i32(res)), Some(&JsVar::from_i32(ms))).unwrap()
+            timeout.call(&[&JsVar::from_i32(res), &JsVar::from_i32(ms)]).unwrap().unwrap().into_promise()
         };
         JsTimer {
             promise
@@ -26,12 +26,7 @@ impl JsTimer {
 impl Future for JsTimer {
     type Output = i32;
 
-    fn poll(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<i32> {
-        if let Poll::Ready(result) = self.promise.poll() {
-            Poll::Ready(unsafe { result.into_i32() })
-        } else {
-            unsafe { self.promise.set_waker() };
-            Poll::Pending
-        }
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<i32> {
+        Pin::new(&mut self.promise).poll(cx).map(|result| result.unwrap())
     }
 }
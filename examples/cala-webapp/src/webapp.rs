@@ -1,6 +1,6 @@
 #![deny(unsafe_code)]
 
-cala_core::exec!(webapp);
+cala_core::start!();
 
 #[allow(unsafe_code)]
 mod timer;
@@ -10,7 +10,7 @@ mod alert;
 
 use timer::JsTimer;
 
-async fn webapp() {
+async fn start() {
     alert!("Hello, world!");
     let result = JsTimer::new(42, 750).await;
     alert!("Waited 3/4 a second to get: {}", result);
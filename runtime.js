@@ -41,15 +41,54 @@ function _cala_js_write_doubles(j,p,l) {
 function _cala_js_function(i) {
     return _cala_js_malloc(Function(_cala_heap[i])());
 }
-function _cala_js_call(f, a, b) {
-    let o = _cala_heap[f](_cala_heap[a], _cala_heap[b]);
+var _cala_thrown = undefined;
+function _cala_js_call(f, t, p, l) {
+    var buf = new Int32Array(Module.instance.exports.memory.buffer,p,l);
+    var args = new Array(l);
+    for(var i = 0; i < l; i++) { args[i] = _cala_heap[buf[i]]; }
+    try {
+        var o = _cala_heap[f].apply(_cala_heap[t], args);
+    } catch(e) {
+        _cala_thrown = e;
+        return -2;
+    }
     if(o == undefined) {
         return -1;
     } else {
         return _cala_js_malloc(o);
     }
 }
+function _cala_js_thrown() {
+    var e = _cala_thrown;
+    _cala_thrown = undefined;
+    return _cala_js_malloc(e);
+}
+function _cala_js_error(j,k) {
+    var e = _cala_heap[j];
+    var s = undefined;
+    if(e instanceof Object) {
+        s = [e.name, e.message, e.stack][k];
+    } else if(k == 1) {
+        s = e;
+    }
+    if(s == undefined) { return -1; }
+    return _cala_js_malloc(String(s));
+}
+function _cala_js_global(j) {
+    var o = globalThis;
+    var parent = undefined;
+    var path = _cala_heap[j].split(".");
+    for(var i = 0; i < path.length; i++) {
+        if(o == undefined) { return -1; }
+        parent = o;
+        o = o[path[i]];
+    }
+    if(typeof o != "function") { return -1; }
+    if(parent != globalThis) { o = o.bind(parent); }
+    return _cala_js_malloc(o);
+}
 function _cala_js_free(i) { return _cala_garbage.push(i); }
+function _cala_js_clone(i) { return _cala_js_malloc(_cala_heap[i]); }
 function _cala_js_read_text(j,p,l) {
     var buf = new Uint16Array(Module.instance.exports.memory.buffer,p,l);
     let get = _cala_heap[j];
@@ -66,7 +105,9 @@ function _cala_js_read_copy(buf,get,len) {
 }
 function _cala_js_read_bytes(j,p,l) {
     var buf = new Uint8Array(Module.instance.exports.memory.buffer,p,l);
-    return _cala_js_read_copy(buf,_cala_heap[j],l);
+    var get = _cala_heap[j];
+    if(get instanceof ArrayBuffer) { get = new Uint8Array(get); }
+    return _cala_js_read_copy(buf,get,l);
 }
 function _cala_js_read_ints(j, p, l) {
     var buf = new Int32Array(Module.instance.exports.memory.buffer,p,l);
@@ -80,18 +121,346 @@ function _cala_js_read_doubles(j, p, l) {
     var buf = new Float64Array(Module.instance.exports.memory.buffer,p,l);
     return _cala_js_read_copy(buf,_cala_heap[j],l);
 }
+var _cala_wakers = new Object();
 function _cala_js_waker(j) {
+    var waker = { live: true };
+    _cala_wakers[j] = waker;
     _cala_heap[j].then((o) => {
-        if(o == undefined || o == null) {
-            Module.instance.exports.wake(j, -1);
-        } else {
-            Module.instance.exports.wake(j, _cala_js_malloc(o))
-        }
+        if(!waker.live) { return; }
+        delete _cala_wakers[j];
+        Module.instance.exports._cala_promise_resolved(j, _cala_js_malloc(o));
+    }, (e) => {
+        if(!waker.live) { return; }
+        delete _cala_wakers[j];
+        Module.instance.exports._cala_promise_rejected(j, _cala_js_malloc(e));
     });
 }
+function _cala_js_cancel(j) {
+    if(_cala_wakers[j] != undefined) {
+        _cala_wakers[j].live = false;
+        delete _cala_wakers[j];
+    }
+}
 function _cala_js_store_int(o) { return _cala_js_malloc(o); }
 function _cala_js_load_int(o) { return _cala_heap[o]; }
 function _cala_js_store_float(o) { return _cala_js_malloc(o); }
 function _cala_js_load_float(o) { return _cala_heap[o]; }
 function _cala_js_store_double(o) { return _cala_js_malloc(o); }
 function _cala_js_load_double(o) { return _cala_heap[o]; }
+function _cala_js_store_bool(o) { return _cala_js_malloc(o != 0); }
+function _cala_js_load_bool(o) { return _cala_heap[o] ? 1 : 0; }
+function _cala_js_null() { return _cala_js_malloc(null); }
+function _cala_js_typeof(j) {
+    var o = _cala_heap[j];
+    switch(typeof o) {
+        case "undefined": return 0;
+        case "boolean": return 2;
+        case "number": return 3;
+        case "bigint": return 4;
+        case "string": return 5;
+        case "symbol": return 6;
+        case "function": return 7;
+    }
+    if(o === null) { return 1; }
+    if(Array.isArray(o)) { return 8; }
+    if(ArrayBuffer.isView(o) || o instanceof ArrayBuffer) { return 9; }
+    return 10;
+}
+function _cala_js_length(j) { return _cala_heap[j].length >>> 0; }
+function _cala_js_index(j,i) { return _cala_js_malloc(_cala_heap[j][i]); }
+function _cala_js_bytes(p,l) {
+    var buf = new Uint8Array(Module.instance.exports.memory.buffer,p,l);
+    return _cala_js_malloc(buf.slice());
+}
+function _cala_js_array(p,l) {
+    var buf = new Int32Array(Module.instance.exports.memory.buffer,p,l);
+    var array = new Array(l);
+    for(var i = 0; i < l; i++) { array[i] = _cala_heap[buf[i]]; }
+    return _cala_js_malloc(array);
+}
+var _cala_timeouts = new Object();
+function _cala_now() { return performance.now(); }
+function _cala_set_timeout(id, ms) {
+    _cala_timeouts[id] = setTimeout(() => {
+        delete _cala_timeouts[id];
+        Module.instance.exports._cala_timer_fired(id);
+    }, ms);
+}
+function _cala_clear_timeout(id) {
+    clearTimeout(_cala_timeouts[id]);
+    delete _cala_timeouts[id];
+}
+function _cala_js_log(level,p,l) {
+    var buf = new Uint8Array(Module.instance.exports.memory.buffer,p,l);
+    var text = new TextDecoder().decode(buf);
+    switch(level) {
+        case 1: console.error(text); break;
+        case 2: console.warn(text); break;
+        case 3: console.info(text); break;
+        default: console.debug(text); break;
+    }
+}
+var _cala_fetches = new Object();
+function _cala_fetch(id,p,l,bp,bl) {
+    var mem = Module.instance.exports.memory.buffer;
+    var lines = new TextDecoder().decode(new Uint8Array(mem,p,l)).split("\n");
+    var headers = new Headers();
+    for(var i = 2; i < lines.length; i++) {
+        var at = lines[i].indexOf(":");
+        if(at != -1) {
+            headers.append(lines[i].slice(0, at), lines[i].slice(at + 1));
+        }
+    }
+    var init = { method: lines[0], headers: headers };
+    if(bl != 0) { init.body = new Uint8Array(mem,bp,bl).slice(); }
+    var controller = new AbortController();
+    init.signal = controller.signal;
+    var done = function(status, head, body) {
+        if(_cala_fetches[id] === undefined) { return; }
+        head = new TextEncoder().encode(head);
+        _cala_fetches[id] = { head: head, body: body };
+        Module.instance.exports._cala_fetch_done(id, status, head.length,
+            body.length);
+    };
+    _cala_fetches[id] = { controller: controller };
+    fetch(lines[1], init).then((r) => {
+        return r.arrayBuffer().then((b) => {
+            var head = "";
+            r.headers.forEach((v, k) => { head += k + ": " + v + "\n"; });
+            done(r.status, head, new Uint8Array(b));
+        });
+    }).catch((e) => { done(0, String(e), new Uint8Array(0)); });
+}
+function _cala_fetch_read(id,hp,bp) {
+    var f = _cala_fetches[id];
+    delete _cala_fetches[id];
+    var mem = Module.instance.exports.memory.buffer;
+    new Uint8Array(mem,hp,f.head.length).set(f.head);
+    new Uint8Array(mem,bp,f.body.length).set(f.body);
+}
+function _cala_fetch_abort(id) {
+    var f = _cala_fetches[id];
+    delete _cala_fetches[id];
+    if(f !== undefined && f.controller !== undefined) { f.controller.abort(); }
+}
+function _cala_ws_open(p,l) {
+    var mem = Module.instance.exports.memory.buffer;
+    var url = new TextDecoder().decode(new Uint8Array(mem,p,l));
+    var ws;
+    try { ws = new WebSocket(url); } catch(e) { return -1; }
+    ws.binaryType = "arraybuffer";
+    ws._cala_queue = [];
+    var slot = _cala_js_malloc(ws);
+    var wake = function() {
+        // The slot may have been freed and reused.
+        if(_cala_heap[slot] === ws) {
+            Module.instance.exports._cala_ws_wake(slot);
+        }
+    };
+    ws.onopen = wake;
+    ws.onmessage = function(e) {
+        if(typeof e.data === "string") {
+            ws._cala_queue.push([1, new TextEncoder().encode(e.data)]);
+        } else {
+            ws._cala_queue.push([2, new Uint8Array(e.data)]);
+        }
+        wake();
+    };
+    ws.onclose = function() {
+        ws._cala_queue.push([8, new Uint8Array(0)]);
+        wake();
+    };
+    return slot;
+}
+function _cala_ws_state(slot) { return _cala_heap[slot].readyState; }
+function _cala_ws_send(slot,kind,p,l) {
+    var ws = _cala_heap[slot];
+    if(ws.readyState != 1) { return -1; }
+    var data = new Uint8Array(Module.instance.exports.memory.buffer,p,l);
+    if(kind == 1) { ws.send(new TextDecoder().decode(data)); }
+    else { ws.send(data.slice()); }
+    return 0;
+}
+function _cala_ws_next(slot) {
+    var queue = _cala_heap[slot]._cala_queue;
+    if(queue.length == 0) { return -1; }
+    return queue[0][1].length;
+}
+function _cala_ws_read(slot,p) {
+    var message = _cala_heap[slot]._cala_queue.shift();
+    var mem = Module.instance.exports.memory.buffer;
+    new Uint8Array(mem,p,message[1].length).set(message[1]);
+    return message[0];
+}
+function _cala_ws_close(slot) { _cala_heap[slot].close(); }
+function _cala_keyboard_listen() {
+    var send = function(e, pressed) {
+        var modifiers = (e.shiftKey ? 1 : 0) | (e.ctrlKey ? 2 : 0)
+            | (e.altKey ? 4 : 0) | (e.metaKey ? 8 : 0);
+        Module.instance.exports._cala_keyboard_event(_cala_js_malloc(
+            [e.code, e.key, pressed, e.repeat, modifiers]
+        ));
+    };
+    window.addEventListener("keydown", function(e) { send(e, true); });
+    window.addEventListener("keyup", function(e) { send(e, false); });
+}
+
+function _cala_text_listen() {
+    var send = function(kind, text) {
+        Module.instance.exports._cala_text_event(kind, _cala_js_malloc(text));
+    };
+    window.addEventListener("beforeinput", function(e) {
+        // Composed text is sent on `compositionend` instead.
+        if (!e.isComposing && (e.inputType == "insertText"
+            || e.inputType == "insertReplacementText") && e.data !== null)
+        {
+            send(0, e.data);
+        }
+    });
+    window.addEventListener("compositionstart", function(e) { send(1, ""); });
+    window.addEventListener("compositionupdate", function(e) {
+        send(2, e.data);
+    });
+    window.addEventListener("compositionend", function(e) {
+        send(3, "");
+        if (e.data) {
+            send(0, e.data);
+        }
+    });
+}
+
+function _cala_mouse_listen() {
+    var send = function(kind, a, b, c, d) {
+        Module.instance.exports._cala_mouse_event(_cala_js_malloc(
+            [kind, a || 0, b || 0, c || 0, d || 0]
+        ));
+    };
+    // Touches are sent to the touch input instead.
+    var mouse = function(e) { return e.pointerType != "touch"; };
+    // `buttons` bit for each `button` (left, middle, right, back, forward).
+    var bits = [1, 4, 2, 8, 16];
+    var button = function(e) {
+        var bit = e.button < bits.length ? bits[e.button] : 1 << e.button;
+        send((e.buttons & bit) ? 1 : 2, e.button);
+    };
+    window.addEventListener("pointermove", function(e) {
+        if (!mouse(e)) { return; }
+        if (e.movementX || e.movementY || !document.pointerLockElement) {
+            send(0, e.clientX, e.clientY, e.movementX, e.movementY);
+        }
+        // Pressing or releasing a button while another is held down.
+        if (e.button >= 0) { button(e); }
+    });
+    window.addEventListener("pointerdown", function(e) {
+        if (mouse(e)) { button(e); }
+    });
+    window.addEventListener("pointerup", function(e) {
+        if (mouse(e)) { button(e); }
+    });
+    window.addEventListener("wheel", function(e) {
+        send(3, e.deltaMode, e.deltaX, e.deltaY);
+    });
+    var root = document.documentElement;
+    root.addEventListener("pointerenter", function(e) {
+        if (mouse(e)) { send(4); }
+    });
+    root.addEventListener("pointerleave", function(e) {
+        if (mouse(e)) { send(5); }
+    });
+    document.addEventListener("pointerlockchange", function(e) {
+        send(6, document.pointerLockElement ? 1 : 0);
+    });
+}
+function _cala_mouse_lock(locked) {
+    if (locked) {
+        document.documentElement.requestPointerLock();
+    } else {
+        document.exitPointerLock();
+    }
+}
+function _cala_mouse_cursor(visible) {
+    document.documentElement.style.cursor = visible ? "" : "none";
+}
+
+function _cala_touch_listen() {
+    // Phases are 0 to 3 for begin, move, end and cancel.
+    var listen = function(type, phase) {
+        window.addEventListener(type, function(e) {
+            if (e.pointerType == "touch") {
+                Module.instance.exports._cala_touch_event(_cala_js_malloc(
+                    [phase, e.pointerId, e.clientX, e.clientY, e.pressure]
+                ));
+            }
+        });
+    };
+    listen("pointerdown", 0);
+    listen("pointermove", 1);
+    listen("pointerup", 2);
+    listen("pointercancel", 3);
+}
+
+// Last state of each gamepad, by index.
+var _cala_pads = [];
+function _cala_pad_listen() {
+    var send = function(kind, index, code, value, name) {
+        Module.instance.exports._cala_pad_event(_cala_js_malloc(
+            [kind, index, code || 0, value || 0, name || ""]
+        ));
+    };
+    // The Gamepad API doesn't have events for buttons and axes.
+    var poll = function() {
+        var pads = navigator.getGamepads ? navigator.getGamepads() : [];
+        for (var i = 0; i < Math.max(pads.length, _cala_pads.length); i++) {
+            var pad = pads[i] && pads[i].connected ? pads[i] : null;
+            var last = _cala_pads[i];
+            if (!pad) {
+                if (last) {
+                    _cala_pads[i] = null;
+                    send(1, i);
+                }
+                continue;
+            }
+            // Starts at rest, so only buttons and axes that aren't get sent.
+            if (!last) {
+                last = _cala_pads[i] = { buttons: [], axes: [0, 0, 0, 0] };
+                for (var b = 0; b < pad.buttons.length; b++) {
+                    last.buttons[b] = b == 6 || b == 7 ? 0 : false;
+                }
+                send(0, i, 0, 0, pad.id);
+            }
+            for (var b = 0; b < pad.buttons.length; b++) {
+                var button = pad.buttons[b];
+                // Triggers (6 and 7) are sent as axes 4 and 5.
+                var value = b == 6 || b == 7 ? button.value : button.pressed;
+                if (last.buttons[b] !== value) {
+                    last.buttons[b] = value;
+                    if (b == 6 || b == 7) {
+                        send(3, i, b - 2, value);
+                    } else {
+                        send(2, i, b, value ? 1 : 0);
+                    }
+                }
+            }
+            for (var a = 0; a < pad.axes.length && a < 4; a++) {
+                if (last.axes[a] !== pad.axes[a]) {
+                    last.axes[a] = pad.axes[a];
+                    send(3, i, a, pad.axes[a]);
+                }
+            }
+        }
+        if (typeof requestAnimationFrame != "undefined") {
+            requestAnimationFrame(poll);
+        } else {
+            setTimeout(poll, 16);
+        }
+    };
+    poll();
+}
+function _cala_pad_rumble(index, strong, weak, ms) {
+    var pad = navigator.getGamepads()[index];
+    if (pad && pad.vibrationActuator) {
+        pad.vibrationActuator.playEffect("dual-rumble", {
+            duration: ms, strongMagnitude: strong, weakMagnitude: weak
+        });
+    }
+}
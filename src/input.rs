@@ -0,0 +1,117 @@
+// cala_core
+//
+// Copyright (c) 2020 Jeron Aldaron Lau
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// https://apache.org/licenses/LICENSE-2.0>, or the Zlib License, <LICENSE-ZLIB
+// or http://opensource.org/licenses/Zlib>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Event queues shared by the input modules.
+
+#[cfg(target_os = "linux")]
+pub(crate) mod evdev;
+
+use std::collections::VecDeque;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::task::{Poll, Waker};
+
+// Events past this many are dropped (oldest first), so that a subscriber
+// that stops receiving doesn't grow without bound.
+const CAPACITY: usize = 1024;
+
+// A panicking subscriber can't leave a queue in an invalid state.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poison| poison.into_inner())
+}
+
+struct Queue<T> {
+    events: VecDeque<T>,
+    waker: Option<Waker>,
+}
+
+/// Sends each event to every subscribed `Receiver`.  Events may be sent from
+/// any thread.
+pub(crate) struct Channel<T> {
+    subscribers: Mutex<Vec<Weak<Mutex<Queue<T>>>>>,
+}
+
+impl<T: Clone> Channel<T> {
+    pub(crate) const fn new() -> Self {
+        Channel {
+            subscribers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Receive events sent from now on.
+    pub(crate) fn subscribe(&self) -> Receiver<T> {
+        let queue = Arc::new(Mutex::new(Queue {
+            events: VecDeque::new(),
+            waker: None,
+        }));
+        lock(&self.subscribers).push(Arc::downgrade(&queue));
+        Receiver(queue)
+    }
+
+    /// Send an event to every subscriber, dropping the ones that are gone.
+    pub(crate) fn send(&self, event: T) {
+        // Woken after unlocking, since a waker may run tasks (on the web).
+        for waker in self.queue(event) {
+            waker.wake();
+        }
+    }
+
+    /// Add an event to every subscriber's queue, returning the wakers to wake
+    /// once any locks held by the caller are released.
+    pub(crate) fn queue(&self, event: T) -> Vec<Waker> {
+        let mut wakers = Vec::new();
+        lock(&self.subscribers).retain(|queue| {
+            let queue = match queue.upgrade() {
+                Some(queue) => queue,
+                None => return false,
+            };
+            let mut queue = lock(&queue);
+            if queue.events.len() == CAPACITY {
+                queue.events.pop_front();
+            }
+            queue.events.push_back(event.clone());
+            wakers.extend(queue.waker.take());
+            true
+        });
+        wakers
+    }
+}
+
+/// Receives events from a `Channel`.
+pub(crate) struct Receiver<T>(Arc<Mutex<Queue<T>>>);
+
+impl<T> Receiver<T> {
+    /// Wait for the next event.
+    pub(crate) async fn recv(&mut self) -> T {
+        std::future::poll_fn(|cx| {
+            let mut queue = lock(&self.0);
+            match queue.events.pop_front() {
+                Some(event) => Poll::Ready(event),
+                None => {
+                    queue.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+    }
+
+    /// Get the next event, if one has already been sent.
+    pub(crate) fn try_recv(&mut self) -> Option<T> {
+        lock(&self.0).events.pop_front()
+    }
+}
+
+impl<T> Debug for Receiver<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_struct("Receiver")
+            .field("pending", &lock(&self.0).events.len())
+            .finish()
+    }
+}
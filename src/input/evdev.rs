@@ -0,0 +1,490 @@
+// cala_core
+//
+// Copyright (c) 2020 Jeron Aldaron Lau
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// https://apache.org/licenses/LICENSE-2.0>, or the Zlib License, <LICENSE-ZLIB
+// or http://opensource.org/licenses/Zlib>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Linux input devices (`/dev/input/event*`), shared by the input modules.
+//!
+//! Devices are found by scanning `/dev/input` once a second, so devices that
+//! are plugged in later get picked up.  Scanning and reading devices happens
+//! on an executor running on a thread of its own.  Devices that can't be opened (usually
+//! for lack of permission to the `input` group) are skipped.
+
+#![allow(unsafe_code)]
+// Some of these are only used by some of the input modules.
+#![cfg_attr(
+    not(all(
+        feature = "keyboard",
+        feature = "mouse",
+        feature = "pad",
+        feature = "touch"
+    )),
+    allow(dead_code)
+)]
+
+use super::lock;
+use crate::os::*;
+use std::convert::TryFrom;
+use std::fs::OpenOptions;
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+mod ffi {
+    use crate::os::*;
+
+    extern "C" {
+        pub(super) fn ioctl(fd: RawFd, request: c_ulong, ...) -> c_sint;
+        pub(super) fn write(
+            fd: RawFd,
+            buf: *const c_void,
+            count: c_usize,
+        ) -> c_ssize;
+    }
+}
+
+pub(crate) const EV_SYN: u16 = 0x00;
+pub(crate) const EV_KEY: u16 = 0x01;
+pub(crate) const EV_REL: u16 = 0x02;
+pub(crate) const EV_ABS: u16 = 0x03;
+pub(crate) const EV_FF: u16 = 0x15;
+
+pub(crate) const SYN_REPORT: u16 = 0x00;
+pub(crate) const SYN_DROPPED: u16 = 0x03;
+
+const KEY_A: u16 = 30;
+const KEY_SPACE: u16 = 57;
+pub(crate) const BTN_MISC: u16 = 0x100;
+pub(crate) const BTN_LEFT: u16 = 0x110;
+const BTN_JOYSTICK: u16 = 0x120;
+const BTN_GAMEPAD: u16 = 0x130;
+pub(crate) const BTN_TOUCH: u16 = 0x14a;
+const KEY_CNT: usize = 0x300;
+
+pub(crate) const REL_X: u16 = 0x00;
+pub(crate) const REL_Y: u16 = 0x01;
+pub(crate) const REL_HWHEEL: u16 = 0x06;
+pub(crate) const REL_WHEEL: u16 = 0x08;
+const REL_CNT: usize = 0x10;
+
+pub(crate) const ABS_X: u16 = 0x00;
+pub(crate) const ABS_Y: u16 = 0x01;
+pub(crate) const ABS_Z: u16 = 0x02;
+pub(crate) const ABS_RX: u16 = 0x03;
+pub(crate) const ABS_RY: u16 = 0x04;
+pub(crate) const ABS_RZ: u16 = 0x05;
+pub(crate) const ABS_HAT0X: u16 = 0x10;
+pub(crate) const ABS_HAT0Y: u16 = 0x11;
+pub(crate) const ABS_MT_SLOT: u16 = 0x2f;
+pub(crate) const ABS_MT_POSITION_X: u16 = 0x35;
+pub(crate) const ABS_MT_POSITION_Y: u16 = 0x36;
+pub(crate) const ABS_MT_TRACKING_ID: u16 = 0x39;
+pub(crate) const ABS_MT_PRESSURE: u16 = 0x3a;
+const ABS_CNT: usize = 0x40;
+
+const INPUT_PROP_DIRECT: u16 = 0x01;
+const INPUT_PROP_CNT: usize = 0x20;
+
+const FF_RUMBLE: u16 = 0x50;
+
+// `_IOR('E', nr, size)`
+const fn ior(nr: u32, size: usize) -> u32 {
+    (2 << 30) | ((size as u32) << 16) | ((b'E' as u32) << 8) | nr
+}
+
+// `_IOW('E', nr, size)`
+const fn iow(nr: u32, size: usize) -> u32 {
+    (1 << 30) | ((size as u32) << 16) | ((b'E' as u32) << 8) | nr
+}
+
+/// An event read from an input device (`struct input_event`, without the
+/// timestamp).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct InputEvent {
+    pub(crate) kind: u16,
+    pub(crate) code: u16,
+    pub(crate) value: i32,
+}
+
+impl InputEvent {
+    /// Size of a `struct input_event`, which starts with a `struct timeval`.
+    pub(crate) const SIZE: usize = 2 * size_of::<std::os::raw::c_long>() + 8;
+
+    /// Parse the events in bytes read from a device (ignoring any partial
+    /// event at the end).
+    pub(crate) fn parse(bytes: &[u8]) -> impl Iterator<Item = Self> + '_ {
+        bytes.chunks_exact(Self::SIZE).map(|event| {
+            let event = &event[Self::SIZE - 8..];
+            InputEvent {
+                kind: u16::from_ne_bytes([event[0], event[1]]),
+                code: u16::from_ne_bytes([event[2], event[3]]),
+                value: i32::from_ne_bytes([
+                    event[4], event[5], event[6], event[7],
+                ]),
+            }
+        })
+    }
+
+    // Write the event, with a zero timestamp, to send to a device.
+    fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        let event = &mut bytes[Self::SIZE - 8..];
+        event[0..2].copy_from_slice(&self.kind.to_ne_bytes());
+        event[2..4].copy_from_slice(&self.code.to_ne_bytes());
+        event[4..8].copy_from_slice(&self.value.to_ne_bytes());
+        bytes
+    }
+}
+
+/// Range of an absolute axis (`struct input_absinfo`).
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub(crate) struct AbsInfo {
+    pub(crate) value: i32,
+    pub(crate) minimum: i32,
+    pub(crate) maximum: i32,
+    pub(crate) fuzz: i32,
+    pub(crate) flat: i32,
+    pub(crate) resolution: i32,
+}
+
+// The effect in a `struct ff_effect`, which is as big as the largest one
+// (`struct ff_periodic_effect`, which ends with a pointer).
+#[repr(C)]
+#[derive(Copy, Clone)]
+union FfUnion {
+    rumble: [u16; 2],
+    periodic: [usize; 24 / size_of::<usize>() + 1],
+}
+
+// `struct ff_effect`
+#[repr(C)]
+struct FfEffect {
+    kind: u16,
+    id: i16,
+    direction: u16,
+    trigger: [u16; 2],
+    replay: [u16; 2],
+    effect: FfUnion,
+}
+
+/// Which input module a device gets used by.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Class {
+    Keyboard,
+    Mouse,
+    Touch,
+    Pad,
+}
+
+/// Receives events from the devices of a `Class`.
+pub(crate) trait Handler: Sync {
+    /// A device was found (or the handler started listening).
+    fn connect(&self, _device: &Device) {}
+    /// A device sent an event.
+    fn event(&self, device: &Device, event: InputEvent);
+    /// A device was unplugged.
+    fn disconnect(&self, _device: &Device) {}
+}
+
+/// An open input device.
+#[derive(Debug)]
+pub(crate) struct Device {
+    path: PathBuf,
+    classes: Vec<Class>,
+    connection: Connection,
+}
+
+impl Device {
+    // Open a device, returning `None` if it's not used by any input module.
+    fn open(path: &Path) -> Option<Self> {
+        // Writing is only needed for force feedback, so it's optional.
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .or_else(|_| OpenOptions::new().read(true).open(path))
+            .ok()?;
+        let connection = unsafe { Connection::from_raw_fd(file.into_raw_fd()) };
+        let fd = connection.as_raw_fd();
+
+        let keys = bits(fd, EV_KEY, KEY_CNT);
+        let rel = bits(fd, EV_REL, REL_CNT);
+        let abs = bits(fd, EV_ABS, ABS_CNT);
+        let props = props(fd);
+        let mut classes = Vec::new();
+        if has(&keys, KEY_A) && has(&keys, KEY_SPACE) {
+            classes.push(Class::Keyboard);
+        }
+        // Mice, and pointing devices with absolute positions (like tablets
+        // and touchpads).
+        if has(&keys, BTN_LEFT)
+            && ((has(&rel, REL_X) && has(&rel, REL_Y))
+                || (has(&abs, ABS_X) && has(&abs, ABS_Y)))
+        {
+            classes.push(Class::Mouse);
+        }
+        // Touchscreens (touchpads have the same axes, but aren't direct).
+        if has(&props, INPUT_PROP_DIRECT)
+            && has(&abs, ABS_MT_SLOT)
+            && has(&abs, ABS_MT_POSITION_X)
+            && has(&abs, ABS_MT_POSITION_Y)
+        {
+            classes.push(Class::Touch);
+        }
+        if has(&keys, BTN_GAMEPAD)
+            || (has(&keys, BTN_JOYSTICK) && has(&abs, ABS_X))
+        {
+            classes.push(Class::Pad);
+        }
+        if classes.is_empty() {
+            return None;
+        }
+
+        Some(Device {
+            path: path.to_path_buf(),
+            classes,
+            connection,
+        })
+    }
+
+    /// Check whether or not the device is used by an input module.
+    pub(crate) fn is(&self, class: Class) -> bool {
+        self.classes.contains(&class)
+    }
+
+    /// Get the path of the device, which identifies it while it's plugged
+    /// in.
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Get the range of an absolute axis, if the device has it.
+    pub(crate) fn abs_info(&self, axis: u16) -> Option<AbsInfo> {
+        let mut info = AbsInfo::default();
+        let request = ior(0x40 + u32::from(axis), size_of::<AbsInfo>());
+        ioctl(self.connection.as_raw_fd(), request, &mut info)?;
+        Some(info)
+    }
+
+    /// Get the name of the device.
+    pub(crate) fn name(&self) -> String {
+        let mut name = [0u8; 256];
+        let request = ior(0x06, name.len());
+        if ioctl(self.connection.as_raw_fd(), request, name.as_mut_ptr())
+            .is_none()
+        {
+            return String::new();
+        }
+        let len = name.iter().position(|byte| *byte == 0).unwrap_or(0);
+        String::from_utf8_lossy(&name[..len]).into_owned()
+    }
+
+    /// Play a rumble effect, with motor strengths from 0 to `u16::MAX`.
+    /// `effect` is the ID of the effect uploaded last time (or -1), which gets
+    /// replaced.  Returns `false` if the device can't rumble.
+    pub(crate) fn rumble(
+        &self,
+        effect: &mut i16,
+        strong: u16,
+        weak: u16,
+        milliseconds: u16,
+    ) -> bool {
+        let fd = self.connection.as_raw_fd();
+        let mut upload = FfEffect {
+            kind: FF_RUMBLE,
+            id: *effect,
+            direction: 0,
+            trigger: [0, 0],
+            replay: [milliseconds, 0],
+            effect: FfUnion {
+                periodic: [0; 24 / size_of::<usize>() + 1],
+            },
+        };
+        upload.effect.rumble = [strong, weak];
+        let request = iow(0x80, size_of::<FfEffect>());
+        if ioctl(fd, request, &mut upload).is_none() {
+            return false;
+        }
+        *effect = upload.id;
+
+        let play = InputEvent {
+            kind: EV_FF,
+            code: upload.id as u16,
+            value: 1,
+        }
+        .to_bytes();
+        let written =
+            unsafe { ffi::write(fd, play.as_ptr().cast(), play.len().into()) };
+        usize::try_from(isize::from(written)) == Ok(play.len())
+    }
+}
+
+// Run an ioctl that writes to `arg`, returning the non-negative result.
+fn ioctl<T>(fd: RawFd, request: u32, arg: *mut T) -> Option<usize> {
+    let ret = unsafe { ffi::ioctl(fd, request.into(), arg) };
+    usize::try_from(i32::try_from(ret).ok()?).ok()
+}
+
+// Get the bitmask of codes a device supports for an event type.
+fn bits(fd: RawFd, kind: u16, count: usize) -> Vec<u8> {
+    let mut bits = vec![0u8; count.div_ceil(8)];
+    let request = ior(0x20 + u32::from(kind), bits.len());
+    if ioctl(fd, request, bits.as_mut_ptr()).is_none() {
+        bits.clear();
+    }
+    bits
+}
+
+// Get the bitmask of a device's properties (`INPUT_PROP_*`).
+fn props(fd: RawFd) -> Vec<u8> {
+    let mut bits = vec![0u8; INPUT_PROP_CNT.div_ceil(8)];
+    if ioctl(fd, ior(0x09, bits.len()), bits.as_mut_ptr()).is_none() {
+        bits.clear();
+    }
+    bits
+}
+
+// Check whether a bit is set in a bitmask from `bits()` or `props()`.
+fn has(bits: &[u8], bit: u16) -> bool {
+    let bit = usize::from(bit);
+    bits.get(bit / 8)
+        .is_some_and(|byte| byte & (1 << (bit % 8)) != 0)
+}
+
+/// Get an open device by its path.
+pub(crate) fn device(path: &Path) -> Option<Arc<Device>> {
+    lock(&STATE)
+        .devices
+        .iter()
+        .find(|device| device.path == path)
+        .cloned()
+}
+
+struct State {
+    handlers: Vec<(Class, &'static dyn Handler)>,
+    devices: Vec<Arc<Device>>,
+    scanning: bool,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    handlers: Vec::new(),
+    devices: Vec::new(),
+    scanning: false,
+});
+
+// Get the handlers for a device, so they can be called without locking.
+fn handlers(device: &Device) -> Vec<&'static dyn Handler> {
+    lock(&STATE)
+        .handlers
+        .iter()
+        .filter(|(class, _)| device.is(*class))
+        .map(|(_, handler)| *handler)
+        .collect()
+}
+
+/// Start sending events from devices of `class` to `handler`, starting a
+/// thread to scan for devices if one isn't running yet.  Does nothing if there's
+/// already a handler for `class`.
+pub(crate) fn listen(class: Class, handler: &'static dyn Handler) {
+    let (devices, scan) = {
+        let mut state = lock(&STATE);
+        if state
+            .handlers
+            .iter()
+            .any(|(listening, _)| *listening == class)
+        {
+            return;
+        }
+        state.handlers.push((class, handler));
+        let devices: Vec<Arc<Device>> = state.devices.clone();
+        (devices, !std::mem::replace(&mut state.scanning, true))
+    };
+    for device in devices.iter().filter(|device| device.is(class)) {
+        handler.connect(device);
+    }
+    // The calling thread might not be running an executor.
+    if scan
+        && std::thread::Builder::new()
+            .name("cala-input".to_string())
+            .spawn(|| crate::start::start(Box::pin(scan_task())))
+            .is_err()
+    {
+        lock(&STATE).scanning = false;
+    }
+}
+
+// Clears `scanning` when the scan task is dropped (if its thread panics), so
+// that the next call to `listen()` starts scanning again.
+struct Scanning;
+
+impl Drop for Scanning {
+    fn drop(&mut self) {
+        lock(&STATE).scanning = false;
+    }
+}
+
+// Open new devices in `/dev/input`, forever.
+async fn scan_task() {
+    let _scanning = Scanning;
+    let mut interval = crate::time::interval(Duration::from_secs(1));
+    loop {
+        scan();
+        interval.tick().await;
+    }
+}
+
+fn scan() {
+    let entries = match std::fs::read_dir("/dev/input") {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let is_event = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with("event"));
+        let is_open = || {
+            lock(&STATE)
+                .devices
+                .iter()
+                .any(|device| device.path == path)
+        };
+        if !is_event || is_open() {
+            continue;
+        }
+        if let Some(device) = Device::open(&path) {
+            let device = Arc::new(device);
+            lock(&STATE).devices.push(device.clone());
+            for handler in handlers(&device) {
+                handler.connect(&device);
+            }
+            drop(crate::spawn(read_task(device)));
+        }
+    }
+}
+
+// Read events from a device until it's unplugged.
+async fn read_task(device: Arc<Device>) {
+    let mut buf = vec![0; InputEvent::SIZE * 64];
+    while let Ok(len @ 1..) = device.connection.read(&mut buf).await {
+        let handlers = handlers(&device);
+        for event in InputEvent::parse(&buf[..len]) {
+            for handler in handlers.iter() {
+                handler.event(&device, event);
+            }
+        }
+    }
+    lock(&STATE)
+        .devices
+        .retain(|open| !Arc::ptr_eq(open, &device));
+    for handler in handlers(&device) {
+        handler.disconnect(&device);
+    }
+}
@@ -0,0 +1,690 @@
+// cala_core
+//
+// Copyright (c) 2020 Jeron Aldaron Lau
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// https://apache.org/licenses/LICENSE-2.0>, or the Zlib License, <LICENSE-ZLIB
+// or http://opensource.org/licenses/Zlib>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Keyboard input.
+//!
+//! Key presses come from `keydown` / `keyup` events on the web, and from
+//! keyboards in `/dev/input` on Linux.  Other platforms only get events sent
+//! with [`inject()`].
+//!
+//! Keys are for shortcuts and game controls; for typing, use [`TextInput`],
+//! which also gets text composed with an input method.
+//!
+//! On Linux, the user's keymap isn't loaded: [`LogicalKey`]s and typed text
+//! always follow a US QWERTY layout, and compose sequences only cover accented
+//! Latin letters.  Typing in other scripts (or with other layouts) only works
+//! on the web for now.
+//!
+//! ```rust,no_run
+//! use cala_core::keyboard::{Key, Keyboard};
+//!
+//! async fn wait_for_escape() {
+//!     let mut keyboard = Keyboard::new();
+//!     loop {
+//!         let event = keyboard.recv().await;
+//!         if event.key == Key::Escape && event.pressed {
+//!             break;
+//!         }
+//!     }
+//! }
+//! ```
+
+mod text;
+
+pub use text::{inject_text, TextEvent, TextInput};
+
+use crate::input::{Channel, Receiver};
+
+/// A physical key, named after what it is on a US QWERTY keyboard.
+#[allow(missing_docs)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Key {
+    Escape,
+    F1,
+    F2,
+    F3,
+    F4,
+    F5,
+    F6,
+    F7,
+    F8,
+    F9,
+    F10,
+    F11,
+    F12,
+    Backquote,
+    Digit1,
+    Digit2,
+    Digit3,
+    Digit4,
+    Digit5,
+    Digit6,
+    Digit7,
+    Digit8,
+    Digit9,
+    Digit0,
+    Minus,
+    Equal,
+    Backspace,
+    Tab,
+    Q,
+    W,
+    E,
+    R,
+    T,
+    Y,
+    U,
+    I,
+    O,
+    P,
+    BracketLeft,
+    BracketRight,
+    Backslash,
+    CapsLock,
+    A,
+    S,
+    D,
+    F,
+    G,
+    H,
+    J,
+    K,
+    L,
+    Semicolon,
+    Quote,
+    Enter,
+    ShiftLeft,
+    /// The extra key next to left shift on ISO keyboards.
+    IntlBackslash,
+    Z,
+    X,
+    C,
+    V,
+    B,
+    N,
+    M,
+    Comma,
+    Period,
+    Slash,
+    ShiftRight,
+    ControlLeft,
+    MetaLeft,
+    AltLeft,
+    Space,
+    AltRight,
+    MetaRight,
+    ContextMenu,
+    ControlRight,
+    PrintScreen,
+    ScrollLock,
+    Pause,
+    Insert,
+    Home,
+    PageUp,
+    Delete,
+    End,
+    PageDown,
+    ArrowUp,
+    ArrowLeft,
+    ArrowDown,
+    ArrowRight,
+    NumLock,
+    NumpadDivide,
+    NumpadMultiply,
+    NumpadSubtract,
+    NumpadAdd,
+    NumpadEnter,
+    NumpadDecimal,
+    Numpad1,
+    Numpad2,
+    Numpad3,
+    Numpad4,
+    Numpad5,
+    Numpad6,
+    Numpad7,
+    Numpad8,
+    Numpad9,
+    Numpad0,
+    /// A key not in this list.
+    Unknown,
+}
+
+// Each key, with its DOM `KeyboardEvent.code` and Linux evdev key code.
+const KEYS: &[(Key, &str, u16)] = &[
+    (Key::Escape, "Escape", 1),
+    (Key::F1, "F1", 59),
+    (Key::F2, "F2", 60),
+    (Key::F3, "F3", 61),
+    (Key::F4, "F4", 62),
+    (Key::F5, "F5", 63),
+    (Key::F6, "F6", 64),
+    (Key::F7, "F7", 65),
+    (Key::F8, "F8", 66),
+    (Key::F9, "F9", 67),
+    (Key::F10, "F10", 68),
+    (Key::F11, "F11", 87),
+    (Key::F12, "F12", 88),
+    (Key::Backquote, "Backquote", 41),
+    (Key::Digit1, "Digit1", 2),
+    (Key::Digit2, "Digit2", 3),
+    (Key::Digit3, "Digit3", 4),
+    (Key::Digit4, "Digit4", 5),
+    (Key::Digit5, "Digit5", 6),
+    (Key::Digit6, "Digit6", 7),
+    (Key::Digit7, "Digit7", 8),
+    (Key::Digit8, "Digit8", 9),
+    (Key::Digit9, "Digit9", 10),
+    (Key::Digit0, "Digit0", 11),
+    (Key::Minus, "Minus", 12),
+    (Key::Equal, "Equal", 13),
+    (Key::Backspace, "Backspace", 14),
+    (Key::Tab, "Tab", 15),
+    (Key::Q, "KeyQ", 16),
+    (Key::W, "KeyW", 17),
+    (Key::E, "KeyE", 18),
+    (Key::R, "KeyR", 19),
+    (Key::T, "KeyT", 20),
+    (Key::Y, "KeyY", 21),
+    (Key::U, "KeyU", 22),
+    (Key::I, "KeyI", 23),
+    (Key::O, "KeyO", 24),
+    (Key::P, "KeyP", 25),
+    (Key::BracketLeft, "BracketLeft", 26),
+    (Key::BracketRight, "BracketRight", 27),
+    (Key::Backslash, "Backslash", 43),
+    (Key::CapsLock, "CapsLock", 58),
+    (Key::A, "KeyA", 30),
+    (Key::S, "KeyS", 31),
+    (Key::D, "KeyD", 32),
+    (Key::F, "KeyF", 33),
+    (Key::G, "KeyG", 34),
+    (Key::H, "KeyH", 35),
+    (Key::J, "KeyJ", 36),
+    (Key::K, "KeyK", 37),
+    (Key::L, "KeyL", 38),
+    (Key::Semicolon, "Semicolon", 39),
+    (Key::Quote, "Quote", 40),
+    (Key::Enter, "Enter", 28),
+    (Key::ShiftLeft, "ShiftLeft", 42),
+    (Key::IntlBackslash, "IntlBackslash", 86),
+    (Key::Z, "KeyZ", 44),
+    (Key::X, "KeyX", 45),
+    (Key::C, "KeyC", 46),
+    (Key::V, "KeyV", 47),
+    (Key::B, "KeyB", 48),
+    (Key::N, "KeyN", 49),
+    (Key::M, "KeyM", 50),
+    (Key::Comma, "Comma", 51),
+    (Key::Period, "Period", 52),
+    (Key::Slash, "Slash", 53),
+    (Key::ShiftRight, "ShiftRight", 54),
+    (Key::ControlLeft, "ControlLeft", 29),
+    (Key::MetaLeft, "MetaLeft", 125),
+    (Key::AltLeft, "AltLeft", 56),
+    (Key::Space, "Space", 57),
+    (Key::AltRight, "AltRight", 100),
+    (Key::MetaRight, "MetaRight", 126),
+    (Key::ContextMenu, "ContextMenu", 127),
+    (Key::ControlRight, "ControlRight", 97),
+    (Key::PrintScreen, "PrintScreen", 99),
+    (Key::ScrollLock, "ScrollLock", 70),
+    (Key::Pause, "Pause", 119),
+    (Key::Insert, "Insert", 110),
+    (Key::Home, "Home", 102),
+    (Key::PageUp, "PageUp", 104),
+    (Key::Delete, "Delete", 111),
+    (Key::End, "End", 107),
+    (Key::PageDown, "PageDown", 109),
+    (Key::ArrowUp, "ArrowUp", 103),
+    (Key::ArrowLeft, "ArrowLeft", 105),
+    (Key::ArrowDown, "ArrowDown", 108),
+    (Key::ArrowRight, "ArrowRight", 106),
+    (Key::NumLock, "NumLock", 69),
+    (Key::NumpadDivide, "NumpadDivide", 98),
+    (Key::NumpadMultiply, "NumpadMultiply", 55),
+    (Key::NumpadSubtract, "NumpadSubtract", 74),
+    (Key::NumpadAdd, "NumpadAdd", 78),
+    (Key::NumpadEnter, "NumpadEnter", 96),
+    (Key::NumpadDecimal, "NumpadDecimal", 83),
+    (Key::Numpad1, "Numpad1", 79),
+    (Key::Numpad2, "Numpad2", 80),
+    (Key::Numpad3, "Numpad3", 81),
+    (Key::Numpad4, "Numpad4", 75),
+    (Key::Numpad5, "Numpad5", 76),
+    (Key::Numpad6, "Numpad6", 77),
+    (Key::Numpad7, "Numpad7", 71),
+    (Key::Numpad8, "Numpad8", 72),
+    (Key::Numpad9, "Numpad9", 73),
+    (Key::Numpad0, "Numpad0", 82),
+];
+
+impl Key {
+    /// Get the key for a DOM `KeyboardEvent.code`.
+    pub fn from_code(code: &str) -> Key {
+        KEYS.iter()
+            .find(|(_, dom, _)| *dom == code)
+            .map_or(Key::Unknown, |(key, _, _)| *key)
+    }
+
+    /// Get the DOM `KeyboardEvent.code` of the key.
+    pub fn code(self) -> &'static str {
+        KEYS.iter()
+            .find(|(key, _, _)| *key == self)
+            .map_or("Unidentified", |(_, dom, _)| dom)
+    }
+
+    // Get the key for a Linux evdev key code.
+    #[cfg(target_os = "linux")]
+    fn from_evdev(code: u16) -> Key {
+        KEYS.iter()
+            .find(|(_, _, evdev)| *evdev == code)
+            .map_or(Key::Unknown, |(key, _, _)| *key)
+    }
+}
+
+/// What a key means with the current keyboard layout and modifiers.
+#[allow(variant_size_differences)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum LogicalKey {
+    /// A key that types a character.
+    Character(char),
+    /// A dead key, which changes the character typed by the next key.
+    Dead,
+    /// A key that doesn't type a character (such as `Enter`, or an arrow).
+    Named(Key),
+}
+
+/// Modifier keys held down during a key event.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Modifiers {
+    /// Either shift key
+    pub shift: bool,
+    /// Either control key
+    pub control: bool,
+    /// Either alt key (option on macOS)
+    pub alt: bool,
+    /// Either meta key (command on macOS, Windows key on Windows)
+    pub meta: bool,
+}
+
+impl Modifiers {
+    // Unpack modifiers from bits 0 (shift) to 3 (meta).
+    #[cfg_attr(not(target_arch = "wasm32"), allow(dead_code))]
+    fn from_bits(bits: u8) -> Self {
+        Modifiers {
+            shift: bits & 1 != 0,
+            control: bits & 2 != 0,
+            alt: bits & 4 != 0,
+            meta: bits & 8 != 0,
+        }
+    }
+}
+
+/// A key being pressed or released.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct KeyEvent {
+    /// Which key on the keyboard
+    pub key: Key,
+    /// What the key means, with the keyboard layout and modifiers
+    pub logical: LogicalKey,
+    /// Modifier keys held down (including this one, if it's pressed)
+    pub modifiers: Modifiers,
+    /// `true` if pressed, `false` if released
+    pub pressed: bool,
+    /// Whether the press was repeated by holding the key down
+    pub repeat: bool,
+}
+
+static EVENTS: Channel<KeyEvent> = Channel::new();
+
+/// Stream of key events, from every keyboard.
+#[derive(Debug)]
+pub struct Keyboard(Receiver<KeyEvent>);
+
+impl Keyboard {
+    /// Start receiving key events, from events that happen after this call.
+    pub fn new() -> Self {
+        #[cfg(target_os = "linux")]
+        crate::input::evdev::listen(
+            crate::input::evdev::Class::Keyboard,
+            &native::HANDLER,
+        );
+        #[cfg(target_arch = "wasm32")]
+        web::listen();
+        Keyboard(EVENTS.subscribe())
+    }
+
+    /// Wait for the next key event.
+    pub async fn recv(&mut self) -> KeyEvent {
+        self.0.recv().await
+    }
+
+    /// Get the next key event, if one has already happened.
+    pub fn try_recv(&mut self) -> Option<KeyEvent> {
+        self.0.try_recv()
+    }
+}
+
+impl Default for Keyboard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Send a synthetic key event to every [`Keyboard`], as if it came from a
+/// real keyboard (for testing).
+pub fn inject(event: KeyEvent) {
+    EVENTS.send(event);
+}
+
+#[cfg(target_os = "linux")]
+mod native {
+    use super::text::native as text;
+    use super::{inject, Key, KeyEvent, LogicalKey, Modifiers};
+    use crate::input::evdev::{Device, Handler, InputEvent, BTN_MISC, EV_KEY};
+    use std::sync::Mutex;
+
+    // Characters typed by each key on a US QWERTY layout, without and with
+    // shift.  Caps lock only affects letters.
+    const LAYOUT: &[(Key, char, char)] = &[
+        (Key::Backquote, '`', '~'),
+        (Key::Digit1, '1', '!'),
+        (Key::Digit2, '2', '@'),
+        (Key::Digit3, '3', '#'),
+        (Key::Digit4, '4', '$'),
+        (Key::Digit5, '5', '%'),
+        (Key::Digit6, '6', '^'),
+        (Key::Digit7, '7', '&'),
+        (Key::Digit8, '8', '*'),
+        (Key::Digit9, '9', '('),
+        (Key::Digit0, '0', ')'),
+        (Key::Minus, '-', '_'),
+        (Key::Equal, '=', '+'),
+        (Key::Q, 'q', 'Q'),
+        (Key::W, 'w', 'W'),
+        (Key::E, 'e', 'E'),
+        (Key::R, 'r', 'R'),
+        (Key::T, 't', 'T'),
+        (Key::Y, 'y', 'Y'),
+        (Key::U, 'u', 'U'),
+        (Key::I, 'i', 'I'),
+        (Key::O, 'o', 'O'),
+        (Key::P, 'p', 'P'),
+        (Key::BracketLeft, '[', '{'),
+        (Key::BracketRight, ']', '}'),
+        (Key::Backslash, '\\', '|'),
+        (Key::A, 'a', 'A'),
+        (Key::S, 's', 'S'),
+        (Key::D, 'd', 'D'),
+        (Key::F, 'f', 'F'),
+        (Key::G, 'g', 'G'),
+        (Key::H, 'h', 'H'),
+        (Key::J, 'j', 'J'),
+        (Key::K, 'k', 'K'),
+        (Key::L, 'l', 'L'),
+        (Key::Semicolon, ';', ':'),
+        (Key::Quote, '\'', '"'),
+        (Key::IntlBackslash, '<', '>'),
+        (Key::Z, 'z', 'Z'),
+        (Key::X, 'x', 'X'),
+        (Key::C, 'c', 'C'),
+        (Key::V, 'v', 'V'),
+        (Key::B, 'b', 'B'),
+        (Key::N, 'n', 'N'),
+        (Key::M, 'm', 'M'),
+        (Key::Comma, ',', '<'),
+        (Key::Period, '.', '>'),
+        (Key::Slash, '/', '?'),
+        (Key::Space, ' ', ' '),
+        (Key::NumpadDivide, '/', '/'),
+        (Key::NumpadMultiply, '*', '*'),
+        (Key::NumpadSubtract, '-', '-'),
+        (Key::NumpadAdd, '+', '+'),
+        (Key::NumpadDecimal, '.', '.'),
+        (Key::Numpad1, '1', '1'),
+        (Key::Numpad2, '2', '2'),
+        (Key::Numpad3, '3', '3'),
+        (Key::Numpad4, '4', '4'),
+        (Key::Numpad5, '5', '5'),
+        (Key::Numpad6, '6', '6'),
+        (Key::Numpad7, '7', '7'),
+        (Key::Numpad8, '8', '8'),
+        (Key::Numpad9, '9', '9'),
+        (Key::Numpad0, '0', '0'),
+    ];
+
+    // Modifier keys held down, and whether caps lock is on (shared by every
+    // keyboard, like on a desktop).
+    #[derive(Copy, Clone)]
+    struct State {
+        held: [bool; 8],
+        caps_lock: bool,
+    }
+
+    const MODIFIERS: [Key; 8] = [
+        Key::ShiftLeft,
+        Key::ShiftRight,
+        Key::ControlLeft,
+        Key::ControlRight,
+        Key::AltLeft,
+        Key::AltRight,
+        Key::MetaLeft,
+        Key::MetaRight,
+    ];
+
+    impl State {
+        fn modifiers(&self) -> Modifiers {
+            Modifiers {
+                shift: self.held[0] || self.held[1],
+                control: self.held[2] || self.held[3],
+                alt: self.held[4] || self.held[5],
+                meta: self.held[6] || self.held[7],
+            }
+        }
+
+        fn logical(&self, key: Key) -> LogicalKey {
+            let shift = self.modifiers().shift;
+            match LAYOUT.iter().find(|(layout, _, _)| *layout == key) {
+                Some((_, lower, upper)) => {
+                    let letter = lower.is_ascii_lowercase();
+                    if shift != (self.caps_lock && letter) {
+                        LogicalKey::Character(*upper)
+                    } else {
+                        LogicalKey::Character(*lower)
+                    }
+                }
+                None => LogicalKey::Named(key),
+            }
+        }
+    }
+
+    pub(super) struct Keyboards(Mutex<State>);
+
+    pub(super) static HANDLER: Keyboards = Keyboards(Mutex::new(State {
+        held: [false; 8],
+        caps_lock: false,
+    }));
+
+    impl Handler for Keyboards {
+        fn event(&self, _device: &Device, event: InputEvent) {
+            // Values are 0 for release, 1 for press and 2 for repeat.  Codes
+            // from `BTN_MISC` on are buttons (of mice, for example), not keys.
+            if event.kind != EV_KEY
+                || event.code >= BTN_MISC
+                || !(0..=2).contains(&event.value)
+            {
+                return;
+            }
+            let key = Key::from_evdev(event.code);
+            let pressed = event.value != 0;
+            let state = {
+                let mut state =
+                    self.0.lock().unwrap_or_else(|poison| poison.into_inner());
+                if let Some(i) = MODIFIERS.iter().position(|m| *m == key) {
+                    state.held[i] = pressed;
+                }
+                if key == Key::CapsLock && event.value == 1 {
+                    state.caps_lock = !state.caps_lock;
+                }
+                *state
+            };
+            let logical = state.logical(key);
+            let modifiers = state.modifiers();
+            inject(KeyEvent {
+                key,
+                logical,
+                modifiers,
+                pressed,
+                repeat: event.value == 2,
+            });
+            // Shortcuts (like Ctrl+C) don't type text.
+            if !pressed || modifiers.control || modifiers.alt || modifiers.meta
+            {
+                return;
+            }
+            match logical {
+                LogicalKey::Character(c) => text::typed(c),
+                LogicalKey::Named(Key::ContextMenu) if event.value == 1 => {
+                    text::compose_key()
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+#[allow(unsafe_code)]
+mod web {
+    use super::{inject, Key, KeyEvent, LogicalKey, Modifiers};
+    use crate::os::web::JsVar;
+    use std::sync::Once;
+
+    #[cfg(feature = "wasm-bindgen")]
+    use wasm_bindgen::prelude::*;
+
+    #[cfg(not(feature = "wasm-bindgen"))]
+    mod ffi {
+        extern "C" {
+            pub(super) fn _cala_keyboard_listen();
+        }
+    }
+
+    #[cfg(feature = "wasm-bindgen")]
+    mod ffi {
+        use wasm_bindgen::prelude::*;
+
+        #[wasm_bindgen]
+        extern "C" {
+            pub(super) fn _cala_keyboard_listen();
+        }
+    }
+
+    #[allow(unused_unsafe)]
+    pub(super) fn listen() {
+        static LISTEN: Once = Once::new();
+        LISTEN.call_once(|| unsafe { ffi::_cala_keyboard_listen() });
+    }
+
+    // Handle a DOM event, stored as `[code, key, pressed, repeat, modifiers]`
+    // in heap slot `event`.
+    fn event(event: u32) {
+        let event = unsafe { JsVar::from_raw(event) };
+        let (code, logical, pressed, repeat, modifiers) =
+            match event.get::<(String, String, bool, bool, u8)>() {
+                Ok(event) => event,
+                Err(_) => return,
+            };
+        let key = Key::from_code(&code);
+        let mut chars = logical.chars();
+        let logical = match (chars.next(), chars.next()) {
+            (Some(c), None) => LogicalKey::Character(c),
+            _ if logical == "Dead" => LogicalKey::Dead,
+            _ => LogicalKey::Named(key),
+        };
+        inject(KeyEvent {
+            key,
+            logical,
+            modifiers: Modifiers::from_bits(modifiers),
+            pressed,
+            repeat,
+        });
+    }
+
+    #[cfg(feature = "wasm-bindgen")]
+    #[allow(unreachable_pub)]
+    /// Called by `runtime.js` when a `keydown` or `keyup` event happens.
+    #[wasm_bindgen]
+    pub fn _cala_keyboard_event(slot: u32) {
+        event(slot);
+    }
+
+    #[cfg(not(feature = "wasm-bindgen"))]
+    #[no_mangle]
+    extern "C" fn _cala_keyboard_event(slot: u32) {
+        event(slot);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn press(key: Key, c: char) -> KeyEvent {
+        KeyEvent {
+            key,
+            logical: LogicalKey::Character(c),
+            modifiers: Modifiers::default(),
+            pressed: true,
+            repeat: false,
+        }
+    }
+
+    #[test]
+    fn inject_reaches_every_keyboard() {
+        let mut first = Keyboard::new();
+        let mut second = Keyboard::new();
+        inject(press(Key::A, 'a'));
+        let mut late = Keyboard::new();
+        inject(press(Key::B, 'b'));
+
+        assert_eq!(first.try_recv(), Some(press(Key::A, 'a')));
+        assert_eq!(first.try_recv(), Some(press(Key::B, 'b')));
+        assert_eq!(first.try_recv(), None);
+        assert_eq!(second.try_recv(), Some(press(Key::A, 'a')));
+        // Only events after a keyboard is created are received.
+        assert_eq!(late.try_recv(), Some(press(Key::B, 'b')));
+        assert_eq!(late.try_recv(), None);
+
+        crate::start::start(Box::pin(async move {
+            inject(press(Key::C, 'c'));
+            assert_eq!(second.recv().await, press(Key::B, 'b'));
+            assert_eq!(second.recv().await, press(Key::C, 'c'));
+        }));
+    }
+
+    #[test]
+    fn key_codes() {
+        for (key, code, _) in KEYS {
+            assert_eq!(key.code(), *code);
+            assert_eq!(Key::from_code(code), *key);
+        }
+        assert_eq!(Key::from_code("NotAKey"), Key::Unknown);
+        assert_eq!(Key::Unknown.code(), "Unidentified");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn evdev_codes() {
+        for (key, _, evdev) in KEYS {
+            assert_eq!(Key::from_evdev(*evdev), *key);
+        }
+        assert_eq!(Key::from_evdev(u16::MAX), Key::Unknown);
+    }
+}
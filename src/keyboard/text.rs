@@ -0,0 +1,293 @@
+// cala_core
+//
+// Copyright (c) 2020 Jeron Aldaron Lau
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// https://apache.org/licenses/LICENSE-2.0>, or the Zlib License, <LICENSE-ZLIB
+// or http://opensource.org/licenses/Zlib>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Text input, including composition with an input method (IME).
+
+use crate::input::{Channel, Receiver};
+
+/// Text typed, or being composed with an input method.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TextEvent {
+    /// Text was typed (or finished composing), and should be inserted.
+    Commit(String),
+    /// Composition started.
+    CompositionStart,
+    /// The text being composed changed.  It's only a preview, which is
+    /// replaced by the next update, or removed when composition ends.
+    CompositionUpdate(String),
+    /// Composition ended.  The composed text (if any) arrives as a `Commit`
+    /// right after.
+    CompositionEnd,
+}
+
+static EVENTS: Channel<TextEvent> = Channel::new();
+
+/// Stream of text input events.
+///
+/// On the web, these come from `beforeinput` and composition events, which
+/// only happen while an editable element has focus.  On Linux, text is typed
+/// with a US layout, and the menu key starts a compose sequence (for example,
+/// menu, `'`, `e` types `é`).  The user's keymap and Compose file aren't
+/// loaded, so only ASCII and accented Latin letters can be typed on Linux.
+#[derive(Debug)]
+pub struct TextInput(Receiver<TextEvent>);
+
+impl TextInput {
+    /// Start receiving text input, from events that happen after this call.
+    pub fn new() -> Self {
+        #[cfg(target_os = "linux")]
+        crate::input::evdev::listen(
+            crate::input::evdev::Class::Keyboard,
+            &super::native::HANDLER,
+        );
+        #[cfg(target_arch = "wasm32")]
+        web::listen();
+        TextInput(EVENTS.subscribe())
+    }
+
+    /// Wait for the next text input event.
+    pub async fn recv(&mut self) -> TextEvent {
+        self.0.recv().await
+    }
+
+    /// Get the next text input event, if one has already happened.
+    pub fn try_recv(&mut self) -> Option<TextEvent> {
+        self.0.try_recv()
+    }
+}
+
+impl Default for TextInput {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Send a synthetic text input event to every [`TextInput`] (for testing).
+pub fn inject_text(event: TextEvent) {
+    EVENTS.send(event);
+}
+
+#[cfg(target_os = "linux")]
+pub(super) mod native {
+    use super::{inject_text, TextEvent};
+    use std::sync::Mutex;
+
+    // Compose sequences: two characters typed after the compose key, and the
+    // character they make.  Uppercase letters compose to uppercase.
+    const COMPOSE: &[(char, char, char)] = &[
+        ('\'', 'a', 'á'),
+        ('\'', 'e', 'é'),
+        ('\'', 'i', 'í'),
+        ('\'', 'o', 'ó'),
+        ('\'', 'u', 'ú'),
+        ('\'', 'y', 'ý'),
+        ('`', 'a', 'à'),
+        ('`', 'e', 'è'),
+        ('`', 'i', 'ì'),
+        ('`', 'o', 'ò'),
+        ('`', 'u', 'ù'),
+        ('^', 'a', 'â'),
+        ('^', 'e', 'ê'),
+        ('^', 'i', 'î'),
+        ('^', 'o', 'ô'),
+        ('^', 'u', 'û'),
+        ('"', 'a', 'ä'),
+        ('"', 'e', 'ë'),
+        ('"', 'i', 'ï'),
+        ('"', 'o', 'ö'),
+        ('"', 'u', 'ü'),
+        ('"', 'y', 'ÿ'),
+        ('~', 'a', 'ã'),
+        ('~', 'n', 'ñ'),
+        ('~', 'o', 'õ'),
+        (',', 'c', 'ç'),
+        ('o', 'a', 'å'),
+        ('a', 'e', 'æ'),
+        ('o', 'e', 'œ'),
+        ('/', 'o', 'ø'),
+        ('s', 's', 'ß'),
+        ('!', '!', '¡'),
+        ('?', '?', '¿'),
+        ('<', '<', '«'),
+        ('>', '>', '»'),
+        ('=', 'e', '€'),
+        ('-', 'l', '£'),
+        ('o', 'c', '©'),
+        ('^', '2', '²'),
+        ('^', '3', '³'),
+    ];
+
+    fn compose(first: char, second: char) -> Option<char> {
+        let find = |second| {
+            COMPOSE
+                .iter()
+                .find(|(a, b, _)| *a == first && *b == second)
+                .map(|(_, _, composed)| *composed)
+        };
+        find(second).or_else(|| {
+            let composed = find(second.to_ascii_lowercase())?;
+            let mut upper = composed.to_uppercase();
+            match (upper.next(), upper.next()) {
+                (Some(upper), None) if second.is_ascii_uppercase() => {
+                    Some(upper)
+                }
+                _ => None,
+            }
+        })
+    }
+
+    // Characters typed since the compose key was pressed, if it was.
+    static SEQUENCE: Mutex<Option<String>> = Mutex::new(None);
+
+    /// Start (or restart) a compose sequence.
+    pub(in crate::keyboard) fn compose_key() {
+        let mut sequence =
+            SEQUENCE.lock().unwrap_or_else(|poison| poison.into_inner());
+        let restarted = sequence.replace(String::new()).is_some();
+        drop(sequence);
+        if restarted {
+            inject_text(TextEvent::CompositionUpdate(String::new()));
+        } else {
+            inject_text(TextEvent::CompositionStart);
+        }
+    }
+
+    /// Type a character, either committing it or adding it to the compose
+    /// sequence.
+    pub(in crate::keyboard) fn typed(c: char) {
+        let mut sequence =
+            SEQUENCE.lock().unwrap_or_else(|poison| poison.into_inner());
+        let events = match sequence.as_mut() {
+            None => vec![TextEvent::Commit(c.to_string())],
+            Some(typed) if typed.is_empty() => {
+                typed.push(c);
+                vec![TextEvent::CompositionUpdate(typed.clone())]
+            }
+            Some(typed) => {
+                let first = typed.chars().next().unwrap_or(c);
+                *sequence = None;
+                // Sequences that don't compose are dropped, like in xkb.
+                match compose(first, c) {
+                    Some(composed) => vec![
+                        TextEvent::CompositionEnd,
+                        TextEvent::Commit(composed.to_string()),
+                    ],
+                    None => vec![TextEvent::CompositionEnd],
+                }
+            }
+        };
+        drop(sequence);
+        for event in events {
+            inject_text(event);
+        }
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+#[allow(unsafe_code)]
+mod web {
+    use super::{inject_text, TextEvent};
+    use crate::os::web::JsVar;
+    use std::sync::Once;
+
+    #[cfg(feature = "wasm-bindgen")]
+    use wasm_bindgen::prelude::*;
+
+    #[cfg(not(feature = "wasm-bindgen"))]
+    mod ffi {
+        extern "C" {
+            pub(super) fn _cala_text_listen();
+        }
+    }
+
+    #[cfg(feature = "wasm-bindgen")]
+    mod ffi {
+        use wasm_bindgen::prelude::*;
+
+        #[wasm_bindgen]
+        extern "C" {
+            pub(super) fn _cala_text_listen();
+        }
+    }
+
+    #[allow(unused_unsafe)]
+    pub(super) fn listen() {
+        static LISTEN: Once = Once::new();
+        LISTEN.call_once(|| unsafe { ffi::_cala_text_listen() });
+    }
+
+    // Handle a DOM event, with the text stored in heap slot `text`.  Kinds
+    // are 0 for commit, and 1, 2 and 3 for composition start, update and end.
+    fn event(kind: u32, text: u32) {
+        let text = unsafe { JsVar::from_raw(text) };
+        let text = text.get::<String>().unwrap_or_default();
+        inject_text(match kind {
+            0 => TextEvent::Commit(text),
+            1 => TextEvent::CompositionStart,
+            2 => TextEvent::CompositionUpdate(text),
+            _ => TextEvent::CompositionEnd,
+        });
+    }
+
+    #[cfg(feature = "wasm-bindgen")]
+    #[allow(unreachable_pub)]
+    /// Called by `runtime.js` when text is typed or composed.
+    #[wasm_bindgen]
+    pub fn _cala_text_event(kind: u32, text: u32) {
+        event(kind, text);
+    }
+
+    #[cfg(not(feature = "wasm-bindgen"))]
+    #[no_mangle]
+    extern "C" fn _cala_text_event(kind: u32, text: u32) {
+        event(kind, text);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_input() {
+        let mut text = TextInput::new();
+        inject_text(TextEvent::Commit("hi".into()));
+        assert_eq!(text.try_recv(), Some(TextEvent::Commit("hi".into())));
+        assert_eq!(text.try_recv(), None);
+
+        #[cfg(target_os = "linux")]
+        {
+            use native::{compose_key, typed};
+
+            // Menu, `'`, `E` types `É`.
+            compose_key();
+            typed('\'');
+            typed('E');
+            // Sequences that don't compose type nothing.
+            compose_key();
+            typed('q');
+            typed('q');
+            typed('x');
+            let expected = [
+                TextEvent::CompositionStart,
+                TextEvent::CompositionUpdate("'".into()),
+                TextEvent::CompositionEnd,
+                TextEvent::Commit("É".into()),
+                TextEvent::CompositionStart,
+                TextEvent::CompositionUpdate("q".into()),
+                TextEvent::CompositionEnd,
+                TextEvent::Commit("x".into()),
+            ];
+            for event in expected {
+                assert_eq!(text.try_recv(), Some(event));
+            }
+            assert_eq!(text.try_recv(), None);
+        }
+    }
+}
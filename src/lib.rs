@@ -34,13 +34,14 @@
 //!
 //! start!();
 //! async fn start() {
+//!     # #[cfg(feature = "log")]
 //!     log!("Hello, world!");
 //! }
 //! ```
 //!
 //! If you want to support WebAssembly, Android, and similar targets you'll also
 //! need **/glue.rs**:
-//! ```rust
+//! ```rust,ignore
 //! include!("src/main.rs");
 //! ```
 
@@ -66,24 +67,55 @@
     variant_size_differences
 )]
 
+#[cfg(any(target_os = "linux", target_arch = "wasm32"))]
+pub mod net;
 pub mod os;
+pub mod time;
 
+#[cfg(any(
+    feature = "keyboard",
+    feature = "mouse",
+    feature = "pad",
+    feature = "touch"
+))]
+mod input;
+#[cfg(target_os = "linux")]
+mod reactor;
 mod start;
 
+pub use start::{spawn, JoinHandle};
+
+#[cfg(feature = "keyboard")]
+pub mod keyboard;
 #[cfg(feature = "log")]
 pub mod log;
+#[cfg(feature = "mouse")]
+pub mod mouse;
+#[cfg(feature = "pad")]
+pub mod pad;
+#[cfg(feature = "touch")]
+pub mod touch;
 
 #[doc(hidden)]
 pub mod _macro {
     #![allow(unsafe_code)]
 
     pub fn start(f: std::pin::Pin<Box<dyn std::future::Future<Output = ()>>>) {
-        unsafe { super::start::start(f) }
+        // Fails if the app already installed a logger, which is fine.
+        #[cfg(feature = "log-facade")]
+        let _ = super::log::init_facade();
+        super::start::start(f)
     }
 
     #[cfg(feature = "log")]
-    pub fn say(text: &str) {
-        super::log::say(text)
+    pub fn log(
+        level: super::log::Level,
+        tag: Option<&super::log::Tag>,
+        file: &'static str,
+        line: u32,
+        args: std::fmt::Arguments<'_>,
+    ) {
+        super::log::log(level, tag, file, line, args)
     }
 }
 
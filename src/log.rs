@@ -1,27 +1,46 @@
 //! Terminal logs.
 
-////////////////////
-//// Wasm32 Web ////
-////////////////////
+use std::collections::VecDeque;
+use std::fmt::{Arguments, Display, Formatter, Result as FmtResult};
+#[cfg(not(target_arch = "wasm32"))]
+use std::fs::{File, OpenOptions};
+#[cfg(not(target_arch = "wasm32"))]
+use std::io::{IsTerminal, Result as IoResult, Write};
+#[cfg(not(target_arch = "wasm32"))]
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::OnceLock;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
+
+#[cfg(feature = "log-facade")]
+mod facade;
+
+#[cfg(feature = "log-facade")]
+pub use facade::init_facade;
+
+// Wasm32 Web //
 
 #[cfg(all(target_arch = "wasm32", not(feature = "wasm-bindgen")))]
 mod ffi {
     extern "C" {
-        pub(super) fn say(size: u32, array: u32);
+        pub(super) fn _cala_js_log(level: u32, array: u32, size: u32);
     }
 }
 
 #[cfg(all(target_arch = "wasm32", not(feature = "wasm-bindgen")))]
 #[allow(unsafe_code)]
-pub(crate) fn say(text: &str) {
+fn say(record: &Record<'_>) {
+    let text = record.to_string();
+    let level = record.level as u32;
     unsafe {
-        ffi::say(text.len() as _, text.as_ptr() as _);
+        ffi::_cala_js_log(level, text.as_ptr() as _, text.len() as _);
     }
 }
 
-/////////////////////////
-//// Wasm32 Wasmpack ////
-/////////////////////////
+// Wasm32 Wasmpack //
 
 #[cfg(all(target_arch = "wasm32", feature = "wasm-bindgen"))]
 mod ffi {
@@ -30,74 +49,592 @@ mod ffi {
 
     #[wasm_bindgen]
     extern "C" {
+        #[wasm_bindgen(js_namespace = console, js_name = error)]
+        pub fn error(data: &str);
+        #[wasm_bindgen(js_namespace = console, js_name = warn)]
+        pub fn warn(data: &str);
         #[wasm_bindgen(js_namespace = console, js_name = info)]
-        pub fn say(data: &str);
+        pub fn info(data: &str);
+        #[wasm_bindgen(js_namespace = console, js_name = debug)]
+        pub fn debug(data: &str);
     }
 }
 
 #[cfg(all(target_arch = "wasm32", feature = "wasm-bindgen"))]
-pub(crate) fn say(text: &str) {
-    ffi::say(text);
+fn say(record: &Record<'_>) {
+    let text = record.to_string();
+    match record.level {
+        Level::Error => ffi::error(&text),
+        Level::Warn => ffi::warn(&text),
+        Level::Info => ffi::info(&text),
+        Level::Debug | Level::Trace => ffi::debug(&text),
+    }
 }
 
-////////////
-//// PC ////
-////////////
+// PC //
 
-#[cfg(not(any(target_arch = "wasm32", target_os = "android")))]
-mod ffi {
-    use crate::os::*;
+// Whether or not to prefix entries with the time since the first entry.
+#[cfg(not(target_arch = "wasm32"))]
+static TIMESTAMPS: AtomicBool = AtomicBool::new(false);
 
-    extern "C" {
-        pub(super) fn write(
-            fd: c_sint,
-            buf: *const c_void,
-            count: c_usize,
-        ) -> c_ssize;
+// When the first entry was logged.
+#[cfg(not(target_arch = "wasm32"))]
+static EPOCH: OnceLock<Instant> = OnceLock::new();
+
+/// Set whether or not the [`Console`] prefixes entries with the monotonic time
+/// (in seconds) since the first entry was logged.  Off by default, and ignored
+/// on the web, where the browser console can show timestamps itself.
+pub fn set_timestamps(enabled: bool) {
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        EPOCH.get_or_init(Instant::now);
+        TIMESTAMPS.store(enabled, Ordering::Relaxed);
     }
+    #[cfg(target_arch = "wasm32")]
+    let _ = enabled;
 }
 
-#[cfg(not(any(target_arch = "wasm32", target_os = "android")))]
-#[allow(unsafe_code)]
-pub(crate) fn say(text: &str) {
-    unsafe {
-        ffi::write(0i16.into(), text.as_ptr().cast(), text.len().into());
-        ffi::write(0i16.into(), b"\n".as_ptr().cast(), 1.into());
+// Write to stderr, with the level colored if stderr is a terminal.
+#[cfg(not(target_arch = "wasm32"))]
+fn say(record: &Record<'_>) {
+    use std::fmt::Write as _;
+
+    let epoch = *EPOCH.get_or_init(Instant::now);
+    let stderr = std::io::stderr();
+    let mut line = String::new();
+    if TIMESTAMPS.load(Ordering::Relaxed) {
+        let time = epoch.elapsed();
+        let _ =
+            write!(line, "[{:5}.{:06}] ", time.as_secs(), time.subsec_micros());
+    }
+    if stderr.is_terminal() {
+        let color = match record.level {
+            Level::Error => 31,
+            Level::Warn => 33,
+            Level::Info => 32,
+            Level::Debug => 34,
+            Level::Trace => 35,
+        };
+        let _ = write!(line, "\x1b[{}m{:<5}\x1b[0m", color, record.level);
+    } else {
+        let _ = write!(line, "{:<5}", record.level);
     }
+    if let (Some(file), Some(num)) = (record.file, record.line) {
+        let _ = write!(line, " {}:{}", file, num);
+    }
+    if let Some(tag) = record.tag {
+        let _ = write!(line, " [{}]", tag);
+    }
+    let _ = writeln!(line, " {}", record.args);
+    // Written all at once, so entries from different threads don't mix.
+    let _ = stderr.lock().write_all(line.as_bytes());
+}
+
+// Leveled Logging //
+
+/// How important a log entry is.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Level {
+    /// Something failed.
+    Error = 1,
+    /// Something might go wrong.
+    Warn = 2,
+    /// Useful information (the level of `log!()`).
+    Info = 3,
+    /// Information for debugging.
+    Debug = 4,
+    /// Very detailed information for debugging.
+    Trace = 5,
 }
 
-/////////////
-//// End ////
-/////////////
+impl Level {
+    fn from_u8(level: u8) -> Option<Self> {
+        Some(match level {
+            1 => Level::Error,
+            2 => Level::Warn,
+            3 => Level::Info,
+            4 => Level::Debug,
+            5 => Level::Trace,
+            _ => return None,
+        })
+    }
 
-/// Tag for log entries.  First element in tuple is the display name, second is
-/// whether or not to print it.
-pub type Tag = (&'static str, bool);
+    /// Get the name of the level in upper case.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
 
-/// Write out debug information to the log.
+impl Display for Level {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.pad(self.as_str())
+    }
+}
+
+// The most verbose level that gets logged (0 for none).
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Trace as u8);
+
+/// Set the most verbose level that gets logged, or `None` to turn off logging.
+/// Defaults to `Some(Level::Trace)` (everything is logged).
+pub fn set_max_level(level: Option<Level>) {
+    MAX_LEVEL.store(level.map(|l| l as u8).unwrap_or(0), Ordering::Relaxed);
+    #[cfg(feature = "log-facade")]
+    facade::set_max_level(level);
+}
+
+/// Get the most verbose level that gets logged, `None` if logging is off.
+pub fn max_level() -> Option<Level> {
+    Level::from_u8(MAX_LEVEL.load(Ordering::Relaxed))
+}
+
+/// Tag for log entries, which can be enabled or disabled at runtime.
+///
+/// ```rust
+/// # #[macro_use] extern crate cala_core;
+/// use cala_core::log::Tag;
+///
+/// static NET: Tag = Tag::new("net");
+///
+/// # fn main() {
+/// info!(NET, "Connected to {}", "example.com");
+/// NET.disable();
+/// info!(NET, "This won't be logged");
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Tag {
+    name: &'static str,
+    enabled: AtomicBool,
+}
+
+impl Tag {
+    /// Create a new enabled tag.
+    pub const fn new(name: &'static str) -> Self {
+        Tag {
+            name,
+            enabled: AtomicBool::new(true),
+        }
+    }
+
+    /// Create a new tag that's disabled until [`enable()`](Tag::enable)d.
+    pub const fn disabled(name: &'static str) -> Self {
+        Tag {
+            name,
+            enabled: AtomicBool::new(false),
+        }
+    }
+
+    /// Get the display name of the tag.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Start logging entries with this tag.
+    pub fn enable(&self) {
+        self.enabled.store(true, Ordering::Relaxed);
+    }
+
+    /// Stop logging entries with this tag.
+    pub fn disable(&self) {
+        self.enabled.store(false, Ordering::Relaxed);
+    }
+
+    /// Check whether or not entries with this tag are logged.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+}
+
+/// Check whether or not an entry with `level` and `tag` would be logged.
+pub fn enabled(level: Level, tag: Option<&Tag>) -> bool {
+    level as u8 <= MAX_LEVEL.load(Ordering::Relaxed)
+        && tag.map(Tag::is_enabled).unwrap_or(true)
+}
+
+pub(crate) fn log(
+    level: Level,
+    tag: Option<&Tag>,
+    file: &'static str,
+    line: u32,
+    args: Arguments<'_>,
+) {
+    if !enabled(level, tag) {
+        return;
+    }
+    dispatch(&Record {
+        level,
+        tag: tag.map(Tag::name),
+        file: Some(file),
+        line: Some(line),
+        args,
+    });
+}
+
+// Get a copy of the sink chain, so that sinks aren't called with the lock
+// held (a sink that logs, or changes the chain, would deadlock).
+fn sinks() -> Option<Vec<Arc<dyn LogSink>>> {
+    SINKS
+        .read()
+        .unwrap_or_else(|poison| poison.into_inner())
+        .clone()
+}
+
+// Pass a record to every sink in the chain.
+fn dispatch(record: &Record<'_>) {
+    match sinks() {
+        Some(sinks) => {
+            for sink in sinks {
+                sink.log(record);
+            }
+        }
+        None => Console.log(record),
+    }
+}
+
+// Sinks //
+
+/// A log entry, passed to each [`LogSink`].
+///
+/// Displays as the level, the source location (if known), the tag (if any) and
+/// then the message.
+#[derive(Debug)]
+pub struct Record<'a> {
+    level: Level,
+    tag: Option<&'a str>,
+    file: Option<&'a str>,
+    line: Option<u32>,
+    args: Arguments<'a>,
+}
+
+impl<'a> Record<'a> {
+    /// Get the level of the entry.
+    pub fn level(&self) -> Level {
+        self.level
+    }
+
+    /// Get the name of the entry's tag (or the module target for entries from
+    /// the `log` crate), if it has one.
+    pub fn tag(&self) -> Option<&'a str> {
+        self.tag
+    }
+
+    /// Get the source file the entry was logged from, if known.
+    pub fn file(&self) -> Option<&'a str> {
+        self.file
+    }
+
+    /// Get the line in the source file the entry was logged from, if known.
+    pub fn line(&self) -> Option<u32> {
+        self.line
+    }
+
+    /// Get the formatted message.
+    pub fn args(&self) -> &Arguments<'a> {
+        &self.args
+    }
+}
+
+impl Display for Record<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{:<5}", self.level)?;
+        if let (Some(file), Some(line)) = (self.file, self.line) {
+            write!(f, " {}:{}", file, line)?;
+        }
+        if let Some(tag) = self.tag {
+            write!(f, " [{}]", tag)?;
+        }
+        write!(f, " {}", self.args)
+    }
+}
+
+/// A destination for log entries.
+///
+/// ```rust
+/// use cala_core::log::{self, LogSink, Record};
+///
+/// struct Stdout;
+///
+/// impl LogSink for Stdout {
+///     fn log(&self, record: &Record<'_>) {
+///         println!("{}", record);
+///     }
+/// }
+///
+/// log::add_sink(Stdout);
+/// ```
+pub trait LogSink: Send + Sync {
+    /// Write out a log entry.
+    fn log(&self, record: &Record<'_>);
+
+    /// Flush any buffered entries.
+    fn flush(&self) {}
+}
+
+impl<T: LogSink + ?Sized> LogSink for Arc<T> {
+    fn log(&self, record: &Record<'_>) {
+        (**self).log(record)
+    }
+
+    fn flush(&self) {
+        (**self).flush()
+    }
+}
+
+impl<T: LogSink + ?Sized> LogSink for Box<T> {
+    fn log(&self, record: &Record<'_>) {
+        (**self).log(record)
+    }
+
+    fn flush(&self) {
+        (**self).flush()
+    }
+}
+
+// The sink chain, `None` until changed (only logging to the `Console`).
+static SINKS: RwLock<Option<Vec<Arc<dyn LogSink>>>> = RwLock::new(None);
+
+/// Add a sink to the end of the chain.  Every entry is passed to each sink in
+/// the chain, which starts out with only [`Console`].
+pub fn add_sink<S: LogSink + 'static>(sink: S) {
+    let mut sinks = SINKS.write().unwrap_or_else(|poison| poison.into_inner());
+    sinks
+        .get_or_insert_with(|| vec![Arc::new(Console)])
+        .push(Arc::new(sink));
+}
+
+/// Remove every sink from the chain, including [`Console`].
+pub fn clear_sinks() {
+    let mut sinks = SINKS.write().unwrap_or_else(|poison| poison.into_inner());
+    *sinks = Some(Vec::new());
+}
+
+/// Flush every sink in the chain.
+pub fn flush() {
+    match sinks() {
+        Some(sinks) => sinks.iter().for_each(|sink| sink.flush()),
+        None => Console.flush(),
+    }
+}
+
+/// The default sink, which writes to stderr on native platforms, and to
+/// the JavaScript console on the web (using `console.error()`,
+/// `console.warn()`, `console.info()` or `console.debug()` depending on the
+/// level).
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Console;
+
+impl LogSink for Console {
+    fn log(&self, record: &Record<'_>) {
+        say(record);
+    }
+}
+
+/// A sink that keeps the most recent entries in memory, useful for checking
+/// what was logged in tests.
+///
+/// ```rust
+/// # #[macro_use] extern crate cala_core;
+/// use cala_core::log::{self, RingBuffer};
+/// use std::sync::Arc;
+///
+/// # fn main() {
+/// let buffer = Arc::new(RingBuffer::new(16));
+/// log::add_sink(buffer.clone());
+/// warn!("Low on {}", "memory");
+/// let lines = buffer.lines();
+/// assert!(lines[0].starts_with("WARN  "));
+/// assert!(lines[0].ends_with(" Low on memory"));
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct RingBuffer {
+    capacity: usize,
+    lines: Mutex<VecDeque<String>>,
+}
+
+impl RingBuffer {
+    /// Create a new ring buffer that keeps up to `capacity` entries.
+    pub fn new(capacity: usize) -> Self {
+        RingBuffer {
+            capacity,
+            lines: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Get the kept entries, oldest first.
+    pub fn lines(&self) -> Vec<String> {
+        self.lock().iter().cloned().collect()
+    }
+
+    /// Remove all of the kept entries.
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    // A panicking sink can't leave the buffer in an invalid state.
+    fn lock(&self) -> MutexGuard<'_, VecDeque<String>> {
+        self.lines
+            .lock()
+            .unwrap_or_else(|poison| poison.into_inner())
+    }
+}
+
+impl LogSink for RingBuffer {
+    fn log(&self, record: &Record<'_>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut lines = self.lock();
+        if lines.len() == self.capacity {
+            lines.pop_front();
+        }
+        lines.push_back(record.to_string());
+    }
+}
+
+/// A sink that appends entries to a file (not available on the web).
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug)]
+pub struct FileSink(Mutex<File>);
+
+#[cfg(not(target_arch = "wasm32"))]
+impl FileSink {
+    /// Open the file at `path` for appending, creating it if it doesn't exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> IoResult<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(FileSink(Mutex::new(file)))
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl From<File> for FileSink {
+    fn from(file: File) -> Self {
+        FileSink(Mutex::new(file))
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl LogSink for FileSink {
+    fn log(&self, record: &Record<'_>) {
+        let mut file =
+            self.0.lock().unwrap_or_else(|poison| poison.into_inner());
+        // Nowhere to report failing to log, so errors are ignored.
+        let _ = writeln!(file, "{}", record);
+    }
+
+    fn flush(&self) {
+        let mut file =
+            self.0.lock().unwrap_or_else(|poison| poison.into_inner());
+        let _ = file.flush();
+    }
+}
+
+/// Write out information to the log, at [`Level::Info`].
+///
+/// Takes an optional [`Tag`], followed by `format!()`-style arguments.
 #[macro_export]
 macro_rules! log {
-    ($text:literal) => {
-        $crate::_macro::say($text)
+    ($($args:tt)+) => {
+        $crate::_log!($crate::log::Level::Info, $($args)+)
+    };
+}
+
+/// Write out an error to the log, at [`Level::Error`].
+#[macro_export]
+macro_rules! error {
+    ($($args:tt)+) => {
+        $crate::_log!($crate::log::Level::Error, $($args)+)
+    };
+}
+
+/// Write out a warning to the log, at [`Level::Warn`].
+#[macro_export]
+macro_rules! warn {
+    ($($args:tt)+) => {
+        $crate::_log!($crate::log::Level::Warn, $($args)+)
     };
+}
 
-    ($text:literal, $($d:expr),+, (,)?) => {
-        let mut string = String::new($text);
-        $(
-            string.write_fmt(format_args!("{}", self))
-                .expect("a Display impl returned an error unexpectedly");
-        )+
-        $crate::_macro::say($text)
+/// Write out information to the log, at [`Level::Info`].
+#[macro_export]
+macro_rules! info {
+    ($($args:tt)+) => {
+        $crate::_log!($crate::log::Level::Info, $($args)+)
     };
+}
 
-    ($tag:ident, $($d:expr),+, (,)?) => {
-        if tag.1 {
-            let mut string = String::new($tag.0);
-            $(
-                string.write_fmt(format_args!("{}", self))
-                    .expect("a Display impl returned an error unexpectedly");
-            )+
-            $crate::_macro::say($text)
-        }
+/// Write out debugging information to the log, at [`Level::Debug`].
+#[macro_export]
+macro_rules! debug {
+    ($($args:tt)+) => {
+        $crate::_log!($crate::log::Level::Debug, $($args)+)
+    };
+}
+
+/// Write out detailed debugging information to the log, at [`Level::Trace`].
+#[macro_export]
+macro_rules! trace {
+    ($($args:tt)+) => {
+        $crate::_log!($crate::log::Level::Trace, $($args)+)
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! _log {
+    ($level:expr, $text:literal $($args:tt)*) => {
+        $crate::_macro::log(
+            $level,
+            None,
+            file!(),
+            line!(),
+            format_args!($text $($args)*),
+        )
+    };
+
+    ($level:expr, $tag:path, $text:literal $($args:tt)*) => {
+        $crate::_macro::log(
+            $level,
+            Some(&$tag),
+            file!(),
+            line!(),
+            format_args!($text $($args)*),
+        )
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static NESTED: Tag = Tag::new("nested");
+
+    // Logs again (and adds a sink) from inside the sink chain.
+    struct Reentrant(Arc<RingBuffer>);
+
+    impl LogSink for Reentrant {
+        fn log(&self, record: &Record<'_>) {
+            if record.tag() != Some("nested") {
+                add_sink(self.0.clone());
+                crate::warn!(NESTED, "Inside {}", record.args());
+            }
+        }
+    }
+
+    #[test]
+    fn reentrant_sink() {
+        let buffer = Arc::new(RingBuffer::new(4));
+        add_sink(Reentrant(buffer.clone()));
+        crate::error!("Outside");
+        let lines = buffer.lines();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].starts_with(&format!("WARN  {}:", file!())));
+        assert!(lines[0].ends_with(" [nested] Inside Outside"));
+    }
+}
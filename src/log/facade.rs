@@ -0,0 +1,78 @@
+// cala_core
+//
+// Copyright (c) 2020 Jeron Aldaron Lau
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// https://apache.org/licenses/LICENSE-2.0>, or the Zlib License, <LICENSE-ZLIB
+// or http://opensource.org/licenses/Zlib>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Routing for entries logged through the `log` crate.
+
+use super::{dispatch, Level, Record};
+use log_crate::{LevelFilter, Metadata, SetLoggerError};
+
+struct Facade;
+
+static FACADE: Facade = Facade;
+
+fn level(level: log_crate::Level) -> Level {
+    match level {
+        log_crate::Level::Error => Level::Error,
+        log_crate::Level::Warn => Level::Warn,
+        log_crate::Level::Info => Level::Info,
+        log_crate::Level::Debug => Level::Debug,
+        log_crate::Level::Trace => Level::Trace,
+    }
+}
+
+fn filter(level: Option<Level>) -> LevelFilter {
+    match level {
+        None => LevelFilter::Off,
+        Some(Level::Error) => LevelFilter::Error,
+        Some(Level::Warn) => LevelFilter::Warn,
+        Some(Level::Info) => LevelFilter::Info,
+        Some(Level::Debug) => LevelFilter::Debug,
+        Some(Level::Trace) => LevelFilter::Trace,
+    }
+}
+
+impl log_crate::Log for Facade {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        super::enabled(level(metadata.level()), None)
+    }
+
+    fn log(&self, record: &log_crate::Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        dispatch(&Record {
+            level: level(record.level()),
+            tag: Some(record.target()),
+            file: record.file(),
+            line: record.line(),
+            args: *record.args(),
+        });
+    }
+
+    fn flush(&self) {
+        super::flush();
+    }
+}
+
+// Keep the `log` crate's filter in sync, so disabled entries aren't formatted.
+pub(super) fn set_max_level(level: Option<Level>) {
+    log_crate::set_max_level(filter(level));
+}
+
+/// Route entries logged through the `log` crate to the sink chain, tagged
+/// with their target (usually the module path).  Entries are filtered by
+/// [`set_max_level()`](super::set_max_level).
+///
+/// This is called by `start!()`, so it's only needed without `start!()`.  It
+/// fails if another logger has already been installed.
+pub fn init_facade() -> Result<(), SetLoggerError> {
+    log_crate::set_logger(&FACADE)?;
+    set_max_level(super::max_level());
+    Ok(())
+}
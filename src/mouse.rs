@@ -0,0 +1,555 @@
+// cala_core
+//
+// Copyright (c) 2020 Jeron Aldaron Lau
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// https://apache.org/licenses/LICENSE-2.0>, or the Zlib License, <LICENSE-ZLIB
+// or http://opensource.org/licenses/Zlib>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Mouse input.
+//!
+//! Events come from pointer and `wheel` events on the web (touches are left
+//! to the touch input), and from mice, touchpads and tablets in `/dev/input`
+//! on Linux.  Other platforms only get events sent with [`inject()`].
+//!
+//! ```rust,no_run
+//! use cala_core::mouse::{Button, Mouse, MouseEvent};
+//!
+//! async fn wait_for_click() -> (f64, f64) {
+//!     let mut mouse = Mouse::new();
+//!     let mut position = (0.0, 0.0);
+//!     loop {
+//!         match mouse.recv().await {
+//!             MouseEvent::Move { x, y, .. } => position = (x, y),
+//!             MouseEvent::ButtonDown(Button::Left) => return position,
+//!             _ => {}
+//!         }
+//!     }
+//! }
+//! ```
+
+use crate::input::{Channel, Receiver};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// A mouse button.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Button {
+    /// Primary button (usually left)
+    Left,
+    /// Middle button (usually pressing the wheel)
+    Middle,
+    /// Secondary button (usually right)
+    Right,
+    /// Side button for going back
+    Back,
+    /// Side button for going forward
+    Forward,
+    /// Another button, numbered by the platform
+    Other(u16),
+}
+
+/// How far to scroll.  Positive `y` scrolls down, and positive `x` scrolls
+/// right.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ScrollDelta {
+    /// Scroll by pixels (from touchpads, and smooth scrolling wheels)
+    Pixels {
+        /// Horizontal distance
+        x: f64,
+        /// Vertical distance
+        y: f64,
+    },
+    /// Scroll by lines of text (from wheels that click)
+    Lines {
+        /// Horizontal distance
+        x: f64,
+        /// Vertical distance
+        y: f64,
+    },
+}
+
+/// Something the mouse did.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MouseEvent {
+    /// The mouse moved.
+    Move {
+        /// Horizontal position of the pointer
+        x: f64,
+        /// Vertical position of the pointer
+        y: f64,
+        /// Horizontal distance moved
+        dx: f64,
+        /// Vertical distance moved
+        dy: f64,
+    },
+    /// A button was pressed.
+    ButtonDown(Button),
+    /// A button was released.
+    ButtonUp(Button),
+    /// The wheel (or touchpad) scrolled.
+    Scroll(ScrollDelta),
+    /// The pointer entered the window (or a mouse was plugged in).
+    Enter,
+    /// The pointer left the window (or the last mouse was unplugged).
+    Leave,
+}
+
+static EVENTS: Channel<MouseEvent> = Channel::new();
+
+static LOCKED: AtomicBool = AtomicBool::new(false);
+
+/// Stream of mouse events, from every mouse.
+///
+/// Positions are in CSS pixels from the top left of the page on the web.  On
+/// Linux there's no window, so the position starts at (0, 0) and follows the
+/// distance moved, which is in device units (touchpads and tablets move the
+/// pointer by how far they're dragged).
+#[derive(Debug)]
+pub struct Mouse(Receiver<MouseEvent>);
+
+impl Mouse {
+    /// Start receiving mouse events, from events that happen after this call.
+    pub fn new() -> Self {
+        #[cfg(target_os = "linux")]
+        crate::input::evdev::listen(
+            crate::input::evdev::Class::Mouse,
+            &native::HANDLER,
+        );
+        #[cfg(target_arch = "wasm32")]
+        web::listen();
+        Mouse(EVENTS.subscribe())
+    }
+
+    /// Wait for the next mouse event.
+    pub async fn recv(&mut self) -> MouseEvent {
+        self.0.recv().await
+    }
+
+    /// Get the next mouse event, if one has already happened.
+    pub fn try_recv(&mut self) -> Option<MouseEvent> {
+        self.0.try_recv()
+    }
+}
+
+impl Default for Mouse {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Send a synthetic mouse event to every [`Mouse`], as if it came from a real
+/// mouse (for testing).
+pub fn inject(event: MouseEvent) {
+    EVENTS.send(event);
+}
+
+/// Lock (or unlock) the pointer, for mouse look in games.  While locked, the
+/// position stops changing, but `Move` events still have the distance moved.
+///
+/// On the web, the browser may only allow locking in response to a click or
+/// key press, and unlocks when escape is pressed; use [`is_locked()`] to
+/// check.
+pub fn set_locked(locked: bool) {
+    #[cfg(target_arch = "wasm32")]
+    web::lock(locked);
+    #[cfg(not(target_arch = "wasm32"))]
+    LOCKED.store(locked, Ordering::Relaxed);
+}
+
+/// Check whether or not the pointer is locked.
+pub fn is_locked() -> bool {
+    LOCKED.load(Ordering::Relaxed)
+}
+
+/// Show or hide the cursor while it's over the page.  Does nothing on other
+/// platforms, which don't draw a cursor.
+pub fn set_cursor_visible(visible: bool) {
+    #[cfg(target_arch = "wasm32")]
+    web::cursor(visible);
+    #[cfg(not(target_arch = "wasm32"))]
+    let _ = visible;
+}
+
+#[cfg(target_os = "linux")]
+mod native {
+    use super::{inject, Button, MouseEvent, ScrollDelta, LOCKED};
+    use crate::input::evdev::{
+        Device, Handler, InputEvent, ABS_X, ABS_Y, BTN_LEFT, BTN_TOUCH, EV_ABS,
+        EV_KEY, EV_REL, EV_SYN, REL_HWHEEL, REL_WHEEL, REL_X, REL_Y,
+        SYN_REPORT,
+    };
+    use std::path::PathBuf;
+    use std::sync::atomic::Ordering;
+    use std::sync::Mutex;
+
+    // Get a button from its code (`BTN_LEFT` to `BTN_TASK`).
+    fn button(code: u16) -> Button {
+        match code - BTN_LEFT {
+            0 => Button::Left,
+            1 => Button::Right,
+            2 => Button::Middle,
+            3 | 6 => Button::Back,
+            4 | 5 => Button::Forward,
+            other => Button::Other(other),
+        }
+    }
+
+    /// A device, and the changes it sent since its last `SYN_REPORT`.
+    pub(super) struct Pointer {
+        path: PathBuf,
+        motion: [i32; 2],
+        scroll: [i32; 2],
+        buttons: Vec<MouseEvent>,
+        // New absolute position, and the last one while touching.
+        absolute: [Option<i32>; 2],
+        last: [Option<i32>; 2],
+    }
+
+    impl Pointer {
+        pub(super) fn new(path: PathBuf) -> Self {
+            Pointer {
+                path,
+                motion: [0, 0],
+                scroll: [0, 0],
+                buttons: Vec::new(),
+                absolute: [None, None],
+                last: [None, None],
+            }
+        }
+
+        /// Decode an event, moving `position` and returning the mouse events
+        /// at the end of each frame (`SYN_REPORT`).
+        pub(super) fn event(
+            &mut self,
+            event: InputEvent,
+            position: &mut [f64; 2],
+        ) -> Vec<MouseEvent> {
+            match (event.kind, event.code) {
+                (EV_SYN, SYN_REPORT) => return self.report(position),
+                (EV_REL, REL_X) => self.motion[0] += event.value,
+                (EV_REL, REL_Y) => self.motion[1] += event.value,
+                // Wheels count up for scrolling up.
+                (EV_REL, REL_WHEEL) => self.scroll[1] -= event.value,
+                (EV_REL, REL_HWHEEL) => self.scroll[0] += event.value,
+                (EV_ABS, ABS_X) => self.absolute[0] = Some(event.value),
+                (EV_ABS, ABS_Y) => self.absolute[1] = Some(event.value),
+                // Lifting a finger off a touchpad doesn't move the pointer
+                // back to where it was put down.
+                (EV_KEY, BTN_TOUCH) if event.value == 0 => {
+                    self.last = [None, None];
+                }
+                (EV_KEY, code) if (BTN_LEFT..BTN_LEFT + 8).contains(&code) => {
+                    let button = button(code);
+                    match event.value {
+                        0 => self.buttons.push(MouseEvent::ButtonUp(button)),
+                        1 => self.buttons.push(MouseEvent::ButtonDown(button)),
+                        _ => {}
+                    }
+                }
+                _ => {}
+            }
+            Vec::new()
+        }
+
+        // Apply the changes, returning the events to send.
+        fn report(&mut self, position: &mut [f64; 2]) -> Vec<MouseEvent> {
+            for axis in 0..2 {
+                if let Some(new) = self.absolute[axis].take() {
+                    if let Some(old) = self.last[axis] {
+                        self.motion[axis] += new - old;
+                    }
+                    self.last[axis] = Some(new);
+                }
+            }
+
+            let mut events = Vec::new();
+            let [dx, dy] = std::mem::take(&mut self.motion);
+            if dx != 0 || dy != 0 {
+                if !LOCKED.load(Ordering::Relaxed) {
+                    position[0] += f64::from(dx);
+                    position[1] += f64::from(dy);
+                }
+                events.push(MouseEvent::Move {
+                    x: position[0],
+                    y: position[1],
+                    dx: dx.into(),
+                    dy: dy.into(),
+                });
+            }
+            events.append(&mut self.buttons);
+            let [x, y] = std::mem::take(&mut self.scroll);
+            if x != 0 || y != 0 {
+                events.push(MouseEvent::Scroll(ScrollDelta::Lines {
+                    x: x.into(),
+                    y: y.into(),
+                }));
+            }
+            events
+        }
+    }
+
+    // The pointer position, shared by every mouse (like on a desktop).
+    struct State {
+        position: [f64; 2],
+        pointers: Vec<Pointer>,
+    }
+
+    pub(super) struct Mice(Mutex<State>);
+
+    pub(super) static HANDLER: Mice = Mice(Mutex::new(State {
+        position: [0.0, 0.0],
+        pointers: Vec::new(),
+    }));
+
+    impl Mice {
+        fn state(&self) -> std::sync::MutexGuard<'_, State> {
+            self.0.lock().unwrap_or_else(|poison| poison.into_inner())
+        }
+    }
+
+    impl Handler for Mice {
+        fn connect(&self, device: &Device) {
+            let first = {
+                let mut state = self.state();
+                state
+                    .pointers
+                    .push(Pointer::new(device.path().to_path_buf()));
+                state.pointers.len() == 1
+            };
+            if first {
+                inject(MouseEvent::Enter);
+            }
+        }
+
+        fn event(&self, device: &Device, event: InputEvent) {
+            let events = {
+                let mut state = self.state();
+                let State { position, pointers } = &mut *state;
+                let pointer = match pointers
+                    .iter_mut()
+                    .find(|pointer| pointer.path == device.path())
+                {
+                    Some(pointer) => pointer,
+                    None => return,
+                };
+                pointer.event(event, position)
+            };
+            for event in events {
+                inject(event);
+            }
+        }
+
+        fn disconnect(&self, device: &Device) {
+            let last = {
+                let mut state = self.state();
+                state
+                    .pointers
+                    .retain(|pointer| pointer.path != device.path());
+                state.pointers.is_empty()
+            };
+            if last {
+                inject(MouseEvent::Leave);
+            }
+        }
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+#[allow(unsafe_code)]
+mod web {
+    use super::{inject, Button, MouseEvent, ScrollDelta, LOCKED};
+    use crate::os::web::JsVar;
+    use std::sync::atomic::Ordering;
+    use std::sync::Once;
+
+    #[cfg(feature = "wasm-bindgen")]
+    use wasm_bindgen::prelude::*;
+
+    #[cfg(not(feature = "wasm-bindgen"))]
+    mod ffi {
+        extern "C" {
+            pub(super) fn _cala_mouse_listen();
+            pub(super) fn _cala_mouse_lock(locked: u32);
+            pub(super) fn _cala_mouse_cursor(visible: u32);
+        }
+    }
+
+    #[cfg(feature = "wasm-bindgen")]
+    mod ffi {
+        use wasm_bindgen::prelude::*;
+
+        #[wasm_bindgen]
+        extern "C" {
+            pub(super) fn _cala_mouse_listen();
+            pub(super) fn _cala_mouse_lock(locked: u32);
+            pub(super) fn _cala_mouse_cursor(visible: u32);
+        }
+    }
+
+    #[allow(unused_unsafe)]
+    pub(super) fn listen() {
+        static LISTEN: Once = Once::new();
+        LISTEN.call_once(|| unsafe { ffi::_cala_mouse_listen() });
+    }
+
+    #[allow(unused_unsafe)]
+    pub(super) fn lock(locked: bool) {
+        // Listens for `pointerlockchange`, to keep `is_locked()` up to date.
+        listen();
+        unsafe { ffi::_cala_mouse_lock(locked.into()) }
+    }
+
+    #[allow(unused_unsafe)]
+    pub(super) fn cursor(visible: bool) {
+        unsafe { ffi::_cala_mouse_cursor(visible.into()) }
+    }
+
+    // Get a button from `MouseEvent.button`.
+    fn button(button: f64) -> Button {
+        match button as u16 {
+            0 => Button::Left,
+            1 => Button::Middle,
+            2 => Button::Right,
+            3 => Button::Back,
+            4 => Button::Forward,
+            other => Button::Other(other),
+        }
+    }
+
+    // Handle a DOM event, stored as `[kind, a, b, c, d]` in heap slot
+    // `event`.  Kinds are 0 for move (`[x, y, dx, dy]`), 1 and 2 for button
+    // down and up (`[button]`), 3 for wheel (`[mode, x, y]`), 4 and 5 for
+    // enter and leave, and 6 for a pointer lock change (`[locked]`).
+    fn event(event: u32) {
+        let event = unsafe { JsVar::from_raw(event) };
+        let (kind, a, b, c, d) = match event.get::<(u8, f64, f64, f64, f64)>() {
+            Ok(event) => event,
+            Err(_) => return,
+        };
+        inject(match kind {
+            0 => MouseEvent::Move {
+                x: a,
+                y: b,
+                dx: c,
+                dy: d,
+            },
+            1 => MouseEvent::ButtonDown(button(a)),
+            2 => MouseEvent::ButtonUp(button(a)),
+            // Scrolling by pages (mode 2) is rare enough to treat as lines.
+            3 if a == 0.0 => {
+                MouseEvent::Scroll(ScrollDelta::Pixels { x: b, y: c })
+            }
+            3 => MouseEvent::Scroll(ScrollDelta::Lines { x: b, y: c }),
+            4 => MouseEvent::Enter,
+            5 => MouseEvent::Leave,
+            _ => {
+                LOCKED.store(a != 0.0, Ordering::Relaxed);
+                return;
+            }
+        });
+    }
+
+    #[cfg(feature = "wasm-bindgen")]
+    #[allow(unreachable_pub)]
+    /// Called by `runtime.js` when a pointer or `wheel` event happens.
+    #[wasm_bindgen]
+    pub fn _cala_mouse_event(slot: u32) {
+        event(slot);
+    }
+
+    #[cfg(not(feature = "wasm-bindgen"))]
+    #[no_mangle]
+    extern "C" fn _cala_mouse_event(slot: u32) {
+        event(slot);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inject_reaches_every_mouse() {
+        let scroll = MouseEvent::Scroll(ScrollDelta::Pixels { x: 0.0, y: 5.0 });
+        let mut first = Mouse::new();
+        let mut second = Mouse::new();
+        inject(MouseEvent::ButtonDown(Button::Left));
+        let mut late = Mouse::new();
+        inject(scroll);
+
+        for mouse in [&mut first, &mut second] {
+            assert_eq!(
+                mouse.try_recv(),
+                Some(MouseEvent::ButtonDown(Button::Left))
+            );
+            assert_eq!(mouse.try_recv(), Some(scroll));
+            assert_eq!(mouse.try_recv(), None);
+        }
+        // Only events after a mouse is created are received.
+        assert_eq!(late.try_recv(), Some(scroll));
+        assert_eq!(late.try_recv(), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn decode_pointer() {
+        use crate::input::evdev::{
+            InputEvent, ABS_X, ABS_Y, BTN_LEFT, BTN_TOUCH, EV_ABS, EV_KEY,
+            EV_REL, EV_SYN, REL_HWHEEL, REL_WHEEL, REL_X, REL_Y, SYN_REPORT,
+        };
+
+        let mut pointer = native::Pointer::new(Default::default());
+        let mut position = [0.0, 0.0];
+        // Send events followed by a `SYN_REPORT`, getting the mouse events.
+        let mut frame = |events: &[(u16, u16, i32)]| {
+            let report = (EV_SYN, SYN_REPORT, 0);
+            let mut frame = Vec::new();
+            for &(kind, code, value) in events.iter().chain([&report]) {
+                let event = InputEvent { kind, code, value };
+                frame.extend(pointer.event(event, &mut position));
+            }
+            frame
+        };
+        let moved = |x, y, dx, dy| MouseEvent::Move { x, y, dx, dy };
+
+        // Relative motion, and buttons after it.
+        assert_eq!(
+            frame(&[(EV_REL, REL_X, 3), (EV_REL, REL_Y, -2)]),
+            [moved(3.0, -2.0, 3.0, -2.0)]
+        );
+        assert_eq!(
+            frame(&[(EV_KEY, BTN_LEFT, 1), (EV_REL, REL_X, 1)]),
+            [
+                moved(4.0, -2.0, 1.0, 0.0),
+                MouseEvent::ButtonDown(Button::Left)
+            ]
+        );
+        assert_eq!(
+            frame(&[(EV_KEY, BTN_LEFT, 0)]),
+            [MouseEvent::ButtonUp(Button::Left)]
+        );
+
+        // Wheels count up for scrolling up, but positive `y` scrolls down.
+        assert_eq!(
+            frame(&[(EV_REL, REL_WHEEL, 1), (EV_REL, REL_HWHEEL, 1)]),
+            [MouseEvent::Scroll(ScrollDelta::Lines { x: 1.0, y: -1.0 })]
+        );
+
+        // Touchpads move by how far they're dragged, starting over each time
+        // the finger is put down.
+        let touch = |x, y| [(EV_ABS, ABS_X, x), (EV_ABS, ABS_Y, y)];
+        assert_eq!(frame(&touch(100, 100)), []);
+        assert_eq!(frame(&touch(110, 105)), [moved(14.0, 3.0, 10.0, 5.0)]);
+        assert_eq!(frame(&[(EV_KEY, BTN_TOUCH, 0)]), []);
+        assert_eq!(frame(&touch(500, 500)), []);
+        assert_eq!(frame(&touch(502, 500)), [moved(16.0, 3.0, 2.0, 0.0)]);
+
+        // Locking keeps the position, but still sends the distance moved.
+        set_locked(true);
+        let locked = frame(&[(EV_REL, REL_X, 5)]);
+        set_locked(false);
+        assert_eq!(locked, [moved(16.0, 3.0, 5.0, 0.0)]);
+        assert_eq!(frame(&[(EV_REL, REL_Y, 1)]), [moved(16.0, 4.0, 0.0, 1.0)]);
+    }
+}
@@ -0,0 +1,41 @@
+// cala_core
+//
+// Copyright (c) 2020 Jeron Aldaron Lau
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// https://apache.org/licenses/LICENSE-2.0>, or the Zlib License, <LICENSE-ZLIB
+// or http://opensource.org/licenses/Zlib>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Asynchronous networking, driven by the `start!()` executor.
+//!
+//! Sockets are only available on Linux, while [`fetch()`] and [`WebSocket`] are
+//! also available on the web.
+//!
+//! ```rust,no_run
+//! use cala_core::net::{TcpListener, TcpStream};
+//!
+//! async fn echo() -> std::io::Result<()> {
+//!     let listener = TcpListener::bind("127.0.0.1:0")?;
+//!     let client = TcpStream::connect(listener.local_addr()?).await?;
+//!     let (server, _addr) = listener.accept().await?;
+//!
+//!     client.write_all(b"ping").await?;
+//!     let mut buf = [0; 4];
+//!     let n = server.read(&mut buf).await?;
+//!     assert_eq!(&buf[..n], b"ping");
+//!     Ok(())
+//! }
+//! ```
+
+mod fetch;
+#[cfg(target_os = "linux")]
+mod http;
+#[cfg(target_os = "linux")]
+mod socket;
+mod websocket;
+
+pub use fetch::{fetch, FetchError, Method, Request, Response};
+#[cfg(target_os = "linux")]
+pub use socket::{TcpListener, TcpStream, UnixListener, UnixStream};
+pub use websocket::{Message, WebSocket, WebSocketError};
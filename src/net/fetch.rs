@@ -0,0 +1,641 @@
+// cala_core
+//
+// Copyright (c) 2020 Jeron Aldaron Lau
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// https://apache.org/licenses/LICENSE-2.0>, or the Zlib License, <LICENSE-ZLIB
+// or http://opensource.org/licenses/Zlib>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! HTTP requests, using `fetch()` on the web and a small HTTP/1.1 client on
+//! native platforms.
+
+use std::error::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::str::Utf8Error;
+
+/// HTTP request method.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Method {
+    /// `GET`
+    Get,
+    /// `HEAD`
+    Head,
+    /// `POST`
+    Post,
+    /// `PUT`
+    Put,
+    /// `DELETE`
+    Delete,
+    /// `PATCH`
+    Patch,
+    /// `OPTIONS`
+    Options,
+}
+
+impl Method {
+    /// Get the method as it's sent over the wire.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Patch => "PATCH",
+            Method::Options => "OPTIONS",
+        }
+    }
+}
+
+impl Display for Method {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.write_str(self.as_str())
+    }
+}
+
+/// An HTTP request, to send with [`fetch()`].
+///
+/// ```rust
+/// use cala_core::net::Request;
+///
+/// let request = Request::post("http://localhost:8080/api")
+///     .header("Content-Type", "application/json")
+///     .body("{}");
+/// ```
+#[derive(Clone, Debug)]
+pub struct Request {
+    method: Method,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    /// Create a new request with an empty body.
+    pub fn new<U: Into<String>>(method: Method, url: U) -> Self {
+        Request {
+            method,
+            url: url.into(),
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    /// Create a new `GET` request.
+    pub fn get<U: Into<String>>(url: U) -> Self {
+        Self::new(Method::Get, url)
+    }
+
+    /// Create a new `POST` request.
+    pub fn post<U: Into<String>>(url: U) -> Self {
+        Self::new(Method::Post, url)
+    }
+
+    /// Add a header to the request.
+    pub fn header<N, V>(mut self, name: N, value: V) -> Self
+    where
+        N: Into<String>,
+        V: Into<String>,
+    {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Set the body of the request.
+    pub fn body<B: Into<Vec<u8>>>(mut self, body: B) -> Self {
+        self.body = body.into();
+        self
+    }
+}
+
+/// An HTTP response, returned from [`fetch()`].
+#[derive(Clone, Debug)]
+pub struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    /// Get the status code.
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// Check whether or not the status code is in the 200-299 range.
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// Get all of the headers, in the order they were received.
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    /// Get the value of the first header named `name` (case-insensitive).
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Get the body.
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// Get the body as UTF-8 text.
+    pub fn text(&self) -> Result<&str, Utf8Error> {
+        std::str::from_utf8(&self.body)
+    }
+
+    /// Take the body.
+    pub fn into_body(self) -> Vec<u8> {
+        self.body
+    }
+}
+
+/// An error from [`fetch()`].
+#[derive(Debug)]
+pub enum FetchError {
+    /// The URL couldn't be parsed, or its scheme isn't supported (only
+    /// `http://` is supported on native platforms).
+    InvalidUrl,
+    /// A request header has an invalid name, or a value with a line break.
+    InvalidHeader,
+    /// The server sent a malformed response.
+    InvalidResponse,
+    /// Connecting, sending or receiving failed (native platforms).
+    Io(std::io::Error),
+    /// The browser failed the request, with its error message (web).
+    Network(Box<str>),
+}
+
+impl Display for FetchError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            FetchError::InvalidUrl => f.write_str("Invalid or unsupported URL"),
+            FetchError::InvalidHeader => f.write_str("Invalid request header"),
+            FetchError::InvalidResponse => f.write_str("Invalid HTTP response"),
+            FetchError::Io(e) => write!(f, "I/O error: {}", e),
+            FetchError::Network(e) => write!(f, "Network error: {}", e),
+        }
+    }
+}
+
+impl Error for FetchError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FetchError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for FetchError {
+    fn from(error: std::io::Error) -> Self {
+        FetchError::Io(error)
+    }
+}
+
+/// Send an HTTP request, and wait for the whole response.
+///
+/// ```rust,no_run
+/// use cala_core::net::{fetch, FetchError, Request};
+///
+/// async fn hello() -> Result<(), FetchError> {
+///     let response = fetch(Request::get("http://localhost:8080/")).await?;
+///     println!("{}: {:?}", response.status(), response.text());
+///     Ok(())
+/// }
+/// ```
+pub async fn fetch(request: Request) -> Result<Response, FetchError> {
+    // Line breaks would let a header inject more headers (or a request).
+    let valid = request.headers.iter().all(|(name, value)| {
+        !name.is_empty()
+            && name.bytes().all(|b| b.is_ascii_graphic() && b != b':')
+            && !value.bytes().any(|b| b == b'\r' || b == b'\n' || b == 0)
+    });
+    if !valid {
+        return Err(FetchError::InvalidHeader);
+    }
+    platform::fetch(request).await
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+use native as platform;
+#[cfg(target_arch = "wasm32")]
+use web as platform;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod native {
+    use super::{FetchError, Method, Request, Response};
+    use crate::net::http::{parse_head, Reader, Url};
+    use crate::net::TcpStream;
+    use std::io::{Error, ErrorKind};
+
+    // Malformed or truncated responses are reported as `InvalidResponse`.
+    fn error(e: Error) -> FetchError {
+        match e.kind() {
+            ErrorKind::InvalidData | ErrorKind::UnexpectedEof => {
+                FetchError::InvalidResponse
+            }
+            _ => FetchError::Io(e),
+        }
+    }
+
+    pub(super) async fn fetch(
+        request: Request,
+    ) -> Result<Response, FetchError> {
+        let url = Url::parse(&request.url, "http://", 80)
+            .ok_or(FetchError::InvalidUrl)?;
+        let stream = TcpStream::connect((url.host, url.port)).await?;
+
+        let mut head = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\n",
+            request.method, url.path, url.authority
+        );
+        let has = |name: &str| {
+            request
+                .headers
+                .iter()
+                .any(|(key, _)| key.eq_ignore_ascii_case(name))
+        };
+        // Only one request per connection, so the end of the response is
+        // known even without a length.
+        if !has("Connection") {
+            head.push_str("Connection: close\r\n");
+        }
+        if !has("Content-Length")
+            && (!request.body.is_empty()
+                || matches!(
+                    request.method,
+                    Method::Post | Method::Put | Method::Patch
+                ))
+        {
+            head.push_str(&format!(
+                "Content-Length: {}\r\n",
+                request.body.len()
+            ));
+        }
+        for (name, value) in &request.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(&request.body).await?;
+
+        let mut reader = Reader::new(stream);
+        // Skip informational (1xx) responses.
+        let (status, headers) = loop {
+            let head = reader.take_until(b"\r\n\r\n").await.map_err(error)?;
+            let (status, headers) = parse_head(&head).map_err(error)?;
+            if !(100..200).contains(&status) {
+                break (status, headers);
+            }
+        };
+        let response = Response {
+            status,
+            headers,
+            body: Vec::new(),
+        };
+
+        let body =
+            if request.method == Method::Head || status == 204 || status == 304
+            {
+                Vec::new()
+            } else if response
+                .header("Transfer-Encoding")
+                .is_some_and(|coding| coding.eq_ignore_ascii_case("chunked"))
+            {
+                reader.take_chunked().await.map_err(error)?
+            } else if let Some(length) = response.header("Content-Length") {
+                let length =
+                    length.parse().map_err(|_| FetchError::InvalidResponse)?;
+                reader.take(length).await.map_err(error)?
+            } else {
+                reader.take_rest().await.map_err(error)?
+            };
+
+        Ok(Response { body, ..response })
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+#[allow(unsafe_code)]
+mod web {
+    use super::{FetchError, Request, Response};
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::{Context, Poll, Waker};
+
+    #[cfg(feature = "wasm-bindgen")]
+    use wasm_bindgen::prelude::*;
+
+    #[cfg(not(feature = "wasm-bindgen"))]
+    mod ffi {
+        extern "C" {
+            pub(super) fn _cala_fetch(
+                id: u32,
+                request: u32,
+                request_len: u32,
+                body: u32,
+                body_len: u32,
+            );
+            pub(super) fn _cala_fetch_read(id: u32, head: u32, body: u32);
+            pub(super) fn _cala_fetch_abort(id: u32);
+        }
+    }
+
+    #[cfg(feature = "wasm-bindgen")]
+    mod ffi {
+        use wasm_bindgen::prelude::*;
+
+        #[wasm_bindgen]
+        extern "C" {
+            pub(super) fn _cala_fetch(
+                id: u32,
+                request: u32,
+                request_len: u32,
+                body: u32,
+                body_len: u32,
+            );
+            pub(super) fn _cala_fetch_read(id: u32, head: u32, body: u32);
+            pub(super) fn _cala_fetch_abort(id: u32);
+        }
+    }
+
+    enum State {
+        Pending(Option<Waker>),
+        Done(Result<Response, FetchError>),
+    }
+
+    struct Fetches {
+        pending: HashMap<u32, State>,
+        next_id: u32,
+    }
+
+    thread_local! {
+        static FETCHES: RefCell<Fetches> = RefCell::new(Fetches {
+            pending: HashMap::new(),
+            next_id: 0,
+        });
+    }
+
+    // Called from runtime.js when a fetch completes.  A `status` of 0 means
+    // the request failed, and the head is the error message; otherwise the
+    // head is the headers as `name: value` lines.
+    #[allow(unused_unsafe)]
+    fn done(id: u32, status: u32, head_len: u32, body_len: u32) {
+        let mut head = vec![0u8; head_len as usize];
+        let mut body = vec![0u8; body_len as usize];
+        unsafe {
+            ffi::_cala_fetch_read(
+                id,
+                head.as_mut_ptr() as u32,
+                body.as_mut_ptr() as u32,
+            )
+        };
+        let head = String::from_utf8_lossy(&head);
+        let result = if status == 0 {
+            Err(FetchError::Network(head.into()))
+        } else {
+            let headers = head
+                .lines()
+                .filter_map(|line| line.split_once(':'))
+                .map(|(name, value)| {
+                    (name.trim().to_string(), value.trim().to_string())
+                })
+                .collect();
+            Ok(Response {
+                status: status as u16,
+                headers,
+                body,
+            })
+        };
+        let waker = FETCHES.with(|fetches| {
+            let mut fetches = fetches.borrow_mut();
+            let state = fetches.pending.get_mut(&id)?;
+            match std::mem::replace(state, State::Done(result)) {
+                State::Pending(waker) => waker,
+                State::Done(_) => None,
+            }
+        });
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    #[cfg(feature = "wasm-bindgen")]
+    #[allow(unreachable_pub)]
+    /// Called by `runtime.js` when a request finishes (or fails).
+    #[wasm_bindgen]
+    pub fn _cala_fetch_done(id: u32, status: u32, head: u32, body: u32) {
+        done(id, status, head, body);
+    }
+
+    #[cfg(not(feature = "wasm-bindgen"))]
+    #[no_mangle]
+    extern "C" fn _cala_fetch_done(id: u32, status: u32, head: u32, body: u32) {
+        done(id, status, head, body);
+    }
+
+    pub(super) async fn fetch(
+        request: Request,
+    ) -> Result<Response, FetchError> {
+        Fetch {
+            request: Some(request),
+            id: 0,
+        }
+        .await
+    }
+
+    // A fetch in progress, aborted when dropped.
+    struct Fetch {
+        request: Option<Request>,
+        id: u32,
+    }
+
+    impl Future for Fetch {
+        type Output = Result<Response, FetchError>;
+
+        #[allow(unused_unsafe)]
+        fn poll(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Self::Output> {
+            if let Some(request) = self.request.take() {
+                let id = FETCHES.with(|fetches| {
+                    let mut fetches = fetches.borrow_mut();
+                    let id = fetches.next_id;
+                    fetches.next_id = id.wrapping_add(1);
+                    let waker = Some(cx.waker().clone());
+                    fetches.pending.insert(id, State::Pending(waker));
+                    id
+                });
+                self.id = id;
+                // Method, URL and headers, one per line.
+                let mut text = format!("{}\n{}\n", request.method, request.url);
+                for (name, value) in &request.headers {
+                    text.push_str(&format!("{}: {}\n", name, value));
+                }
+                unsafe {
+                    ffi::_cala_fetch(
+                        id,
+                        text.as_ptr() as u32,
+                        text.len() as u32,
+                        request.body.as_ptr() as u32,
+                        request.body.len() as u32,
+                    )
+                };
+                return Poll::Pending;
+            }
+            FETCHES.with(|fetches| {
+                let mut fetches = fetches.borrow_mut();
+                match fetches.pending.remove(&self.id) {
+                    Some(State::Done(result)) => Poll::Ready(result),
+                    Some(State::Pending(_)) => {
+                        let waker = Some(cx.waker().clone());
+                        fetches.pending.insert(self.id, State::Pending(waker));
+                        Poll::Pending
+                    }
+                    // Already completed.
+                    None => Poll::Pending,
+                }
+            })
+        }
+    }
+
+    impl Drop for Fetch {
+        #[allow(unused_unsafe)]
+        fn drop(&mut self) {
+            if self.request.is_some() {
+                return;
+            }
+            let pending = FETCHES.with(|fetches| {
+                let mut fetches = fetches.borrow_mut();
+                matches!(
+                    fetches.pending.remove(&self.id),
+                    Some(State::Pending(_))
+                )
+            });
+            if pending {
+                unsafe { ffi::_cala_fetch_abort(self.id) };
+            }
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread::JoinHandle;
+
+    // Serve one request with a canned `response`, returning the request.
+    fn serve(response: &'static [u8]) -> (String, JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url =
+            format!("http://{}/path?query", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut stream, _addr) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buf).unwrap();
+                assert_ne!(n, 0);
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream.write_all(response).unwrap();
+            request
+        });
+        (url, server)
+    }
+
+    fn fetch_blocking(request: Request) -> Result<Response, FetchError> {
+        let result = std::rc::Rc::new(std::cell::RefCell::new(None));
+        let output = result.clone();
+        crate::start::start(Box::pin(async move {
+            *output.borrow_mut() = Some(fetch(request).await);
+        }));
+        let result = result.borrow_mut().take().unwrap();
+        result
+    }
+
+    #[test]
+    fn content_length() {
+        let (url, server) =
+            serve(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello, extra");
+        let request = Request::get(url).header("X-Test", "yes");
+        let response = fetch_blocking(request).unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.body(), b"hello");
+        let request = String::from_utf8(server.join().unwrap()).unwrap();
+        assert!(request.starts_with("GET /path?query HTTP/1.1\r\n"));
+        assert!(request.contains("\r\nX-Test: yes\r\n"));
+        assert!(request.contains("\r\nConnection: close\r\n"));
+    }
+
+    #[test]
+    fn chunked() {
+        let (url, server) = serve(
+            b"HTTP/1.1 100 Continue\r\n\r\n\
+              HTTP/1.1 201 Created\r\nTransfer-Encoding: chunked\r\n\r\n\
+              5;ext=1\r\nhello\r\n7\r\n, world\r\n0\r\nTrailer: x\r\n\r\n",
+        );
+        let response = fetch_blocking(Request::get(url)).unwrap();
+        assert_eq!(response.status(), 201);
+        assert_eq!(response.text(), Ok("hello, world"));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn until_eof() {
+        let (url, server) = serve(b"HTTP/1.0 404 Not Found\r\n\r\nmissing");
+        let response = fetch_blocking(Request::get(url)).unwrap();
+        assert!(!response.is_success());
+        assert_eq!(response.body(), b"missing");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn chunk_size_overflow() {
+        let (url, server) = serve(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+              ffffffffffffffff\r\n",
+        );
+        let error = fetch_blocking(Request::get(url)).unwrap_err();
+        assert!(matches!(error, FetchError::InvalidResponse));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn chunk_without_crlf() {
+        let (url, server) = serve(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+              2\r\nhello\r\n0\r\n\r\n",
+        );
+        let error = fetch_blocking(Request::get(url)).unwrap_err();
+        assert!(matches!(error, FetchError::InvalidResponse));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn header_injection() {
+        let url = "http://127.0.0.1:1/";
+        let request = Request::get(url).header("X-Test", "a\r\nInjected: b");
+        let error = fetch_blocking(request).unwrap_err();
+        assert!(matches!(error, FetchError::InvalidHeader));
+        let request = Request::get(url).header("X-Test\r\nInjected", "b");
+        let error = fetch_blocking(request).unwrap_err();
+        assert!(matches!(error, FetchError::InvalidHeader));
+        let request = Request::get("http://127.0.0.1:1/\r\nInjected: b");
+        let error = fetch_blocking(request).unwrap_err();
+        assert!(matches!(error, FetchError::InvalidUrl));
+    }
+}
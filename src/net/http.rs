@@ -0,0 +1,201 @@
+// cala_core
+//
+// Copyright (c) 2020 Jeron Aldaron Lau
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// https://apache.org/licenses/LICENSE-2.0>, or the Zlib License, <LICENSE-ZLIB
+// or http://opensource.org/licenses/Zlib>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! HTTP/1.1 parsing shared by `fetch()` and `WebSocket` on native platforms.
+//!
+//! Malformed input is reported as `ErrorKind::InvalidData`, and input cut off
+//! by the end of the stream as `ErrorKind::UnexpectedEof`.
+
+use crate::net::TcpStream;
+use std::io::{Error, ErrorKind, Result};
+
+/// Largest response body that's read into memory.
+const MAX_BODY: usize = 64 * 1024 * 1024;
+
+fn invalid() -> Error {
+    Error::new(ErrorKind::InvalidData, "Malformed HTTP")
+}
+
+fn too_large() -> Error {
+    Error::new(ErrorKind::InvalidData, "HTTP body too large")
+}
+
+/// Parts of an `http://` (or `ws://`) URL.
+pub(super) struct Url<'a> {
+    /// Host and port, as written in the URL.
+    pub(super) authority: &'a str,
+    pub(super) host: &'a str,
+    pub(super) port: u16,
+    /// Path and query.
+    pub(super) path: &'a str,
+}
+
+impl<'a> Url<'a> {
+    /// Parse a URL with `scheme` (for example, `"http://"`), returning `None`
+    /// if it's invalid or has a different scheme.
+    pub(super) fn parse(
+        url: &'a str,
+        scheme: &str,
+        default_port: u16,
+    ) -> Option<Self> {
+        if !url.get(..scheme.len())?.eq_ignore_ascii_case(scheme) {
+            return None;
+        }
+        // Would end the request line early, or inject headers.
+        if url.bytes().any(|b| b.is_ascii_control() || b == b' ') {
+            return None;
+        }
+        let rest = &url[scheme.len()..];
+        let rest = rest.split('#').next().unwrap_or(rest);
+        let end = rest.find(['/', '?']).unwrap_or(rest.len());
+        let (authority, path) = rest.split_at(end);
+        let path = if path.is_empty() { "/" } else { path };
+        if authority.is_empty() || authority.contains('@') {
+            return None;
+        }
+        let (host, port) = if let Some(v6) = authority.strip_prefix('[') {
+            let (host, port) = v6.split_once(']')?;
+            (host, port.strip_prefix(':'))
+        } else {
+            match authority.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            }
+        };
+        let port = match port {
+            Some(port) => port.parse().ok()?,
+            None => default_port,
+        };
+        Some(Url {
+            authority,
+            host,
+            port,
+            path,
+        })
+    }
+}
+
+/// Buffered reading from a stream.
+#[derive(Debug)]
+pub(super) struct Reader {
+    pub(super) stream: TcpStream,
+    buf: Vec<u8>,
+}
+
+impl Reader {
+    pub(super) fn new(stream: TcpStream) -> Self {
+        Reader {
+            stream,
+            buf: Vec::new(),
+        }
+    }
+
+    // Read more data into the buffer, returning false at end of stream.
+    async fn fill(&mut self) -> Result<bool> {
+        let mut chunk = [0; 4096];
+        let n = self.stream.read(&mut chunk).await?;
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(n != 0)
+    }
+
+    /// Take `len` bytes from the front of the buffer.
+    pub(super) async fn take(&mut self, len: usize) -> Result<Vec<u8>> {
+        if len > MAX_BODY {
+            return Err(too_large());
+        }
+        while self.buf.len() < len {
+            if !self.fill().await? {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+        }
+        Ok(self.buf.drain(..len).collect())
+    }
+
+    /// Take everything up to and including `delimiter`.
+    pub(super) async fn take_until(
+        &mut self,
+        delimiter: &[u8],
+    ) -> Result<Vec<u8>> {
+        let mut start = 0;
+        loop {
+            if let Some(i) = self.buf[start..]
+                .windows(delimiter.len())
+                .position(|window| window == delimiter)
+            {
+                return self.take(start + i + delimiter.len()).await;
+            }
+            start = self.buf.len().saturating_sub(delimiter.len() - 1);
+            if self.buf.len() > MAX_BODY {
+                return Err(too_large());
+            }
+            if !self.fill().await? {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+
+    /// Read until the end of the stream.
+    pub(super) async fn take_rest(&mut self) -> Result<Vec<u8>> {
+        while self.fill().await? {
+            if self.buf.len() > MAX_BODY {
+                return Err(too_large());
+            }
+        }
+        Ok(std::mem::take(&mut self.buf))
+    }
+
+    /// Decode a `Transfer-Encoding: chunked` body.
+    pub(super) async fn take_chunked(&mut self) -> Result<Vec<u8>> {
+        let mut body = Vec::new();
+        loop {
+            let line = self.take_until(b"\r\n").await?;
+            let line = std::str::from_utf8(&line).map_err(|_| invalid())?;
+            let size = line.trim_end().split(';').next().unwrap_or("").trim();
+            if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(invalid());
+            }
+            // Too many digits also overflows.
+            let size = usize::from_str_radix(size, 16)
+                .ok()
+                .filter(|size| {
+                    body.len()
+                        .checked_add(*size)
+                        .is_some_and(|len| len <= MAX_BODY)
+                })
+                .ok_or_else(too_large)?;
+            if size == 0 {
+                // Skip trailers.
+                while self.take_until(b"\r\n").await?.len() > 2 {}
+                return Ok(body);
+            }
+            body.extend_from_slice(&self.take(size).await?);
+            if self.take(2).await? != b"\r\n" {
+                return Err(invalid());
+            }
+        }
+    }
+}
+
+/// Parse the status line and headers of a response.
+pub(super) fn parse_head(head: &[u8]) -> Result<(u16, Vec<(String, String)>)> {
+    let head = std::str::from_utf8(head).map_err(|_| invalid())?;
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .filter(|line| line.starts_with("HTTP/1."))
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(invalid)?;
+    let mut headers = Vec::new();
+    for line in lines.filter(|line| !line.is_empty()) {
+        let (name, value) = line.split_once(':').ok_or_else(invalid)?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+    Ok((status, headers))
+}
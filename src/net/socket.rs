@@ -0,0 +1,427 @@
+// cala_core
+//
+// Copyright (c) 2020 Jeron Aldaron Lau
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// https://apache.org/licenses/LICENSE-2.0>, or the Zlib License, <LICENSE-ZLIB
+// or http://opensource.org/licenses/Zlib>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! TCP and Unix domain sockets.
+
+#![allow(unsafe_code)]
+
+use crate::os::*;
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind, Result};
+use std::mem::{size_of, ManuallyDrop};
+use std::net::{Shutdown, SocketAddr, ToSocketAddrs};
+use std::os::unix::net::SocketAddr as UnixSocketAddr;
+use std::path::Path;
+
+mod ffi {
+    use crate::os::*;
+
+    pub(super) const AF_INET: i16 = 2;
+    pub(super) const AF_INET6: i16 = 10;
+    pub(super) const SOCK_STREAM: i32 = 1;
+    pub(super) const SOCK_NONBLOCK: i32 = 0o4000;
+    pub(super) const SOCK_CLOEXEC: i32 = 0o2000000;
+    pub(super) const EINPROGRESS: i32 = 115;
+
+    #[repr(C)]
+    pub(super) struct SockAddrIn {
+        pub(super) sin_family: u16,
+        pub(super) sin_port: u16,
+        pub(super) sin_addr: [u8; 4],
+        pub(super) sin_zero: [u8; 8],
+    }
+
+    #[repr(C)]
+    pub(super) struct SockAddrIn6 {
+        pub(super) sin6_family: u16,
+        pub(super) sin6_port: u16,
+        pub(super) sin6_flowinfo: u32,
+        pub(super) sin6_addr: [u8; 16],
+        pub(super) sin6_scope_id: u32,
+    }
+
+    extern "C" {
+        pub(super) fn socket(
+            domain: c_sint,
+            kind: c_sint,
+            protocol: c_sint,
+        ) -> RawFd;
+        pub(super) fn connect(
+            fd: RawFd,
+            addr: *const c_void,
+            len: c_uint,
+        ) -> c_sint;
+    }
+}
+
+// Borrow a connection as a standard library socket to use its methods.  The
+// socket is never dropped, so the connection keeps ownership.
+fn borrow<T: FromRawFd>(connection: &Connection) -> ManuallyDrop<T> {
+    ManuallyDrop::new(unsafe { T::from_raw_fd(connection.as_raw_fd()) })
+}
+
+// Take ownership of a standard library socket.
+fn own<T: IntoRawFd>(socket: T) -> Connection {
+    unsafe { Connection::from_raw_fd(socket.into_raw_fd()) }
+}
+
+/// A TCP socket server, listening for connections.
+#[derive(Debug)]
+pub struct TcpListener(Connection);
+
+impl TcpListener {
+    /// Create a new `TcpListener` bound to the address.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let listener = std::net::TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self(own(listener)))
+    }
+
+    /// Accept a new incoming connection, waiting until one is available.
+    pub async fn accept(&self) -> Result<(TcpStream, SocketAddr)> {
+        loop {
+            match borrow::<std::net::TcpListener>(&self.0).accept() {
+                Ok((stream, addr)) => {
+                    return Ok((TcpStream(own(stream)), addr));
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    self.0.readable().await?
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Get the local address this listener is bound to.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        borrow::<std::net::TcpListener>(&self.0).local_addr()
+    }
+}
+
+impl From<TcpListener> for Connection {
+    fn from(listener: TcpListener) -> Self {
+        listener.0
+    }
+}
+
+/// A TCP stream between a local and a remote socket.
+#[derive(Debug)]
+pub struct TcpStream(Connection);
+
+impl TcpStream {
+    /// Open a TCP connection to a remote host, trying each address in turn.
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let mut error = Error::new(ErrorKind::InvalidInput, "No addresses");
+        for addr in addr.to_socket_addrs()? {
+            match Self::connect_addr(addr).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => error = e,
+            }
+        }
+        Err(error)
+    }
+
+    async fn connect_addr(addr: SocketAddr) -> Result<Self> {
+        let domain = match addr {
+            SocketAddr::V4(_) => ffi::AF_INET,
+            SocketAddr::V6(_) => ffi::AF_INET6,
+        };
+        let kind = ffi::SOCK_STREAM | ffi::SOCK_NONBLOCK | ffi::SOCK_CLOEXEC;
+        let fd = unsafe {
+            ffi::socket(
+                domain.into(),
+                c_sint::try_from(kind).unwrap_or_default(),
+                0i16.into(),
+            )
+        };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        let connection = unsafe { Connection::from_raw_fd(fd) };
+        let ret = match addr {
+            SocketAddr::V4(addr) => {
+                let sockaddr = ffi::SockAddrIn {
+                    sin_family: ffi::AF_INET as u16,
+                    sin_port: addr.port().to_be(),
+                    sin_addr: addr.ip().octets(),
+                    sin_zero: [0; 8],
+                };
+                connect(&connection, &sockaddr)
+            }
+            SocketAddr::V6(addr) => {
+                let sockaddr = ffi::SockAddrIn6 {
+                    sin6_family: ffi::AF_INET6 as u16,
+                    sin6_port: addr.port().to_be(),
+                    sin6_flowinfo: addr.flowinfo(),
+                    sin6_addr: addr.ip().octets(),
+                    sin6_scope_id: addr.scope_id(),
+                };
+                connect(&connection, &sockaddr)
+            }
+        };
+        if let Err(e) = ret {
+            if e.raw_os_error() != Some(ffi::EINPROGRESS) {
+                return Err(e);
+            }
+            // Writable once the connection is established, or has failed.
+            connection.writable().await?;
+            let stream = borrow::<std::net::TcpStream>(&connection);
+            if let Some(e) = stream.take_error()? {
+                return Err(e);
+            }
+        }
+        Ok(Self(connection))
+    }
+
+    /// Read bytes into `buf`, waiting until some are available.  Returns the
+    /// number of bytes read, which is 0 once the remote end has shut down.
+    pub async fn read(&self, buf: &mut [u8]) -> Result<usize> {
+        self.0.read(buf).await
+    }
+
+    /// Write bytes from `buf`, returning the number of bytes written.
+    pub async fn write(&self, buf: &[u8]) -> Result<usize> {
+        self.0.write(buf).await
+    }
+
+    /// Write all of `buf`, waiting as needed.
+    pub async fn write_all(&self, buf: &[u8]) -> Result<()> {
+        self.0.write_all(buf).await
+    }
+
+    /// Get the address of the local end of the stream.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        borrow::<std::net::TcpStream>(&self.0).local_addr()
+    }
+
+    /// Get the address of the remote end of the stream.
+    pub fn peer_addr(&self) -> Result<SocketAddr> {
+        borrow::<std::net::TcpStream>(&self.0).peer_addr()
+    }
+
+    /// Shut down the read half, write half, or both halves of the stream.
+    pub fn shutdown(&self, how: Shutdown) -> Result<()> {
+        borrow::<std::net::TcpStream>(&self.0).shutdown(how)
+    }
+
+    /// Set whether or not Nagle's algorithm is disabled.
+    pub fn set_nodelay(&self, nodelay: bool) -> Result<()> {
+        borrow::<std::net::TcpStream>(&self.0).set_nodelay(nodelay)
+    }
+}
+
+impl From<TcpStream> for Connection {
+    fn from(stream: TcpStream) -> Self {
+        stream.0
+    }
+}
+
+// Start a non-blocking `connect()`.
+fn connect<T>(connection: &Connection, sockaddr: &T) -> Result<()> {
+    let len = size_of::<T>() as u16;
+    let sockaddr: *const T = sockaddr;
+    let fd = connection.as_raw_fd();
+    let ret = unsafe { ffi::connect(fd, sockaddr.cast(), len.into()) };
+    if i32::try_from(ret).unwrap_or(-1) < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// A Unix domain socket server, listening for connections.
+#[derive(Debug)]
+pub struct UnixListener(Connection);
+
+impl UnixListener {
+    /// Create a new `UnixListener` bound to the path.
+    pub fn bind<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let listener = std::os::unix::net::UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self(own(listener)))
+    }
+
+    /// Accept a new incoming connection, waiting until one is available.
+    pub async fn accept(&self) -> Result<(UnixStream, UnixSocketAddr)> {
+        loop {
+            let listener = borrow::<std::os::unix::net::UnixListener>(&self.0);
+            match listener.accept() {
+                Ok((stream, addr)) => {
+                    return Ok((UnixStream(own(stream)), addr));
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    self.0.readable().await?
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Get the local address this listener is bound to.
+    pub fn local_addr(&self) -> Result<UnixSocketAddr> {
+        borrow::<std::os::unix::net::UnixListener>(&self.0).local_addr()
+    }
+}
+
+impl From<UnixListener> for Connection {
+    fn from(listener: UnixListener) -> Self {
+        listener.0
+    }
+}
+
+/// A Unix domain socket stream.
+#[derive(Debug)]
+pub struct UnixStream(Connection);
+
+impl UnixStream {
+    /// Connect to the socket at `path`.  Connecting to a Unix socket doesn't
+    /// wait on the server, so this isn't `async`.
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self(own(std::os::unix::net::UnixStream::connect(path)?)))
+    }
+
+    /// Create a pair of connected sockets.
+    pub fn pair() -> Result<(Self, Self)> {
+        let (a, b) = std::os::unix::net::UnixStream::pair()?;
+        Ok((Self(own(a)), Self(own(b))))
+    }
+
+    /// Read bytes into `buf`, waiting until some are available.  Returns the
+    /// number of bytes read, which is 0 once the remote end has shut down.
+    pub async fn read(&self, buf: &mut [u8]) -> Result<usize> {
+        self.0.read(buf).await
+    }
+
+    /// Write bytes from `buf`, returning the number of bytes written.
+    pub async fn write(&self, buf: &[u8]) -> Result<usize> {
+        self.0.write(buf).await
+    }
+
+    /// Write all of `buf`, waiting as needed.
+    pub async fn write_all(&self, buf: &[u8]) -> Result<()> {
+        self.0.write_all(buf).await
+    }
+
+    /// Get the address of the local end of the stream.
+    pub fn local_addr(&self) -> Result<UnixSocketAddr> {
+        borrow::<std::os::unix::net::UnixStream>(&self.0).local_addr()
+    }
+
+    /// Get the address of the remote end of the stream.
+    pub fn peer_addr(&self) -> Result<UnixSocketAddr> {
+        borrow::<std::os::unix::net::UnixStream>(&self.0).peer_addr()
+    }
+
+    /// Shut down the read half, write half, or both halves of the stream.
+    pub fn shutdown(&self, how: Shutdown) -> Result<()> {
+        borrow::<std::os::unix::net::UnixStream>(&self.0).shutdown(how)
+    }
+}
+
+impl From<UnixStream> for Connection {
+    fn from(stream: UnixStream) -> Self {
+        stream.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block_on(future: impl std::future::Future<Output = ()> + 'static) {
+        crate::start::start(Box::pin(future));
+    }
+
+    // Read until end of file.
+    async fn read_to_end(stream: &UnixStream) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut buf = [0; 4096];
+        loop {
+            match stream.read(&mut buf).await.unwrap() {
+                0 => return data,
+                n => data.extend_from_slice(&buf[..n]),
+            }
+        }
+    }
+
+    #[test]
+    fn tcp_round_trip() {
+        block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let client = TcpStream::connect(addr).await.unwrap();
+            let (server, peer) = listener.accept().await.unwrap();
+            assert_eq!(peer, client.local_addr().unwrap());
+            assert_eq!(server.local_addr().unwrap(), addr);
+
+            client.write_all(b"ping").await.unwrap();
+            let mut buf = [0; 4];
+            let mut read = 0;
+            while read < 4 {
+                read += server.read(&mut buf[read..]).await.unwrap();
+            }
+            assert_eq!(&buf, b"ping");
+
+            server.write_all(b"pong").await.unwrap();
+            server.shutdown(Shutdown::Write).unwrap();
+            let mut buf = [0; 8];
+            let mut read = 0;
+            loop {
+                match client.read(&mut buf[read..]).await.unwrap() {
+                    0 => break,
+                    n => read += n,
+                }
+            }
+            assert_eq!(&buf[..read], b"pong");
+        });
+    }
+
+    #[test]
+    fn tcp_connect_refused() {
+        block_on(async {
+            // Bind to get a free port, then close it so nothing listens.
+            let addr = TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap();
+            let error = TcpStream::connect(addr).await.unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::ConnectionRefused);
+        });
+    }
+
+    #[test]
+    fn unix_pair_large_write() {
+        block_on(async {
+            let (a, b) = UnixStream::pair().unwrap();
+            // Larger than the socket buffer, so writing has to wait.
+            let data: Vec<u8> = (0..1 << 20).map(|i| i as u8).collect();
+            let reader = crate::spawn(async move { read_to_end(&b).await });
+            a.write_all(&data).await.unwrap();
+            a.shutdown(Shutdown::Write).unwrap();
+            assert!(reader.await == data);
+        });
+    }
+
+    #[test]
+    fn unix_listener() {
+        let path = std::env::temp_dir()
+            .join(format!("cala_core-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let client = UnixStream::connect(&path).unwrap();
+        block_on(async move {
+            let (server, _addr) = listener.accept().await.unwrap();
+            client.write_all(b"hello").await.unwrap();
+            drop(client);
+            assert_eq!(read_to_end(&server).await, b"hello");
+        });
+        std::fs::remove_file(&path).unwrap();
+    }
+}
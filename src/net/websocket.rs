@@ -0,0 +1,865 @@
+// cala_core
+//
+// Copyright (c) 2020 Jeron Aldaron Lau
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// https://apache.org/licenses/LICENSE-2.0>, or the Zlib License, <LICENSE-ZLIB
+// or http://opensource.org/licenses/Zlib>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! WebSocket client, using the browser's `WebSocket` on the web and RFC 6455
+//! over a TCP stream on native platforms.
+
+use std::error::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+#[cfg(not(target_arch = "wasm32"))]
+use native as platform;
+#[cfg(target_arch = "wasm32")]
+use web as platform;
+
+/// A WebSocket message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    /// UTF-8 text.
+    Text(String),
+    /// Binary data.
+    Binary(Vec<u8>),
+}
+
+impl From<String> for Message {
+    fn from(text: String) -> Self {
+        Message::Text(text)
+    }
+}
+
+impl From<&str> for Message {
+    fn from(text: &str) -> Self {
+        Message::Text(text.to_string())
+    }
+}
+
+impl From<Vec<u8>> for Message {
+    fn from(data: Vec<u8>) -> Self {
+        Message::Binary(data)
+    }
+}
+
+impl From<&[u8]> for Message {
+    fn from(data: &[u8]) -> Self {
+        Message::Binary(data.to_vec())
+    }
+}
+
+/// An error from a [`WebSocket`].
+#[derive(Debug)]
+pub enum WebSocketError {
+    /// The URL couldn't be parsed, or its scheme isn't supported (only `ws://`
+    /// is supported on native platforms).
+    InvalidUrl,
+    /// The server refused to upgrade the connection to a WebSocket.
+    Handshake,
+    /// The server sent a malformed frame.
+    Protocol,
+    /// The WebSocket is closed, so messages can't be sent.
+    Closed,
+    /// Connecting, sending or receiving failed (native platforms).
+    Io(std::io::Error),
+    /// The browser failed the connection, with its error message (web).
+    Network(Box<str>),
+}
+
+impl Display for WebSocketError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            WebSocketError::InvalidUrl => {
+                f.write_str("Invalid or unsupported URL")
+            }
+            WebSocketError::Handshake => {
+                f.write_str("Server refused the WebSocket handshake")
+            }
+            WebSocketError::Protocol => f.write_str("Invalid WebSocket frame"),
+            WebSocketError::Closed => f.write_str("WebSocket is closed"),
+            WebSocketError::Io(e) => write!(f, "I/O error: {}", e),
+            WebSocketError::Network(e) => write!(f, "Network error: {}", e),
+        }
+    }
+}
+
+impl Error for WebSocketError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WebSocketError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for WebSocketError {
+    fn from(error: std::io::Error) -> Self {
+        WebSocketError::Io(error)
+    }
+}
+
+/// A WebSocket client connection.
+///
+/// ```rust,no_run
+/// use cala_core::net::{Message, WebSocket, WebSocketError};
+///
+/// async fn echo() -> Result<(), WebSocketError> {
+///     let mut socket = WebSocket::connect("ws://localhost:8080/").await?;
+///     socket.send("Hello!").await?;
+///     while let Some(message) = socket.recv().await? {
+///         if let Message::Text(text) = message {
+///             println!("{}", text);
+///         }
+///     }
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug)]
+pub struct WebSocket(platform::Socket);
+
+impl WebSocket {
+    /// Open a WebSocket connection, waiting for the handshake to finish.
+    pub async fn connect(url: &str) -> Result<Self, WebSocketError> {
+        Ok(WebSocket(platform::Socket::connect(url).await?))
+    }
+
+    /// Send a text or binary message.
+    pub async fn send<M: Into<Message>>(
+        &mut self,
+        message: M,
+    ) -> Result<(), WebSocketError> {
+        self.0.send(message.into()).await
+    }
+
+    /// Wait for the next message, returning `None` once the connection has
+    /// been closed.  Pings are answered automatically.
+    pub async fn recv(&mut self) -> Result<Option<Message>, WebSocketError> {
+        self.0.recv().await
+    }
+
+    /// Close the connection, dropping any messages received until the server
+    /// acknowledges.
+    pub async fn close(&mut self) -> Result<(), WebSocketError> {
+        self.0.close().await
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+mod native {
+    use super::{Message, WebSocketError};
+    use crate::net::http::{parse_head, Reader, Url};
+    use crate::net::TcpStream;
+    use std::convert::TryFrom;
+    use std::io::{Error, ErrorKind, Read};
+    use std::net::Shutdown;
+
+    const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+    const CONTINUATION: u8 = 0x0;
+    const TEXT: u8 = 0x1;
+    const BINARY: u8 = 0x2;
+    const CLOSE: u8 = 0x8;
+    const PING: u8 = 0x9;
+    const PONG: u8 = 0xA;
+
+    // Largest message (all fragments together) that's read into memory.
+    const MAX_MESSAGE: usize = 16 * 1024 * 1024;
+
+    // Malformed frames or handshakes are reported as protocol errors.
+    fn error(e: Error) -> WebSocketError {
+        match e.kind() {
+            ErrorKind::InvalidData => WebSocketError::Protocol,
+            _ => WebSocketError::Io(e),
+        }
+    }
+
+    // Random bytes for the handshake key and frame masks, which must be
+    // unpredictable (RFC 6455 section 10.3).
+    fn random<const N: usize>() -> Result<[u8; N], Error> {
+        let mut bytes = [0; N];
+        std::fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn base64(data: &[u8]) -> String {
+        const TABLE: &[u8; 64] =
+            b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let mut out = String::new();
+        for chunk in data.chunks(3) {
+            let byte = |i: usize| u32::from(chunk.get(i).copied().unwrap_or(0));
+            let bits = byte(0) << 16 | byte(1) << 8 | byte(2);
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    let index = (bits >> (18 - 6 * i)) as usize & 63;
+                    out.push(char::from(TABLE[index]));
+                } else {
+                    out.push('=');
+                }
+            }
+        }
+        out
+    }
+
+    fn sha1(data: &[u8]) -> [u8; 20] {
+        let mut state: [u32; 5] =
+            [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+        let mut message = data.to_vec();
+        message.push(0x80);
+        while message.len() % 64 != 56 {
+            message.push(0);
+        }
+        message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+        for block in message.chunks(64) {
+            let mut w = [0u32; 80];
+            for (w, word) in w.iter_mut().zip(block.chunks(4)) {
+                *w = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+            }
+            for i in 16..80 {
+                w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16])
+                    .rotate_left(1);
+            }
+            let [mut a, mut b, mut c, mut d, mut e] = state;
+            for (i, w) in w.iter().enumerate() {
+                let (f, k) = match i {
+                    0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                    20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                    40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                    _ => (b ^ c ^ d, 0xCA62C1D6),
+                };
+                let temp = a
+                    .rotate_left(5)
+                    .wrapping_add(f)
+                    .wrapping_add(e)
+                    .wrapping_add(k)
+                    .wrapping_add(*w);
+                e = d;
+                d = c;
+                c = b.rotate_left(30);
+                b = a;
+                a = temp;
+            }
+            for (state, value) in state.iter_mut().zip([a, b, c, d, e]) {
+                *state = state.wrapping_add(value);
+            }
+        }
+        let mut hash = [0; 20];
+        for (bytes, word) in hash.chunks_mut(4).zip(state) {
+            bytes.copy_from_slice(&word.to_be_bytes());
+        }
+        hash
+    }
+
+    #[derive(Debug)]
+    pub(super) struct Socket {
+        reader: Reader,
+        // A message split into fragments, and its opcode.
+        fragments: Option<(u8, Vec<u8>)>,
+        // Whether or not a close frame has been sent.
+        closing: bool,
+        // Whether or not a close frame has been received.
+        closed: bool,
+    }
+
+    impl Socket {
+        pub(super) async fn connect(url: &str) -> Result<Self, WebSocketError> {
+            let url = Url::parse(url, "ws://", 80)
+                .ok_or(WebSocketError::InvalidUrl)?;
+            let stream = TcpStream::connect((url.host, url.port)).await?;
+            let key = base64(&random::<16>()?);
+            let request = format!(
+                "GET {} HTTP/1.1\r\n\
+                 Host: {}\r\n\
+                 Upgrade: websocket\r\n\
+                 Connection: Upgrade\r\n\
+                 Sec-WebSocket-Key: {}\r\n\
+                 Sec-WebSocket-Version: 13\r\n\r\n",
+                url.path, url.authority, key
+            );
+            stream.write_all(request.as_bytes()).await?;
+
+            let mut reader = Reader::new(stream);
+            let head = reader.take_until(b"\r\n\r\n").await.map_err(error)?;
+            let (status, headers) = parse_head(&head).map_err(error)?;
+            let accept = base64(&sha1(format!("{}{}", key, GUID).as_bytes()));
+            let accepted = headers.iter().any(|(name, value)| {
+                name.eq_ignore_ascii_case("Sec-WebSocket-Accept")
+                    && *value == accept
+            });
+            if status != 101 || !accepted {
+                return Err(WebSocketError::Handshake);
+            }
+            Ok(Socket {
+                reader,
+                fragments: None,
+                closing: false,
+                closed: false,
+            })
+        }
+
+        // Send a single masked frame.
+        async fn send_frame(
+            &self,
+            opcode: u8,
+            payload: &[u8],
+        ) -> Result<(), WebSocketError> {
+            let mut frame = vec![0x80 | opcode];
+            match payload.len() {
+                len @ 0..=125 => frame.push(0x80 | len as u8),
+                len @ 126..=0xFFFF => {
+                    frame.push(0x80 | 126);
+                    frame.extend_from_slice(&(len as u16).to_be_bytes());
+                }
+                len => {
+                    frame.push(0x80 | 127);
+                    frame.extend_from_slice(&(len as u64).to_be_bytes());
+                }
+            }
+            let mask = random::<4>()?;
+            frame.extend_from_slice(&mask);
+            frame.extend(
+                payload.iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m),
+            );
+            Ok(self.reader.stream.write_all(&frame).await?)
+        }
+
+        pub(super) async fn send(
+            &mut self,
+            message: Message,
+        ) -> Result<(), WebSocketError> {
+            if self.closing || self.closed {
+                return Err(WebSocketError::Closed);
+            }
+            match message {
+                Message::Text(text) => {
+                    self.send_frame(TEXT, text.as_bytes()).await
+                }
+                Message::Binary(data) => self.send_frame(BINARY, &data).await,
+            }
+        }
+
+        pub(super) async fn recv(
+            &mut self,
+        ) -> Result<Option<Message>, WebSocketError> {
+            while !self.closed {
+                let head = self.reader.take(2).await?;
+                let (fin, opcode) = (head[0] & 0x80 != 0, head[0] & 0x0F);
+                // Extensions aren't negotiated, so reserved bits must be 0,
+                // and servers must not mask frames.
+                if head[0] & 0x70 != 0 || head[1] & 0x80 != 0 {
+                    return Err(self.fail());
+                }
+                let len = match head[1] & 0x7F {
+                    126 => {
+                        let len = self.reader.take(2).await?;
+                        u64::from(u16::from_be_bytes([len[0], len[1]]))
+                    }
+                    127 => {
+                        let mut bytes = [0; 8];
+                        bytes.copy_from_slice(&self.reader.take(8).await?);
+                        u64::from_be_bytes(bytes)
+                    }
+                    len => u64::from(len),
+                };
+                // Control frames can't be fragmented, and are short.
+                if opcode & 0x8 != 0 && (!fin || len > 125) {
+                    return Err(self.fail());
+                }
+                let buffered = match &self.fragments {
+                    Some((_, data)) if opcode == CONTINUATION => data.len(),
+                    _ => 0,
+                };
+                let len = match usize::try_from(len) {
+                    Ok(len) if len <= MAX_MESSAGE - buffered => len,
+                    _ => return Err(self.fail()),
+                };
+                let payload = self.reader.take(len).await?;
+
+                let (opcode, payload) = match (opcode, self.fragments.take()) {
+                    (CONTINUATION, Some((opcode, mut data))) => {
+                        data.extend_from_slice(&payload);
+                        (opcode, data)
+                    }
+                    (TEXT | BINARY, None) => (opcode, payload),
+                    (CLOSE, fragments) => {
+                        self.fragments = fragments;
+                        self.closed = true;
+                        if !self.closing {
+                            // Echo the status code back.
+                            let code = payload.get(..2).unwrap_or(&[]);
+                            self.closing = true;
+                            self.send_frame(CLOSE, code).await?;
+                        }
+                        let _ = self.reader.stream.shutdown(Shutdown::Both);
+                        break;
+                    }
+                    (PING, fragments) => {
+                        self.fragments = fragments;
+                        if !self.closing {
+                            self.send_frame(PONG, &payload).await?;
+                        }
+                        continue;
+                    }
+                    (PONG, fragments) => {
+                        self.fragments = fragments;
+                        continue;
+                    }
+                    _ => return Err(self.fail()),
+                };
+                if !fin {
+                    self.fragments = Some((opcode, payload));
+                    continue;
+                }
+                return Ok(Some(if opcode == TEXT {
+                    match String::from_utf8(payload) {
+                        Ok(text) => Message::Text(text),
+                        Err(_) => return Err(self.fail()),
+                    }
+                } else {
+                    Message::Binary(payload)
+                }));
+            }
+            Ok(None)
+        }
+
+        // Fail the connection after a protocol error, closing it without a
+        // close frame (RFC 6455 section 7.1.7).
+        fn fail(&mut self) -> WebSocketError {
+            self.closing = true;
+            self.closed = true;
+            let _ = self.reader.stream.shutdown(Shutdown::Both);
+            WebSocketError::Protocol
+        }
+
+        pub(super) async fn close(&mut self) -> Result<(), WebSocketError> {
+            if !self.closing {
+                self.closing = true;
+                // Normal closure.
+                self.send_frame(CLOSE, &1000u16.to_be_bytes()).await?;
+            }
+            while self.recv().await?.is_some() {}
+            Ok(())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::super::{Message, WebSocket, WebSocketError};
+        use super::{base64, sha1, GUID};
+        use std::convert::TryFrom;
+        use std::io::{Read, Write};
+        use std::net::{TcpListener, TcpStream};
+        use std::thread::JoinHandle;
+
+        // Accept one WebSocket connection, and then run `script` on it.
+        fn serve<F>(script: F) -> (String, JoinHandle<()>)
+        where
+            F: FnOnce(&mut TcpStream) + Send + 'static,
+        {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("ws://{}/", listener.local_addr().unwrap());
+            let server = std::thread::spawn(move || {
+                let (mut stream, _addr) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut byte = [0];
+                while !request.ends_with(b"\r\n\r\n") {
+                    stream.read_exact(&mut byte).unwrap();
+                    request.push(byte[0]);
+                }
+                let request = String::from_utf8(request).unwrap();
+                let key = request
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Sec-WebSocket-Key: "))
+                    .unwrap();
+                let accept =
+                    base64(&sha1(format!("{}{}", key, GUID).as_bytes()));
+                write!(
+                    stream,
+                    "HTTP/1.1 101 Switching Protocols\r\n\
+                     Upgrade: websocket\r\n\
+                     Connection: Upgrade\r\n\
+                     Sec-WebSocket-Accept: {}\r\n\r\n",
+                    accept
+                )
+                .unwrap();
+                script(&mut stream);
+            });
+            (url, server)
+        }
+
+        // Read a masked frame from the client, returning its first byte and
+        // unmasked payload.
+        fn read_frame(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+            let mut head = [0; 2];
+            stream.read_exact(&mut head).unwrap();
+            assert_ne!(head[1] & 0x80, 0, "Client frames must be masked");
+            let len = match head[1] & 0x7F {
+                126 => {
+                    let mut len = [0; 2];
+                    stream.read_exact(&mut len).unwrap();
+                    usize::from(u16::from_be_bytes(len))
+                }
+                127 => {
+                    let mut len = [0; 8];
+                    stream.read_exact(&mut len).unwrap();
+                    usize::try_from(u64::from_be_bytes(len)).unwrap()
+                }
+                len => usize::from(len),
+            };
+            let mut mask = [0; 4];
+            stream.read_exact(&mut mask).unwrap();
+            let mut payload = vec![0; len];
+            stream.read_exact(&mut payload).unwrap();
+            for (byte, mask) in payload.iter_mut().zip(mask.iter().cycle()) {
+                *byte ^= mask;
+            }
+            (head[0], payload)
+        }
+
+        // Write an unmasked frame from the server.
+        fn write_frame(stream: &mut TcpStream, first: u8, payload: &[u8]) {
+            let mut frame = vec![first];
+            match payload.len() {
+                len @ 0..=125 => frame.push(len as u8),
+                len @ 126..=0xFFFF => {
+                    frame.push(126);
+                    frame.extend_from_slice(&(len as u16).to_be_bytes());
+                }
+                len => {
+                    frame.push(127);
+                    frame.extend_from_slice(&(len as u64).to_be_bytes());
+                }
+            }
+            frame.extend_from_slice(payload);
+            stream.write_all(&frame).unwrap();
+        }
+
+        // Connect to `url`, and run `client` on the WebSocket.
+        fn connect<F, T>(url: String, client: F)
+        where
+            F: FnOnce(WebSocket) -> T + 'static,
+            T: std::future::Future<Output = ()> + 'static,
+        {
+            crate::start::start(Box::pin(async move {
+                client(WebSocket::connect(&url).await.unwrap()).await
+            }));
+        }
+
+        #[test]
+        fn echo() {
+            let (url, server) = serve(|stream| {
+                // Echo two messages, the second one fragmented.
+                let (first, text) = read_frame(stream);
+                assert_eq!(first, 0x81);
+                write_frame(stream, 0x81, &text);
+                let (first, data) = read_frame(stream);
+                assert_eq!(first, 0x82);
+                write_frame(stream, 0x02, &data[..100]);
+                write_frame(stream, 0x89, b"ping");
+                write_frame(stream, 0x80, &data[100..]);
+                assert_eq!(read_frame(stream), (0x8A, b"ping".to_vec()));
+                // Close, and wait for the client to echo the status code.
+                write_frame(stream, 0x88, &1000u16.to_be_bytes());
+                assert_eq!(read_frame(stream), (0x88, vec![0x03, 0xE8]));
+            });
+            connect(url, |mut socket| async move {
+                socket.send("Hello!").await.unwrap();
+                let message = socket.recv().await.unwrap();
+                assert_eq!(message, Some(Message::Text("Hello!".into())));
+                let data: Vec<u8> = (0..300).map(|i| i as u8).collect();
+                socket.send(data.clone()).await.unwrap();
+                let message = socket.recv().await.unwrap();
+                assert_eq!(message, Some(Message::Binary(data)));
+                assert_eq!(socket.recv().await.unwrap(), None);
+                assert!(matches!(
+                    socket.send("Closed").await,
+                    Err(WebSocketError::Closed)
+                ));
+            });
+            server.join().unwrap();
+        }
+
+        #[test]
+        fn large_frames() {
+            // Big enough to need a 64-bit length.
+            let data: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+            let expected = data.clone();
+            let (url, server) = serve(move |stream| {
+                let (first, data) = read_frame(stream);
+                assert_eq!(first, 0x82);
+                assert_eq!(data, expected);
+                write_frame(stream, 0x82, &data);
+                write_frame(stream, 0x88, &1000u16.to_be_bytes());
+                assert_eq!(read_frame(stream), (0x88, vec![0x03, 0xE8]));
+            });
+            connect(url, |mut socket| async move {
+                socket.send(data.clone()).await.unwrap();
+                let message = socket.recv().await.unwrap();
+                assert_eq!(message, Some(Message::Binary(data)));
+                assert_eq!(socket.recv().await.unwrap(), None);
+            });
+            server.join().unwrap();
+        }
+
+        // Check that the client fails the connection on a `frame`.
+        fn fails_on(frame: &'static [u8]) {
+            let (url, server) = serve(move |stream| {
+                stream.write_all(frame).unwrap();
+                // The connection is closed without a close frame.
+                let mut rest = Vec::new();
+                stream.read_to_end(&mut rest).unwrap();
+                assert!(rest.is_empty());
+            });
+            connect(url, |mut socket| async move {
+                assert!(matches!(
+                    socket.recv().await,
+                    Err(WebSocketError::Protocol)
+                ));
+                assert_eq!(socket.recv().await.unwrap(), None);
+            });
+            server.join().unwrap();
+        }
+
+        #[test]
+        fn masked_frame() {
+            fails_on(b"\x81\x82\x00\x00\x00\x00hi");
+        }
+
+        #[test]
+        fn fragmented_control_frame() {
+            fails_on(b"\x09\x02hi");
+        }
+
+        #[test]
+        fn long_control_frame() {
+            fails_on(b"\x89\x7E\x00\x7E");
+        }
+
+        #[test]
+        fn huge_payload() {
+            fails_on(b"\x82\x7F\x7F\xFF\xFF\xFF\xFF\xFF\xFF\xFF");
+        }
+
+        #[test]
+        fn invalid_utf8() {
+            fails_on(b"\x81\x02\xC3\x28");
+        }
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+#[allow(unsafe_code)]
+mod web {
+    use super::{Message, WebSocketError};
+    use crate::os::Connection;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::future::poll_fn;
+    use std::task::{Poll, Waker};
+
+    #[cfg(feature = "wasm-bindgen")]
+    use wasm_bindgen::prelude::*;
+
+    #[cfg(not(feature = "wasm-bindgen"))]
+    mod ffi {
+        extern "C" {
+            pub(super) fn _cala_ws_open(url: u32, url_len: u32) -> i32;
+            pub(super) fn _cala_ws_state(slot: u32) -> u32;
+            pub(super) fn _cala_ws_send(
+                slot: u32,
+                kind: u32,
+                data: u32,
+                len: u32,
+            ) -> i32;
+            pub(super) fn _cala_ws_next(slot: u32) -> i32;
+            pub(super) fn _cala_ws_read(slot: u32, data: u32) -> u32;
+            pub(super) fn _cala_ws_close(slot: u32);
+        }
+    }
+
+    #[cfg(feature = "wasm-bindgen")]
+    mod ffi {
+        use wasm_bindgen::prelude::*;
+
+        #[wasm_bindgen]
+        extern "C" {
+            pub(super) fn _cala_ws_open(url: u32, url_len: u32) -> i32;
+            pub(super) fn _cala_ws_state(slot: u32) -> u32;
+            pub(super) fn _cala_ws_send(
+                slot: u32,
+                kind: u32,
+                data: u32,
+                len: u32,
+            ) -> i32;
+            pub(super) fn _cala_ws_next(slot: u32) -> i32;
+            pub(super) fn _cala_ws_read(slot: u32, data: u32) -> u32;
+            pub(super) fn _cala_ws_close(slot: u32);
+        }
+    }
+
+    // `WebSocket.readyState` values.
+    const CONNECTING: u32 = 0;
+    const OPEN: u32 = 1;
+
+    // Message kinds, matching the native opcodes.
+    const TEXT: u32 = 0x1;
+    const BINARY: u32 = 0x2;
+    const CLOSE: u32 = 0x8;
+
+    thread_local! {
+        // Wakers for tasks waiting on each WebSocket, by heap slot.
+        static WAKERS: RefCell<HashMap<u32, Waker>> =
+            RefCell::new(HashMap::new());
+    }
+
+    // Called from runtime.js when a WebSocket opens, closes, or receives a
+    // message.
+    fn wake(slot: u32) {
+        let waker = WAKERS.with(|wakers| wakers.borrow_mut().remove(&slot));
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    #[cfg(feature = "wasm-bindgen")]
+    #[allow(unreachable_pub)]
+    /// Called by `runtime.js` when a WebSocket opens, closes, or receives a
+    /// message.
+    #[wasm_bindgen]
+    pub fn _cala_ws_wake(slot: u32) {
+        wake(slot);
+    }
+
+    #[cfg(not(feature = "wasm-bindgen"))]
+    #[no_mangle]
+    extern "C" fn _cala_ws_wake(slot: u32) {
+        wake(slot);
+    }
+
+    #[derive(Debug)]
+    pub(super) struct Socket {
+        // The JavaScript `WebSocket` object.
+        socket: Connection,
+        // Whether or not the close event has been received.
+        closed: bool,
+    }
+
+    impl Socket {
+        fn slot(&self) -> u32 {
+            self.socket.as_raw()
+        }
+
+        #[allow(unused_unsafe)]
+        pub(super) async fn connect(url: &str) -> Result<Self, WebSocketError> {
+            let slot = unsafe {
+                ffi::_cala_ws_open(url.as_ptr() as u32, url.len() as u32)
+            };
+            if slot < 0 {
+                return Err(WebSocketError::InvalidUrl);
+            }
+            let socket = Socket {
+                socket: unsafe { Connection::from_raw(slot as u32) },
+                closed: false,
+            };
+            let state = poll_fn(|cx| {
+                let state = unsafe { ffi::_cala_ws_state(socket.slot()) };
+                if state == CONNECTING {
+                    WAKERS.with(|wakers| {
+                        wakers
+                            .borrow_mut()
+                            .insert(socket.slot(), cx.waker().clone())
+                    });
+                    Poll::Pending
+                } else {
+                    Poll::Ready(state)
+                }
+            })
+            .await;
+            if state != OPEN {
+                return Err(WebSocketError::Network(
+                    "WebSocket connection failed".into(),
+                ));
+            }
+            Ok(socket)
+        }
+
+        #[allow(unused_unsafe)]
+        pub(super) async fn send(
+            &mut self,
+            message: Message,
+        ) -> Result<(), WebSocketError> {
+            let (kind, data) = match &message {
+                Message::Text(text) => (TEXT, text.as_bytes()),
+                Message::Binary(data) => (BINARY, data.as_slice()),
+            };
+            let ret = unsafe {
+                ffi::_cala_ws_send(
+                    self.slot(),
+                    kind,
+                    data.as_ptr() as u32,
+                    data.len() as u32,
+                )
+            };
+            if ret < 0 {
+                return Err(WebSocketError::Closed);
+            }
+            Ok(())
+        }
+
+        #[allow(unused_unsafe)]
+        pub(super) async fn recv(
+            &mut self,
+        ) -> Result<Option<Message>, WebSocketError> {
+            if self.closed {
+                return Ok(None);
+            }
+            let slot = self.slot();
+            let (kind, data) = poll_fn(|cx| {
+                let len = unsafe { ffi::_cala_ws_next(slot) };
+                if len < 0 {
+                    WAKERS.with(|wakers| {
+                        wakers.borrow_mut().insert(slot, cx.waker().clone())
+                    });
+                    return Poll::Pending;
+                }
+                let mut data = vec![0u8; len as usize];
+                let kind = unsafe {
+                    ffi::_cala_ws_read(slot, data.as_mut_ptr() as u32)
+                };
+                Poll::Ready((kind, data))
+            })
+            .await;
+            Ok(match kind {
+                TEXT => Some(Message::Text(
+                    String::from_utf8(data)
+                        .map_err(|_| WebSocketError::Protocol)?,
+                )),
+                BINARY => Some(Message::Binary(data)),
+                CLOSE => {
+                    self.closed = true;
+                    None
+                }
+                _ => return Err(WebSocketError::Protocol),
+            })
+        }
+
+        #[allow(unused_unsafe)]
+        pub(super) async fn close(&mut self) -> Result<(), WebSocketError> {
+            unsafe { ffi::_cala_ws_close(self.slot()) };
+            while self.recv().await?.is_some() {}
+            Ok(())
+        }
+    }
+
+    impl Drop for Socket {
+        #[allow(unused_unsafe)]
+        fn drop(&mut self) {
+            WAKERS.with(|wakers| wakers.borrow_mut().remove(&self.slot()));
+            if !self.closed {
+                unsafe { ffi::_cala_ws_close(self.slot()) };
+            }
+        }
+    }
+}
@@ -9,25 +9,83 @@
 
 //! Operating System Specific APIs
 
-#![allow(non_camel_case_types, trivial_numeric_casts, clippy::useless_conversion)]
+#![allow(
+    non_camel_case_types,
+    trivial_numeric_casts,
+    clippy::useless_conversion
+)]
 
 #[cfg(all(unix, not(target_arch = "wasm32")))]
 pub use std::os::unix::prelude::*;
+#[cfg(all(windows, not(target_arch = "wasm32")))]
+pub use std::os::windows::prelude::*;
 
 use std::{convert::TryFrom, os::raw};
 
 #[cfg(any(feature = "docs-rs", target_arch = "wasm32"))]
 pub mod web;
 
-/// File Descriptor (Unix), Socket (Windows) or Promise (Web)
+/// The raw handle owned by a [`Connection`].
+#[cfg(all(unix, not(target_arch = "wasm32")))]
+pub type RawConnection = RawFd;
+/// The raw handle owned by a [`Connection`].
+#[cfg(all(windows, not(target_arch = "wasm32")))]
+pub type RawConnection = RawSocket;
+/// The raw handle owned by a [`Connection`].
+#[cfg(target_arch = "wasm32")]
+pub type RawConnection = u32;
+
+/// File Descriptor (Unix), Socket (Windows) or JavaScript heap slot (Web),
+/// which is closed (or freed) on drop.
 #[repr(transparent)]
 #[derive(Debug)]
-pub struct Connection(
-    #[cfg(all(unix, not(target_arch = "wasm32")))] RawFd,
-    #[cfg(all(windows, not(target_arch = "wasm32")))]
-    os::windows::io::RawSocket,
-    #[cfg(target_arch = "wasm32")] u32,
-);
+pub struct Connection(RawConnection);
+
+impl Connection {
+    /// Take ownership of a raw file descriptor, socket or JavaScript heap
+    /// slot.
+    ///
+    /// # Safety
+    /// `raw` must be open, and not owned by anything else.
+    #[allow(unsafe_code)]
+    pub unsafe fn from_raw(raw: RawConnection) -> Self {
+        Self(raw)
+    }
+
+    /// Give up ownership of the raw handle without closing it.
+    pub fn into_raw(self) -> RawConnection {
+        #[cfg(target_os = "linux")]
+        crate::reactor::deregister(self.0);
+        let raw = self.0;
+        std::mem::forget(self);
+        raw
+    }
+
+    /// Get the raw handle, without giving up ownership.
+    pub fn as_raw(&self) -> RawConnection {
+        self.0
+    }
+
+    /// Create a new `Connection` that refers to the same underlying file,
+    /// socket or JavaScript object.
+    #[allow(unsafe_code)]
+    pub fn try_clone(&self) -> std::io::Result<Self> {
+        #[cfg(all(unix, not(target_arch = "wasm32")))]
+        {
+            let fd = unsafe { BorrowedFd::borrow_raw(self.0) };
+            Ok(Self(fd.try_clone_to_owned()?.into_raw_fd()))
+        }
+        #[cfg(all(windows, not(target_arch = "wasm32")))]
+        {
+            let socket = unsafe { BorrowedSocket::borrow_raw(self.0) };
+            Ok(Self(socket.try_clone_to_owned()?.into_raw_socket()))
+        }
+        #[cfg(target_arch = "wasm32")]
+        {
+            Ok(Self(web::clone_slot(self.0)))
+        }
+    }
+}
 
 #[cfg(all(unix, not(target_arch = "wasm32")))]
 impl AsRawFd for Connection {
@@ -36,9 +94,220 @@ impl AsRawFd for Connection {
     }
 }
 
+#[cfg(all(unix, not(target_arch = "wasm32")))]
+impl FromRawFd for Connection {
+    #[allow(unsafe_code)]
+    unsafe fn from_raw_fd(fd: RawFd) -> Self {
+        Self(fd)
+    }
+}
+
+#[cfg(all(unix, not(target_arch = "wasm32")))]
+impl IntoRawFd for Connection {
+    fn into_raw_fd(self) -> RawFd {
+        self.into_raw()
+    }
+}
+
+#[cfg(all(windows, not(target_arch = "wasm32")))]
+impl AsRawSocket for Connection {
+    fn as_raw_socket(&self) -> RawSocket {
+        self.0
+    }
+}
+
+#[cfg(all(windows, not(target_arch = "wasm32")))]
+impl FromRawSocket for Connection {
+    #[allow(unsafe_code)]
+    unsafe fn from_raw_socket(socket: RawSocket) -> Self {
+        Self(socket)
+    }
+}
+
+#[cfg(all(windows, not(target_arch = "wasm32")))]
+impl IntoRawSocket for Connection {
+    fn into_raw_socket(self) -> RawSocket {
+        self.into_raw()
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Connection {
+    /// Wait until the connection is readable.
+    pub async fn readable(&self) -> std::io::Result<()> {
+        self.ready(crate::reactor::Interest::Read).await
+    }
+
+    /// Wait until the connection is writable.
+    pub async fn writable(&self) -> std::io::Result<()> {
+        self.ready(crate::reactor::Interest::Write).await
+    }
+
+    /// Read bytes into `buf`, waiting until some are available.  Returns the
+    /// number of bytes read, which is 0 at end of file.
+    #[allow(unsafe_code)]
+    pub async fn read(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let interest = crate::reactor::Interest::Read;
+        std::future::poll_fn(|cx| {
+            self.poll_io(cx, interest, || unsafe {
+                ffi::read(self.0, buf.as_mut_ptr().cast(), buf.len().into())
+            })
+        })
+        .await
+    }
+
+    /// Write bytes from `buf`, waiting until some can be written.  Returns the
+    /// number of bytes written.
+    #[allow(unsafe_code)]
+    pub async fn write(&self, buf: &[u8]) -> std::io::Result<usize> {
+        let interest = crate::reactor::Interest::Write;
+        std::future::poll_fn(|cx| {
+            self.poll_io(cx, interest, || unsafe {
+                ffi::write(self.0, buf.as_ptr().cast(), buf.len().into())
+            })
+        })
+        .await
+    }
+
+    /// Write all of `buf`, waiting as needed.
+    pub async fn write_all(&self, mut buf: &[u8]) -> std::io::Result<()> {
+        while !buf.is_empty() {
+            match self.write(buf).await? {
+                0 => return Err(std::io::ErrorKind::WriteZero.into()),
+                n => buf = &buf[n..],
+            }
+        }
+        Ok(())
+    }
+
+    async fn ready(
+        &self,
+        interest: crate::reactor::Interest,
+    ) -> std::io::Result<()> {
+        use std::task::Poll;
+
+        let reactor = crate::reactor::reactor();
+        reactor.register(self.0)?;
+        std::future::poll_fn(|cx| {
+            if self.is_ready(interest)? {
+                return Poll::Ready(Ok(()));
+            }
+            reactor.set_waker(self.0, interest, cx.waker());
+            // Check again, in case the edge happened before the waker was set
+            // (spurious wakes are then also caught by the check above).
+            if self.is_ready(interest)? {
+                Poll::Ready(Ok(()))
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    // Check readiness without blocking.  Errors and hang ups count as ready,
+    // so that the next operation reports them.
+    #[allow(unsafe_code)]
+    fn is_ready(
+        &self,
+        interest: crate::reactor::Interest,
+    ) -> std::io::Result<bool> {
+        let events = match interest {
+            crate::reactor::Interest::Read => ffi::POLLIN,
+            crate::reactor::Interest::Write => ffi::POLLOUT,
+        };
+        let mut pollfd = ffi::PollFd {
+            fd: self.0,
+            events: events.into(),
+            revents: 0.into(),
+        };
+        loop {
+            let count =
+                unsafe { ffi::poll(&mut pollfd, 1u32.into(), 0.into()) };
+            match i32::try_from(count) {
+                Ok(count) if count >= 0 => break,
+                _ => {}
+            }
+            let error = std::io::Error::last_os_error();
+            if error.kind() != std::io::ErrorKind::Interrupted {
+                return Err(error);
+            }
+        }
+        let revents = i16::try_from(pollfd.revents).unwrap_or_default();
+        let closed = ffi::POLLERR | ffi::POLLHUP | ffi::POLLNVAL;
+        Ok(revents & (events | closed) != 0)
+    }
+
+    // Run a non-blocking syscall, registering the waker if it would block.
+    fn poll_io(
+        &self,
+        cx: &mut std::task::Context<'_>,
+        interest: crate::reactor::Interest,
+        mut syscall: impl FnMut() -> c_ssize,
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        use std::{io::ErrorKind, task::Poll};
+
+        let reactor = crate::reactor::reactor();
+        if let Err(e) = reactor.register(self.0) {
+            return Poll::Ready(Err(e));
+        }
+        loop {
+            if let Ok(n) = usize::try_from(isize::from(syscall())) {
+                return Poll::Ready(Ok(n));
+            }
+            let error = std::io::Error::last_os_error();
+            match error.kind() {
+                ErrorKind::Interrupted => continue,
+                ErrorKind::WouldBlock => {
+                    reactor.set_waker(self.0, interest, cx.waker());
+                    return Poll::Pending;
+                }
+                _ => return Poll::Ready(Err(error)),
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod ffi {
+    use super::*;
+
+    pub(super) const POLLIN: i16 = 0x001;
+    pub(super) const POLLOUT: i16 = 0x004;
+    pub(super) const POLLERR: i16 = 0x008;
+    pub(super) const POLLHUP: i16 = 0x010;
+    pub(super) const POLLNVAL: i16 = 0x020;
+
+    #[repr(C)]
+    pub(super) struct PollFd {
+        pub(super) fd: RawFd,
+        pub(super) events: c_sshort,
+        pub(super) revents: c_sshort,
+    }
+
+    extern "C" {
+        pub(super) fn poll(
+            fds: *mut PollFd,
+            nfds: c_ulong,
+            timeout: c_sint,
+        ) -> c_sint;
+        pub(super) fn read(
+            fd: RawFd,
+            buf: *mut c_void,
+            count: c_usize,
+        ) -> c_ssize;
+        pub(super) fn write(
+            fd: RawFd,
+            buf: *const c_void,
+            count: c_usize,
+        ) -> c_ssize;
+    }
+}
+
 impl Drop for Connection {
     #[allow(unsafe_code)]
     fn drop(&mut self) {
+        #[cfg(target_os = "linux")]
+        crate::reactor::deregister(self.0);
         #[cfg(all(unix, not(target_arch = "wasm32")))]
         {
             extern "C" {
@@ -48,11 +317,11 @@ impl Drop for Connection {
         }
         #[cfg(all(windows, not(target_arch = "wasm32")))]
         {
-            todo!()
+            drop(unsafe { OwnedSocket::from_raw_socket(self.0) });
         }
         #[cfg(target_arch = "wasm32")]
         {
-            todo!()
+            web::free_slot(self.0);
         }
     }
 }
@@ -64,51 +333,51 @@ pub struct c_void(raw::c_void);
 
 /// C `char` (no sign)
 #[repr(transparent)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Default)]
 pub struct c_char(raw::c_char);
 /// C `signed char`
 #[repr(transparent)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Default)]
 pub struct c_schar(raw::c_schar);
 /// C `unsigned char`
 #[repr(transparent)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Default)]
 pub struct c_uchar(raw::c_uchar);
 
 /// C `short`, `signed short`
 #[repr(transparent)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Default)]
 pub struct c_sshort(raw::c_short);
 /// C `unsigned short`
 #[repr(transparent)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Default)]
 pub struct c_ushort(raw::c_ushort);
 
 /// C `int`, `signed int`
 #[repr(transparent)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Default)]
 pub struct c_sint(raw::c_int);
 /// C `unsigned int`
 #[repr(transparent)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Default)]
 pub struct c_uint(raw::c_uint);
 
 /// C `long`, `signed long`
 #[repr(transparent)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Default)]
 pub struct c_slong(raw::c_long);
 /// C `unsigned long`
 #[repr(transparent)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Default)]
 pub struct c_ulong(raw::c_ulong);
 
 /// C `long`, `signed long long`
 #[repr(transparent)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Default)]
 pub struct c_slonglong(raw::c_longlong);
 /// C `unsigned long long`
 #[repr(transparent)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Default)]
 pub struct c_ulonglong(raw::c_ulonglong);
 
 /// C `size_t`
@@ -149,8 +418,6 @@ impl<T> c_ref<T> {
     /// Use a closure to free the memory pointer.
     pub fn free<F: FnOnce(*const T)>(self, f: F) {
         f(self.0);
-        let mut this = self;
-        this.0 = std::ptr::null_mut();
     }
 
     /// Run closure with a reference to the data in the pointer.
@@ -205,72 +472,6 @@ impl<T> Drop for c_ptr<T> {
     }
 }
 
-impl Default for c_char {
-    fn default() -> Self {
-        Self(0)
-    }
-}
-
-impl Default for c_schar {
-    fn default() -> Self {
-        Self(0)
-    }
-}
-
-impl Default for c_uchar {
-    fn default() -> Self {
-        Self(0)
-    }
-}
-
-impl Default for c_sshort {
-    fn default() -> Self {
-        Self(0)
-    }
-}
-
-impl Default for c_ushort {
-    fn default() -> Self {
-        Self(0)
-    }
-}
-
-impl Default for c_sint {
-    fn default() -> Self {
-        Self(0)
-    }
-}
-
-impl Default for c_uint {
-    fn default() -> Self {
-        Self(0)
-    }
-}
-
-impl Default for c_slong {
-    fn default() -> Self {
-        Self(0)
-    }
-}
-
-impl Default for c_ulong {
-    fn default() -> Self {
-        Self(0)
-    }
-}
-
-impl Default for c_slonglong {
-    fn default() -> Self {
-        Self(0)
-    }
-}
-
-impl Default for c_ulonglong {
-    fn default() -> Self {
-        Self(0)
-    }
-}
-
 impl Default for c_float {
     fn default() -> Self {
         Self(0.0)
@@ -395,7 +596,7 @@ impl TryFrom<c_ulong> for u64 {
     type Error = ();
 
     fn try_from(other: c_ulong) -> Result<Self, ()> {
-        Self::try_from(other.0).map_err(|_| ())
+        Ok(Self::from(other.0))
     }
 }
 
@@ -403,7 +604,7 @@ impl TryFrom<c_slong> for i64 {
     type Error = ();
 
     fn try_from(other: c_slong) -> Result<Self, ()> {
-        Self::try_from(other.0).map_err(|_| ())
+        Ok(Self::from(other.0))
     }
 }
 
@@ -411,7 +612,7 @@ impl TryFrom<c_ulonglong> for u128 {
     type Error = ();
 
     fn try_from(other: c_ulonglong) -> Result<Self, ()> {
-        Self::try_from(other.0).map_err(|_| ())
+        Ok(Self::from(other.0))
     }
 }
 
@@ -419,7 +620,7 @@ impl TryFrom<c_slonglong> for i128 {
     type Error = ();
 
     fn try_from(other: c_slonglong) -> Result<Self, ()> {
-        Self::try_from(other.0).map_err(|_| ())
+        Ok(Self::from(other.0))
     }
 }
 
@@ -459,7 +660,7 @@ impl TryFrom<c_float> for f32 {
     type Error = ();
 
     fn try_from(other: c_float) -> Result<Self, ()> {
-        Ok(f32::try_from(other.0).map_err(|_| ())?)
+        f32::try_from(other.0).map_err(|_| ())
     }
 }
 
@@ -467,7 +668,7 @@ impl TryFrom<c_double> for f64 {
     type Error = ();
 
     fn try_from(other: c_double) -> Result<Self, ()> {
-        Ok(f64::try_from(other.0).map_err(|_| ())?)
+        f64::try_from(other.0).map_err(|_| ())
     }
 }
 
@@ -494,3 +695,45 @@ impl From<c_ssize> for isize {
         other.0
     }
 }
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::Connection;
+    use std::io::Write;
+    use std::os::unix::{io::FromRawFd, io::IntoRawFd, net::UnixStream};
+    use std::time::Duration;
+
+    #[allow(unsafe_code)]
+    fn pair() -> (Connection, UnixStream) {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let ours = unsafe { Connection::from_raw_fd(ours.into_raw_fd()) };
+        (ours, theirs)
+    }
+
+    #[test]
+    fn ready_without_waiting() {
+        let (connection, mut peer) = pair();
+        peer.write_all(b"x").unwrap();
+        crate::start::start(Box::pin(async move {
+            connection.writable().await.unwrap();
+            connection.readable().await.unwrap();
+        }));
+    }
+
+    #[test]
+    fn readable_waits_for_data() {
+        let (connection, mut peer) = pair();
+        let writer = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            peer.write_all(b"x").unwrap();
+            peer
+        });
+        crate::start::start(Box::pin(async move {
+            connection.readable().await.unwrap();
+            let mut buf = [0; 2];
+            assert_eq!(connection.read(&mut buf).await.unwrap(), 1);
+            assert_eq!(buf[0], b'x');
+        }));
+        drop(writer.join().unwrap());
+    }
+}
@@ -8,6 +8,33 @@
 // copied, modified, or distributed except according to those terms.
 
 //! Web-Specific APIs
+//!
+//! # Exports
+//! These functions are exported from the module (on both the raw and
+//! `wasm-bindgen` builds) for runtime.js to call, and won't change:
+//!
+//!  - `start()`: Run the `start!()` task.
+//!  - `_cala_promise_resolved(promise: i32, result: i32)`: The promise in
+//!    heap slot `promise` resolved to the value stored in heap slot `result`.
+//!  - `_cala_promise_rejected(promise: i32, error: i32)`: The promise in heap
+//!    slot `promise` was rejected with the value stored in heap slot `error`.
+//!  - `_cala_timer_fired(id: u32)`: A timeout set by `_cala_set_timeout()`
+//!    elapsed.
+//!  - `_cala_fetch_done(id: u32, status: u32, head: u32, body: u32)`: A
+//!    request started by `_cala_fetch()` completed.
+//!  - `_cala_ws_wake(slot: u32)`: The state of a `WebSocket` changed.
+//!  - `_cala_keyboard_event(event: u32)`: A `keydown` or `keyup` event
+//!    happened, stored as `[code, key, pressed, repeat, modifiers]` in heap
+//!    slot `event`.
+//!  - `_cala_text_event(kind: u32, text: u32)`: Text was typed (`kind` 0),
+//!    or composition started, updated or ended (`kind` 1, 2 or 3), with the
+//!    text stored in heap slot `text`.
+//!  - `_cala_mouse_event(event: u32)`: A pointer or `wheel` event happened,
+//!    stored as `[kind, a, b, c, d]` in heap slot `event`.
+//!  - `_cala_touch_event(event: u32)`: A touch pointer event happened, stored
+//!    as `[phase, id, x, y, pressure]` in heap slot `event`.
+//!  - `_cala_pad_event(event: u32)`: A gamepad changed, stored as `[kind,
+//!    index, code, value, name]` in heap slot `event`.
 
 #![allow(unsafe_code)]
 
@@ -17,39 +44,164 @@ use std::{
     future::Future,
     marker::PhantomData,
     pin::Pin,
-    task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
+    task::{Context, Poll, Waker},
 };
 
 #[cfg(feature = "wasm-bindgen")]
 use wasm_bindgen::prelude::*;
 
+mod convert;
+mod error;
+
+pub use convert::{FromJs, JsType, JsTypeError, ToJs};
+pub use error::JsError;
+
+// Imports from runtime.js
+#[cfg(not(feature = "wasm-bindgen"))]
+mod ffi {
+    extern "C" {
+        pub(super) fn _cala_js_typeof(idx: i32) -> u32;
+        pub(super) fn _cala_js_store_bool(value: u32) -> i32;
+        pub(super) fn _cala_js_load_bool(idx: i32) -> u32;
+        pub(super) fn _cala_js_null() -> i32;
+        pub(super) fn _cala_js_length(idx: i32) -> u32;
+        pub(super) fn _cala_js_index(idx: i32, index: u32) -> i32;
+        pub(super) fn _cala_js_bytes(p: u32, l: u32) -> i32;
+        pub(super) fn _cala_js_array(p: u32, l: u32) -> i32;
+        pub(super) fn _cala_js_function(idx: i32) -> i32;
+        pub(super) fn _cala_js_global(idx: i32) -> i32;
+        pub(super) fn _cala_js_call(
+            function: i32,
+            this: i32,
+            p: u32,
+            l: u32,
+        ) -> i32;
+        pub(super) fn _cala_js_thrown() -> i32;
+        pub(super) fn _cala_js_error(idx: i32, field: u32) -> i32;
+        pub(super) fn _cala_js_cancel(idx: i32);
+    }
+}
+
+// Imports from runtime.js
+#[cfg(feature = "wasm-bindgen")]
+mod ffi {
+    use wasm_bindgen::prelude::*;
+
+    #[wasm_bindgen]
+    extern "C" {
+        pub(super) fn _cala_js_typeof(idx: i32) -> u32;
+        pub(super) fn _cala_js_store_bool(value: u32) -> i32;
+        pub(super) fn _cala_js_load_bool(idx: i32) -> u32;
+        pub(super) fn _cala_js_null() -> i32;
+        pub(super) fn _cala_js_length(idx: i32) -> u32;
+        pub(super) fn _cala_js_index(idx: i32, index: u32) -> i32;
+        pub(super) fn _cala_js_bytes(p: u32, l: u32) -> i32;
+        pub(super) fn _cala_js_array(p: u32, l: u32) -> i32;
+        pub(super) fn _cala_js_function(idx: i32) -> i32;
+        pub(super) fn _cala_js_global(idx: i32) -> i32;
+        pub(super) fn _cala_js_call(
+            function: i32,
+            this: i32,
+            p: u32,
+            l: u32,
+        ) -> i32;
+        pub(super) fn _cala_js_thrown() -> i32;
+        pub(super) fn _cala_js_error(idx: i32, field: u32) -> i32;
+        pub(super) fn _cala_js_cancel(idx: i32);
+    }
+}
+
 thread_local! {
-    // A map of the resolved promises / ready futures
-    static READY: RefCell<HashMap<i32, JsVar>> = RefCell::new(HashMap::new())
+    // A map of the settled promises / ready futures (`Err` if rejected)
+    static READY: RefCell<HashMap<i32, Result<JsVar, JsVar>>> =
+        RefCell::new(HashMap::new());
+    // Wakers for the tasks awaiting each promise
+    static WAKERS: RefCell<HashMap<i32, Waker>> = RefCell::new(HashMap::new());
+}
+
+// Store the settled value of a promise, and wake the task awaiting it.
+fn settle(promise: i32, value: Result<JsVar, JsVar>) {
+    READY.with(|w| w.borrow_mut().insert(promise, value));
+    let waker = WAKERS.with(|w| w.borrow_mut().remove(&promise));
+    if let Some(waker) = waker {
+        waker.wake();
+    }
 }
 
 // Run whenever a Promise resolves.
 fn wake_internal(promise: i32, result: i32) {
-    // Promise resolving marks the future as "ready" with a value in the map.
-    READY.with(|w| w.borrow_mut().insert(promise, JsVar(result)));
-    // Promise resolving wakes the thread, so run the executor.
-    executor();
+    settle(promise, Ok(JsVar(result)));
 }
 
-/// A JavaScript Promise
+// Run whenever a Promise rejects.
+fn reject_internal(promise: i32, error: i32) {
+    settle(promise, Err(JsVar(error)));
+}
+
+#[cfg(feature = "wasm-bindgen")]
+#[allow(unreachable_pub)]
+/// Called by `runtime.js` when a promise resolves.
+#[wasm_bindgen]
+pub fn _cala_promise_resolved(promise: i32, result: i32) {
+    wake_internal(promise, result);
+}
+
+#[cfg(not(feature = "wasm-bindgen"))]
+#[no_mangle]
+extern "C" fn _cala_promise_resolved(promise: i32, result: i32) {
+    wake_internal(promise, result);
+}
+
+#[cfg(feature = "wasm-bindgen")]
+#[allow(unreachable_pub)]
+/// Called by `runtime.js` when a promise is rejected.
+#[wasm_bindgen]
+pub fn _cala_promise_rejected(promise: i32, error: i32) {
+    reject_internal(promise, error);
+}
+
+#[cfg(not(feature = "wasm-bindgen"))]
+#[no_mangle]
+extern "C" fn _cala_promise_rejected(promise: i32, error: i32) {
+    reject_internal(promise, error);
+}
+
+/// A JavaScript Promise, which can be `.await`ed.  Fails if the promise is
+/// rejected, or if the value it resolved to can't be converted to `T`.
 #[derive(Debug)]
-pub struct JsPromise<T: From<JsVar>>(JsVar, PhantomData<T>);
-
-impl<T: From<JsVar>> JsPromise<T> {
-    /// Poll a JavaScript Promise.
-    pub fn poll(&self) -> Poll<T> {
-        READY.with(|w| {
-            if let Some(value) = w.borrow_mut().remove(&(self.0).0) {
-                Poll::Ready(value.into())
-            } else {
+pub struct JsPromise<T: FromJs>(JsVar, PhantomData<T>);
+
+impl<T: FromJs> JsPromise<T> {
+    /// Detach from the promise, so that it settling no longer wakes the
+    /// executor.  Dropping a `JsPromise` does the same.
+    pub fn cancel(self) {}
+}
+
+impl<T: FromJs> Future for JsPromise<T> {
+    type Output = Result<T, JsError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let promise = (self.0).0;
+        // Dropping the value uses `READY`, so it must be removed first.
+        let settled = READY.with(|w| w.borrow_mut().remove(&promise));
+        match settled {
+            Some(Ok(value)) => Poll::Ready(Ok(T::from_js(&value)?)),
+            Some(Err(error)) => Poll::Ready(Err(JsError::from_var(&error))),
+            None => {
+                WAKERS.with(|w| {
+                    w.borrow_mut().insert(promise, cx.waker().clone())
+                });
                 Poll::Pending
             }
-        })
+        }
+    }
+}
+
+impl<T: FromJs> Drop for JsPromise<T> {
+    #[allow(unused_unsafe)]
+    fn drop(&mut self) {
+        unsafe { ffi::_cala_js_cancel((self.0).0) };
+        WAKERS.with(|w| w.borrow_mut().remove(&(self.0).0));
     }
 }
 
@@ -58,14 +210,28 @@ impl<T: From<JsVar>> JsPromise<T> {
 pub struct JsVar(i32);
 
 impl JsVar {
+    /// Take ownership of a heap slot passed in from runtime.js.
+    ///
+    /// # Safety
+    /// The slot must be allocated, and not owned by anything else.
+    #[cfg(any(
+        feature = "keyboard",
+        feature = "mouse",
+        feature = "pad",
+        feature = "touch"
+    ))]
+    pub(crate) unsafe fn from_raw(slot: u32) -> Self {
+        JsVar(slot as i32)
+    }
+
     /// Assume the JavaScript variable is a promise, and convert to a JsPromise.
-    pub unsafe fn into_promise<T: From<JsVar>>(self) -> JsPromise<T> {
+    pub unsafe fn into_promise<T: FromJs>(self) -> JsPromise<T> {
         self.set_waker_internal();
         JsPromise(self, PhantomData)
     }
 
-    /// Assuming the JavaScript variable is a function with two parameters,
-    /// convert into a `JsFn`.
+    /// Assume the JavaScript variable is a function, and convert into a
+    /// `JsFn`.
     pub unsafe fn into_fn(self) -> JsFn {
         JsFn(self)
     }
@@ -486,6 +652,49 @@ impl Drop for JsVar {
     }
 }
 
+#[cfg(target_arch = "wasm32")]
+impl From<JsVar> for super::Connection {
+    fn from(var: JsVar) -> Self {
+        let slot = var.0 as u32;
+        std::mem::forget(var);
+        super::Connection(slot)
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+impl From<super::Connection> for JsVar {
+    fn from(connection: super::Connection) -> Self {
+        JsVar(connection.into_raw() as i32)
+    }
+}
+
+// Free the JavaScript heap slot owned by a `Connection`.
+pub(super) fn free_slot(slot: u32) {
+    drop(JsVar(slot as i32));
+}
+
+// Copy a reference to a JavaScript object into a new heap slot.
+#[cfg(feature = "wasm-bindgen")]
+pub(super) fn clone_slot(slot: u32) -> u32 {
+    #[wasm_bindgen]
+    extern "C" {
+        fn _cala_js_clone(idx: i32) -> i32;
+    }
+
+    _cala_js_clone(slot as i32) as u32
+}
+
+// Copy a reference to a JavaScript object into a new heap slot.
+#[cfg(not(feature = "wasm-bindgen"))]
+pub(super) fn clone_slot(slot: u32) -> u32 {
+    extern "C" {
+        // Copy a JavaScript object reference
+        fn _cala_js_clone(idx: i32) -> i32;
+    }
+
+    unsafe { _cala_js_clone(slot as i32) as u32 }
+}
+
 /// A JavaScript String
 #[derive(Debug)]
 pub struct JsString(JsVar);
@@ -545,170 +754,138 @@ impl JsString {
 pub struct JsFn(JsVar);
 
 impl JsFn {
-    /// Define a function (two parameters param_a: u32, and param_b: u32,
-    /// returns a u32)
-    #[cfg(feature = "wasm-bindgen")]
-    pub unsafe fn new(string: &str) -> JsFn {
-        #[wasm_bindgen]
-        extern "C" {
-            // Execute some JavaScript string.
-            fn _cala_js_function(idx: i32) -> i32;
-        }
-
+    /// Define a function with named parameters, from a JavaScript function
+    /// body.
+    ///
+    /// ```rust,no_run
+    /// use cala_core::os::web::{JsFn, ToJs};
+    ///
+    /// let add = unsafe { JsFn::new(&["a", "b"], "return a + b;") };
+    /// let sum = unsafe { add.call(&[&1.to_js(), &2.to_js()]) };
+    /// ```
+    #[allow(unused_unsafe)]
+    pub unsafe fn new(params: &[&str], body: &str) -> JsFn {
         let javascript = format!(
-            "\
-            \"use strict\";\
-            return function(param_a, param_b) {{ {} }};\
-            ",
-            string
+            "\"use strict\";return function({}) {{ {} }};",
+            params.join(", "),
+            body,
         );
 
         let string = JsString::new(&javascript);
-        let func = _cala_js_function(string.as_var().0);
+        let func = unsafe { ffi::_cala_js_function(string.as_var().0) };
 
         JsFn(JsVar(func))
     }
 
-    /// Define a function (two parameters param_a: u32, and param_b: u32,
-    /// returns a u32)
-    #[cfg(not(feature = "wasm-bindgen"))]
-    pub unsafe fn new(string: &str) -> JsFn {
-        extern "C" {
-            // Execute some JavaScript string.
-            fn _cala_js_function(idx: i32) -> i32;
-        }
-
-        let javascript = format!(
-            "\
-            \"use strict\";\
-            return function(param_a, param_b) {{ {} }};\
-        ",
-            string
-        );
-
-        let string = JsString::new(&javascript);
-        let func = _cala_js_function(string.as_var().0);
-
-        JsFn(JsVar(func))
-    }
-
-    /// Call a JavaScript function.
-    #[cfg(feature = "wasm-bindgen")]
-    pub unsafe fn call(
-        &self,
-        a: Option<&JsVar>,
-        b: Option<&JsVar>,
-    ) -> Option<JsVar> {
-        #[wasm_bindgen]
-        extern "C" {
-            // A generic javascript shim
-            fn _cala_js_call(function: i32, param_a: i32, param_b: i32) -> i32;
-        }
-        let ret = _cala_js_call(
-            (self.0).0,
-            a.map(|x| x.0).unwrap_or(-1),
-            b.map(|x| x.0).unwrap_or(-1),
-        );
-        if ret == -1 {
+    /// Look up an existing function by its path from the global object (for
+    /// example, `"console.log"`), without evaluating any JavaScript.  Returns
+    /// `None` if the path doesn't lead to a function.
+    ///
+    /// Functions found on an object (rather than the global object itself)
+    /// are bound to that object, so `"document.getElementById"` can be called
+    /// directly.
+    #[allow(unused_unsafe)]
+    pub fn global(path: &str) -> Option<JsFn> {
+        let path = JsString::new(path);
+        let func = unsafe { ffi::_cala_js_global(path.as_var().0) };
+        if func == -1 {
             None
         } else {
-            Some(JsVar(ret))
+            Some(JsFn(JsVar(func)))
         }
     }
 
-    /// Call a JavaScript function.
-    #[cfg(not(feature = "wasm-bindgen"))]
+    /// Call a JavaScript function, with any number of arguments.  Returns
+    /// `Ok(None)` if it returns `undefined`, or the exception if it throws.
+    ///
+    /// # Safety
+    /// The function runs arbitrary JavaScript, which must not write to
+    /// WebAssembly memory that Rust is using, or free variables that Rust
+    /// still owns.
     pub unsafe fn call(
         &self,
-        a: Option<&JsVar>,
-        b: Option<&JsVar>,
-    ) -> Option<JsVar> {
-        extern "C" {
-            // A generic javascript shim
-            fn _cala_js_call(function: i32, param_a: i32, param_b: i32) -> i32;
-        }
-        let ret = _cala_js_call(
-            (self.0).0,
-            a.map(|x| x.0).unwrap_or(-1),
-            b.map(|x| x.0).unwrap_or(-1),
-        );
-        if ret == -1 {
-            None
-        } else {
-            Some(JsVar(ret))
-        }
+        args: &[&JsVar],
+    ) -> Result<Option<JsVar>, JsError> {
+        self.call_internal(-1, args)
+    }
+
+    /// Call a JavaScript function as a method of `this`.  Returns `Ok(None)`
+    /// if it returns `undefined`, or the exception if it throws.
+    ///
+    /// # Safety
+    /// The function runs arbitrary JavaScript, which must not write to
+    /// WebAssembly memory that Rust is using, or free variables that Rust
+    /// still owns.
+    pub unsafe fn call_method(
+        &self,
+        this: &JsVar,
+        args: &[&JsVar],
+    ) -> Result<Option<JsVar>, JsError> {
+        self.call_internal(this.0, args)
     }
-}
-
-thread_local!(
-    static FUTURE: RefCell<Option<Pin<Box<dyn Future<Output = ()>>>>> =
-        RefCell::new(None);
-);
-
-/// WASM is non-blocking, but after the function returns, the Future will be
-/// running on the main (and only) thread inside the JavaScript executor.
-pub fn block_on<F: Future<Output = ()> + 'static>(main: F) {
-    FUTURE.with(move |future| {
-        *future.borrow_mut() = Some(Box::pin(async {
-            panic_hook();
-            main.await;
-        }));
-    });
-    executor();
-}
 
-fn executor() {
-    FUTURE.with(|future| {
-        if let Some(future) = future.borrow_mut().as_mut() {
-            // Create a dummy context, nothing important
-            let waker = waker();
-            let mut cx = Context::from_waker(&waker);
-            // Whether app exits or continues, we don't care.
-            let _ = future.as_mut().poll(&mut cx);
-        }
-    });
-}
-
-/// Create a waker for the JavaScript executor - doesn't need any associated
-/// state.
-#[inline]
-fn waker() -> Waker {
-    #[inline]
-    unsafe fn clone(data: *const ()) -> RawWaker {
-        RawWaker::new(data, &RawWakerVTable::new(clone, wake, wake, drop))
+    /// Get a reference to this `JsFn` as a `JsVar`.
+    pub fn as_var(&self) -> &JsVar {
+        &self.0
     }
 
-    #[inline]
-    unsafe fn wake(_data: *const ()) {
-        executor();
+    #[allow(unused_unsafe)]
+    unsafe fn call_internal(
+        &self,
+        this: i32,
+        args: &[&JsVar],
+    ) -> Result<Option<JsVar>, JsError> {
+        let args: Vec<i32> = args.iter().map(|arg| arg.0).collect();
+        let ret = unsafe {
+            ffi::_cala_js_call(
+                (self.0).0,
+                this,
+                args.as_ptr() as u32,
+                args.len() as u32,
+            )
+        };
+        match ret {
+            -1 => Ok(None),
+            // Threw an exception
+            -2 => {
+                let thrown = JsVar(unsafe { ffi::_cala_js_thrown() });
+                Err(JsError::from_var(&thrown))
+            }
+            ret => Ok(Some(JsVar(ret))),
+        }
     }
+}
 
-    #[inline]
-    unsafe fn drop(_data: *const ()) {}
-
-    unsafe {
-        Waker::from_raw(RawWaker::new(
-            std::ptr::null(),
-            &RawWakerVTable::new(clone, wake, wake, drop),
-        ))
-    }
+/// Spawn a task on the executor that runs `start!()`.  WASM is non-blocking,
+/// so this returns once the task is waiting, and the task continues running on
+/// the main (and only) thread as it's woken.
+pub fn block_on<F: Future<Output = ()> + 'static>(main: F) {
+    crate::start::start(Box::pin(async {
+        panic_hook();
+        main.await;
+    }));
 }
 
 fn panic_hook_internal(panic_info: &std::panic::PanicInfo<'_>) {
     let msg = panic_info.to_string();
 
     let message = JsString::new(&format!("Cala App panicked!: {:?}", msg));
-    let eprint = unsafe { JsFn::new("throw new Error(param_a);") };
-    unsafe {
-        assert!(eprint.call(Some(message.as_var()), None).is_none());
+    if let Some(eprint) = JsFn::global("console.error") {
+        unsafe {
+            let _ = eprint.call(&[message.as_var()]);
+        }
     }
 }
 
-/// Set the panic hook for nicely printed `panic!`s.
+/// Set the panic hook for nicely printed `panic!`s.  Only installed once, no
+/// matter how many times this is called.
 pub fn panic_hook() {
-    let hook = std::panic::take_hook();
-    std::panic::set_hook(Box::new(move |p| {
-        hook(p);
-        panic_hook_internal(p);
-    }));
+    static INSTALL: std::sync::Once = std::sync::Once::new();
+    INSTALL.call_once(|| {
+        let hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |p| {
+            hook(p);
+            panic_hook_internal(p);
+        }));
+    });
 }
@@ -0,0 +1,370 @@
+// cala_core
+//
+// Copyright (c) 2020 Jeron Aldaron Lau
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// https://apache.org/licenses/LICENSE-2.0>, or the Zlib License, <LICENSE-ZLIB
+// or http://opensource.org/licenses/Zlib>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Type-checked conversions between Rust values and JavaScript variables.
+
+#![allow(unused_unsafe)]
+
+use super::{ffi, JsString, JsVar};
+use std::error::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+/// The type of a JavaScript variable, checked at runtime.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum JsType {
+    /// `undefined`
+    Undefined,
+    /// `null`
+    Null,
+    /// `true` or `false`
+    Boolean,
+    /// A number (always a 64-bit float)
+    Number,
+    /// A `BigInt`
+    BigInt,
+    /// A string
+    String,
+    /// A `Symbol`
+    Symbol,
+    /// A function
+    Function,
+    /// An `Array`
+    Array,
+    /// A typed array (such as `Uint8Array`), or an `ArrayBuffer`
+    TypedArray,
+    /// Any other object
+    Object,
+}
+
+impl JsType {
+    /// Get the name of the type.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JsType::Undefined => "undefined",
+            JsType::Null => "null",
+            JsType::Boolean => "boolean",
+            JsType::Number => "number",
+            JsType::BigInt => "bigint",
+            JsType::String => "string",
+            JsType::Symbol => "symbol",
+            JsType::Function => "function",
+            JsType::Array => "array",
+            JsType::TypedArray => "typed array",
+            JsType::Object => "object",
+        }
+    }
+}
+
+impl Display for JsType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Error converting a JavaScript variable that has the wrong type (or value)
+/// into a Rust value.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct JsTypeError {
+    expected: &'static str,
+    found: JsType,
+}
+
+impl JsTypeError {
+    /// Create a new error, for a conversion that expected `expected`.
+    pub fn new(expected: &'static str, found: JsType) -> Self {
+        JsTypeError { expected, found }
+    }
+
+    /// Get a description of what was expected.
+    pub fn expected(&self) -> &'static str {
+        self.expected
+    }
+
+    /// Get the type that was found.
+    pub fn found(&self) -> JsType {
+        self.found
+    }
+}
+
+impl Display for JsTypeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "Expected {}, found {}", self.expected, self.found)
+    }
+}
+
+impl Error for JsTypeError {}
+
+/// Conversion of a Rust value into a new JavaScript variable.
+pub trait ToJs {
+    /// Copy `self` into a new JavaScript variable.
+    fn to_js(&self) -> JsVar;
+}
+
+/// Type-checked conversion of a JavaScript variable into a Rust value.
+pub trait FromJs: Sized {
+    /// Copy a JavaScript variable into a Rust value, failing if it has the
+    /// wrong type.
+    fn from_js(var: &JsVar) -> Result<Self, JsTypeError>;
+}
+
+impl JsVar {
+    /// Get the type of the JavaScript variable.
+    pub fn type_of(&self) -> JsType {
+        match unsafe { ffi::_cala_js_typeof(self.0) } {
+            0 => JsType::Undefined,
+            1 => JsType::Null,
+            2 => JsType::Boolean,
+            3 => JsType::Number,
+            4 => JsType::BigInt,
+            5 => JsType::String,
+            6 => JsType::Symbol,
+            7 => JsType::Function,
+            8 => JsType::Array,
+            9 => JsType::TypedArray,
+            _ => JsType::Object,
+        }
+    }
+
+    /// Create a new `null` JavaScript variable.
+    pub fn null() -> JsVar {
+        JsVar(unsafe { ffi::_cala_js_null() })
+    }
+
+    /// Convert the JavaScript variable into a Rust value.
+    ///
+    /// ```rust,no_run
+    /// use cala_core::os::web::{JsTypeError, JsVar, ToJs};
+    ///
+    /// fn check() -> Result<(), JsTypeError> {
+    ///     let var = (1u8, String::from("Two")).to_js();
+    ///     let (one, two): (u8, String) = var.get()?;
+    ///     assert!(var.get::<bool>().is_err());
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn get<T: FromJs>(&self) -> Result<T, JsTypeError> {
+        T::from_js(self)
+    }
+
+    // Check the type, returning an error if it's not one of `types`.
+    fn expect(
+        &self,
+        expected: &'static str,
+        types: &[JsType],
+    ) -> Result<(), JsTypeError> {
+        let found = self.type_of();
+        if types.contains(&found) {
+            Ok(())
+        } else {
+            Err(JsTypeError::new(expected, found))
+        }
+    }
+
+    // Get the `length` property of an array or string.
+    fn length(&self) -> u32 {
+        unsafe { ffi::_cala_js_length(self.0) }
+    }
+
+    // Get an element of an array.
+    fn index(&self, index: u32) -> JsVar {
+        JsVar(unsafe { ffi::_cala_js_index(self.0, index) })
+    }
+}
+
+// Create a JavaScript array from JavaScript variables.
+fn array(vars: &[&JsVar]) -> JsVar {
+    let slots: Vec<i32> = vars.iter().map(|var| var.0).collect();
+    JsVar(unsafe {
+        ffi::_cala_js_array(slots.as_ptr() as u32, slots.len() as u32)
+    })
+}
+
+impl ToJs for JsVar {
+    fn to_js(&self) -> JsVar {
+        JsVar(super::clone_slot(self.0 as u32) as i32)
+    }
+}
+
+impl FromJs for JsVar {
+    fn from_js(var: &JsVar) -> Result<Self, JsTypeError> {
+        Ok(var.to_js())
+    }
+}
+
+impl ToJs for bool {
+    fn to_js(&self) -> JsVar {
+        JsVar(unsafe { ffi::_cala_js_store_bool(u32::from(*self)) })
+    }
+}
+
+impl FromJs for bool {
+    fn from_js(var: &JsVar) -> Result<Self, JsTypeError> {
+        var.expect("boolean", &[JsType::Boolean])?;
+        Ok(unsafe { ffi::_cala_js_load_bool(var.0) } != 0)
+    }
+}
+
+// Numbers are converted through `f64`, so integers outside of ±2⁵³ lose
+// precision.
+macro_rules! number {
+    ($($int:ty),*) => {$(
+        impl ToJs for $int {
+            fn to_js(&self) -> JsVar {
+                JsVar::from_f64(*self as f64)
+            }
+        }
+
+        impl FromJs for $int {
+            fn from_js(var: &JsVar) -> Result<Self, JsTypeError> {
+                let expected = stringify!($int);
+                var.expect(expected, &[JsType::Number])?;
+                let value = unsafe { var.into_f64() };
+                // `MAX + 1` is a power of two, so it's exact as an `f64`
+                // (unlike `MAX` itself, which rounds up for 64-bit types).
+                if value.fract() == 0.0
+                    && value >= <$int>::MIN as f64
+                    && value < <$int>::MAX as f64 + 1.0
+                {
+                    Ok(value as $int)
+                } else {
+                    Err(JsTypeError::new(expected, JsType::Number))
+                }
+            }
+        }
+    )*};
+}
+
+number!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+impl ToJs for f32 {
+    fn to_js(&self) -> JsVar {
+        JsVar::from_f64((*self).into())
+    }
+}
+
+impl FromJs for f32 {
+    fn from_js(var: &JsVar) -> Result<Self, JsTypeError> {
+        var.expect("f32", &[JsType::Number])?;
+        Ok(unsafe { var.into_f64() } as f32)
+    }
+}
+
+impl ToJs for f64 {
+    fn to_js(&self) -> JsVar {
+        JsVar::from_f64(*self)
+    }
+}
+
+impl FromJs for f64 {
+    fn from_js(var: &JsVar) -> Result<Self, JsTypeError> {
+        var.expect("f64", &[JsType::Number])?;
+        Ok(unsafe { var.into_f64() })
+    }
+}
+
+impl ToJs for str {
+    fn to_js(&self) -> JsVar {
+        JsString::new(self).0
+    }
+}
+
+impl ToJs for String {
+    fn to_js(&self) -> JsVar {
+        self.as_str().to_js()
+    }
+}
+
+impl FromJs for String {
+    fn from_js(var: &JsVar) -> Result<Self, JsTypeError> {
+        var.expect("string", &[JsType::String])?;
+        let mut utf16 = Vec::new();
+        unsafe { var.read_utf16(&mut utf16) };
+        Ok(String::from_utf16_lossy(&utf16))
+    }
+}
+
+impl ToJs for [u8] {
+    fn to_js(&self) -> JsVar {
+        JsVar(unsafe {
+            ffi::_cala_js_bytes(self.as_ptr() as u32, self.len() as u32)
+        })
+    }
+}
+
+/// Converts to a `Uint8Array`.
+impl ToJs for Vec<u8> {
+    fn to_js(&self) -> JsVar {
+        self.as_slice().to_js()
+    }
+}
+
+/// Converts from an `Array`, typed array or `ArrayBuffer`.
+impl FromJs for Vec<u8> {
+    fn from_js(var: &JsVar) -> Result<Self, JsTypeError> {
+        var.expect("bytes", &[JsType::Array, JsType::TypedArray])?;
+        let mut bytes = Vec::new();
+        unsafe { var.read_bytes(&mut bytes) };
+        Ok(bytes)
+    }
+}
+
+/// `None` converts to `null`.
+impl<T: ToJs> ToJs for Option<T> {
+    fn to_js(&self) -> JsVar {
+        match self {
+            Some(value) => value.to_js(),
+            None => JsVar::null(),
+        }
+    }
+}
+
+/// `null` and `undefined` convert to `None`.
+impl<T: FromJs> FromJs for Option<T> {
+    fn from_js(var: &JsVar) -> Result<Self, JsTypeError> {
+        match var.type_of() {
+            JsType::Undefined | JsType::Null => Ok(None),
+            _ => T::from_js(var).map(Some),
+        }
+    }
+}
+
+impl<T: ToJs + ?Sized> ToJs for &T {
+    fn to_js(&self) -> JsVar {
+        (**self).to_js()
+    }
+}
+
+// Tuples convert to and from arrays of the same length.
+macro_rules! tuple {
+    ($len:literal: $($name:ident $index:tt),+) => {
+        impl<$($name: ToJs),+> ToJs for ($($name,)+) {
+            fn to_js(&self) -> JsVar {
+                array(&[$(&self.$index.to_js()),+])
+            }
+        }
+
+        impl<$($name: FromJs),+> FromJs for ($($name,)+) {
+            fn from_js(var: &JsVar) -> Result<Self, JsTypeError> {
+                let expected = concat!("array of length ", $len);
+                var.expect(expected, &[JsType::Array])?;
+                if var.length() != $len {
+                    return Err(JsTypeError::new(expected, JsType::Array));
+                }
+                Ok(($($name::from_js(&var.index($index))?,)+))
+            }
+        }
+    };
+}
+
+tuple!(1: A 0);
+tuple!(2: A 0, B 1);
+tuple!(3: A 0, B 1, C 2);
+tuple!(4: A 0, B 1, C 2, D 3);
+tuple!(5: A 0, B 1, C 2, D 3, E 4);
+tuple!(6: A 0, B 1, C 2, D 3, E 4, F 5);
@@ -0,0 +1,79 @@
+// cala_core
+//
+// Copyright (c) 2020 Jeron Aldaron Lau
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// https://apache.org/licenses/LICENSE-2.0>, or the Zlib License, <LICENSE-ZLIB
+// or http://opensource.org/licenses/Zlib>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! JavaScript exceptions.
+
+#![allow(unused_unsafe)]
+
+use super::{ffi, JsTypeError, JsVar};
+use std::error::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+/// A JavaScript exception, or the reason a promise was rejected.
+#[derive(Clone, Debug)]
+pub struct JsError {
+    name: String,
+    message: String,
+    stack: Option<String>,
+}
+
+impl JsError {
+    // Read the name, message and stack of a thrown value.  Values that aren't
+    // objects (`throw "oops"`) become the message of a plain `Error`.
+    pub(super) fn from_var(var: &JsVar) -> Self {
+        let field = |index| {
+            let slot = unsafe { ffi::_cala_js_error(var.0, index) };
+            if slot == -1 {
+                None
+            } else {
+                JsVar(slot).get::<String>().ok()
+            }
+        };
+
+        JsError {
+            name: field(0).unwrap_or_else(|| "Error".to_string()),
+            message: field(1).unwrap_or_default(),
+            stack: field(2),
+        }
+    }
+
+    /// Get the name of the error (for example, `"TypeError"`).
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Get the error message.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Get the stack trace, if the browser provides one.
+    pub fn stack(&self) -> Option<&str> {
+        self.stack.as_deref()
+    }
+}
+
+impl Display for JsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{}: {}", self.name, self.message)
+    }
+}
+
+impl Error for JsError {}
+
+/// A value that couldn't be converted becomes a `TypeError`.
+impl From<JsTypeError> for JsError {
+    fn from(error: JsTypeError) -> Self {
+        JsError {
+            name: "TypeError".to_string(),
+            message: error.to_string(),
+            stack: None,
+        }
+    }
+}
@@ -0,0 +1,851 @@
+// cala_core
+//
+// Copyright (c) 2020 Jeron Aldaron Lau
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// https://apache.org/licenses/LICENSE-2.0>, or the Zlib License, <LICENSE-ZLIB
+// or http://opensource.org/licenses/Zlib>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Gamepad input.
+//!
+//! Buttons and axes use the layout of an Xbox or PlayStation controller
+//! (the "standard" gamepad of the web), whatever the gamepad is.  Pads come
+//! from the Gamepad API on the web (which only shows a gamepad after one of
+//! its buttons is pressed), and from gamepads and joysticks in `/dev/input` on
+//! Linux.  Other platforms only get events sent with [`inject()`], or from a
+//! [`VirtualPad`].
+//!
+//! ```rust,no_run
+//! use cala_core::pad::{Button, Pad, PadEvent};
+//!
+//! async fn wait_for_start() {
+//!     let mut pad = Pad::new();
+//!     loop {
+//!         if let PadEvent::Button {
+//!             button: Button::Start,
+//!             pressed: true,
+//!             ..
+//!         } = pad.recv().await
+//!         {
+//!             break;
+//!         }
+//!     }
+//! }
+//! ```
+
+use crate::input::{lock, Channel, Receiver};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Identifies a gamepad while it's connected.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PadId(u32);
+
+impl PadId {
+    // IDs are given out in order, so they aren't reused when a gamepad is
+    // unplugged.
+    fn next() -> Self {
+        static NEXT: AtomicU32 = AtomicU32::new(0);
+        PadId(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// A gamepad button.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Button {
+    /// Bottom face button (A on Xbox, cross on PlayStation)
+    South,
+    /// Right face button (B on Xbox, circle on PlayStation)
+    East,
+    /// Left face button (X on Xbox, square on PlayStation)
+    West,
+    /// Top face button (Y on Xbox, triangle on PlayStation)
+    North,
+    /// Left shoulder button
+    LeftBumper,
+    /// Right shoulder button
+    RightBumper,
+    /// Left of the center buttons (back, select or share)
+    Select,
+    /// Right of the center buttons (start or options)
+    Start,
+    /// Pressing the left stick
+    LeftStick,
+    /// Pressing the right stick
+    RightStick,
+    /// D-pad up
+    Up,
+    /// D-pad down
+    Down,
+    /// D-pad left
+    Left,
+    /// D-pad right
+    Right,
+    /// Center button (with the logo)
+    Home,
+}
+
+/// A gamepad axis.  Sticks go from -1.0 to 1.0 (positive is right and down),
+/// and triggers from 0.0 to 1.0.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Axis {
+    /// Left stick, horizontal
+    LeftX,
+    /// Left stick, vertical
+    LeftY,
+    /// Right stick, horizontal
+    RightX,
+    /// Right stick, vertical
+    RightY,
+    /// Left trigger
+    LeftTrigger,
+    /// Right trigger
+    RightTrigger,
+}
+
+/// Something a gamepad did.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PadEvent {
+    /// A gamepad was plugged in (or found).
+    Connect {
+        /// Which gamepad
+        pad: PadId,
+        /// Name of the gamepad, from its driver
+        name: String,
+    },
+    /// A gamepad was unplugged.
+    Disconnect {
+        /// Which gamepad
+        pad: PadId,
+    },
+    /// A button was pressed or released.
+    Button {
+        /// Which gamepad
+        pad: PadId,
+        /// Which button
+        button: Button,
+        /// `true` if pressed, `false` if released
+        pressed: bool,
+    },
+    /// A stick or trigger moved.
+    Axis {
+        /// Which gamepad
+        pad: PadId,
+        /// Which stick or trigger
+        axis: Axis,
+        /// Where it moved to
+        value: f64,
+    },
+}
+
+/// Vibration of a gamepad's motors.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Rumble {
+    /// Strength of the big (low frequency) motor, from 0.0 to 1.0
+    pub strong: f64,
+    /// Strength of the small (high frequency) motor, from 0.0 to 1.0
+    pub weak: f64,
+    /// How long to rumble for (up to about a minute)
+    pub duration: Duration,
+}
+
+static EVENTS: Channel<PadEvent> = Channel::new();
+
+// Gamepads that are connected, for sending `Connect` to new `Pad`s.
+static CONNECTED: Mutex<Vec<(PadId, String)>> = Mutex::new(Vec::new());
+
+/// Stream of gamepad events, from every gamepad.
+///
+/// Sticks and triggers have a dead zone (0.1 by default), since they rarely
+/// rest at exactly zero.  Values in it become zero, and values past it are
+/// scaled to still reach 1.0.  Axis events that don't change the value
+/// (after the dead zone) are skipped.
+#[derive(Debug)]
+pub struct Pad {
+    connected: VecDeque<PadEvent>,
+    receiver: Receiver<PadEvent>,
+    dead_zone: f64,
+    axes: Vec<(PadId, Axis, f64)>,
+}
+
+impl Pad {
+    /// Start receiving gamepad events, from events that happen after this
+    /// call.  Gamepads that are already connected send `Connect` events.
+    pub fn new() -> Self {
+        // Subscribed while locked, so a gamepad connecting now is either
+        // listed, or sends its `Connect` to the receiver (not both).
+        let (receiver, connected) = {
+            let connected = lock(&CONNECTED);
+            let receiver = EVENTS.subscribe();
+            let connected = connected
+                .iter()
+                .map(|(pad, name)| PadEvent::Connect {
+                    pad: *pad,
+                    name: name.clone(),
+                })
+                .collect();
+            (receiver, connected)
+        };
+        #[cfg(target_os = "linux")]
+        crate::input::evdev::listen(
+            crate::input::evdev::Class::Pad,
+            &native::HANDLER,
+        );
+        #[cfg(target_arch = "wasm32")]
+        web::listen();
+        Pad {
+            connected,
+            receiver,
+            dead_zone: 0.1,
+            axes: Vec::new(),
+        }
+    }
+
+    /// Set the dead zone, from 0.0 (none) to less than 1.0.
+    pub fn set_dead_zone(&mut self, dead_zone: f64) {
+        self.dead_zone = dead_zone.clamp(0.0, 0.99);
+    }
+
+    /// Wait for the next gamepad event.
+    pub async fn recv(&mut self) -> PadEvent {
+        if let Some(event) = self.connected.pop_front() {
+            return event;
+        }
+        loop {
+            let event = self.receiver.recv().await;
+            if let Some(event) = self.filter(event) {
+                return event;
+            }
+        }
+    }
+
+    /// Get the next gamepad event, if one has already happened.
+    pub fn try_recv(&mut self) -> Option<PadEvent> {
+        if let Some(event) = self.connected.pop_front() {
+            return Some(event);
+        }
+        while let Some(event) = self.receiver.try_recv() {
+            if let Some(event) = self.filter(event) {
+                return Some(event);
+            }
+        }
+        None
+    }
+
+    // Apply the dead zone, returning `None` if the event should be skipped.
+    fn filter(&mut self, event: PadEvent) -> Option<PadEvent> {
+        match event {
+            PadEvent::Axis { pad, axis, value } => {
+                let magnitude = (value.abs() - self.dead_zone).max(0.0);
+                let value =
+                    (magnitude / (1.0 - self.dead_zone)).copysign(value);
+                let value = if value == 0.0 { 0.0 } else { value };
+                match self
+                    .axes
+                    .iter_mut()
+                    .find(|(id, moved, _)| *id == pad && *moved == axis)
+                {
+                    Some((_, _, last)) if *last == value => return None,
+                    Some((_, _, last)) => *last = value,
+                    None => self.axes.push((pad, axis, value)),
+                }
+                Some(PadEvent::Axis { pad, axis, value })
+            }
+            PadEvent::Disconnect { pad } => {
+                self.axes.retain(|(id, _, _)| *id != pad);
+                Some(event)
+            }
+            event => Some(event),
+        }
+    }
+}
+
+impl Default for Pad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Send a synthetic gamepad event to every [`Pad`], as if it came from a real
+/// gamepad (for testing).
+pub fn inject(event: PadEvent) {
+    // Queued while locked, so a new `Pad` either lists the gamepad as
+    // connected, or receives the event (not both).
+    let mut connected = lock(&CONNECTED);
+    let wakers = match &event {
+        PadEvent::Connect { pad, name } => {
+            connected.push((*pad, name.clone()));
+            EVENTS.queue(event)
+        }
+        PadEvent::Disconnect { pad } => {
+            connected.retain(|(id, _)| id != pad);
+            EVENTS.queue(event)
+        }
+        _ => {
+            drop(connected);
+            return EVENTS.send(event);
+        }
+    };
+    drop(connected);
+    for waker in wakers {
+        waker.wake();
+    }
+}
+
+// Rumbles requested for each `VirtualPad`.
+static VIRTUAL: Mutex<Vec<(PadId, Option<Rumble>)>> = Mutex::new(Vec::new());
+
+/// Make a gamepad vibrate.  Does nothing if it can't (on Linux, this needs
+/// permission to write to the gamepad's device).
+pub fn rumble(pad: PadId, rumble: Rumble) {
+    if let Some((_, last)) =
+        lock(&VIRTUAL).iter_mut().find(|(id, _)| *id == pad)
+    {
+        *last = Some(rumble);
+    } else {
+        #[cfg(target_os = "linux")]
+        native::rumble(pad, rumble);
+        #[cfg(target_arch = "wasm32")]
+        web::rumble(pad, rumble);
+    }
+}
+
+/// A gamepad that isn't real, for testing.  It's connected when created, and
+/// disconnected when dropped.
+#[derive(Debug)]
+pub struct VirtualPad(PadId);
+
+impl VirtualPad {
+    /// Connect a virtual gamepad.
+    pub fn new(name: &str) -> Self {
+        let pad = PadId::next();
+        lock(&VIRTUAL).push((pad, None));
+        inject(PadEvent::Connect {
+            pad,
+            name: name.to_string(),
+        });
+        VirtualPad(pad)
+    }
+
+    /// Get the ID of the gamepad.
+    pub fn id(&self) -> PadId {
+        self.0
+    }
+
+    /// Press a button.
+    pub fn press(&self, button: Button) {
+        inject(PadEvent::Button {
+            pad: self.0,
+            button,
+            pressed: true,
+        });
+    }
+
+    /// Release a button.
+    pub fn release(&self, button: Button) {
+        inject(PadEvent::Button {
+            pad: self.0,
+            button,
+            pressed: false,
+        });
+    }
+
+    /// Move a stick or trigger.
+    pub fn set_axis(&self, axis: Axis, value: f64) {
+        inject(PadEvent::Axis {
+            pad: self.0,
+            axis,
+            value,
+        });
+    }
+
+    /// Get the last rumble requested with [`rumble()`], if any.
+    pub fn rumbled(&self) -> Option<Rumble> {
+        lock(&VIRTUAL)
+            .iter()
+            .find(|(id, _)| *id == self.0)
+            .and_then(|(_, rumble)| *rumble)
+    }
+}
+
+impl Drop for VirtualPad {
+    fn drop(&mut self) {
+        lock(&VIRTUAL).retain(|(id, _)| *id != self.0);
+        inject(PadEvent::Disconnect { pad: self.0 });
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod native {
+    use super::{inject, Axis, Button, PadEvent, PadId, Rumble};
+    use crate::input::evdev::{
+        self, AbsInfo, Device, Handler, InputEvent, ABS_HAT0X, ABS_HAT0Y,
+        ABS_RX, ABS_RY, ABS_RZ, ABS_X, ABS_Y, ABS_Z, EV_ABS, EV_KEY,
+    };
+    use crate::input::lock;
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+
+    // Buttons, from the Linux gamepad mapping (`BTN_SOUTH` and so on).
+    const BUTTONS: &[(u16, Button)] = &[
+        (0x130, Button::South),
+        (0x131, Button::East),
+        (0x133, Button::North),
+        (0x134, Button::West),
+        (0x136, Button::LeftBumper),
+        (0x137, Button::RightBumper),
+        (0x13a, Button::Select),
+        (0x13b, Button::Start),
+        (0x13c, Button::Home),
+        (0x13d, Button::LeftStick),
+        (0x13e, Button::RightStick),
+        (0x220, Button::Up),
+        (0x221, Button::Down),
+        (0x222, Button::Left),
+        (0x223, Button::Right),
+    ];
+
+    // Digital triggers (`BTN_TL2` and `BTN_TR2`).
+    const TRIGGERS: &[(u16, Axis)] =
+        &[(0x138, Axis::LeftTrigger), (0x139, Axis::RightTrigger)];
+
+    const AXES: &[(u16, Axis)] = &[
+        (ABS_X, Axis::LeftX),
+        (ABS_Y, Axis::LeftY),
+        (ABS_RX, Axis::RightX),
+        (ABS_RY, Axis::RightY),
+        (ABS_Z, Axis::LeftTrigger),
+        (ABS_RZ, Axis::RightTrigger),
+    ];
+
+    // The d-pad as a hat, for each direction of each axis.
+    const HAT: [[Button; 2]; 2] =
+        [[Button::Left, Button::Right], [Button::Up, Button::Down]];
+
+    struct Gamepad {
+        path: PathBuf,
+        pad: PadId,
+        axes: Vec<(u16, AbsInfo)>,
+        // Which way the hat is pressed on each axis (-1, 0 or 1).
+        hat: [i32; 2],
+        // Force feedback effect uploaded for rumble.
+        effect: i16,
+    }
+
+    impl Gamepad {
+        // Get the events for an event from the device.
+        fn event(&mut self, event: InputEvent) -> Vec<PadEvent> {
+            let pad = self.pad;
+            let code = event.code;
+            match event.kind {
+                // Values are 0 for release, 1 for press and 2 for repeat.
+                EV_KEY if event.value == 2 => Vec::new(),
+                EV_KEY => {
+                    let pressed = event.value != 0;
+                    if let Some((_, button)) =
+                        BUTTONS.iter().find(|(c, _)| *c == code)
+                    {
+                        vec![PadEvent::Button {
+                            pad,
+                            button: *button,
+                            pressed,
+                        }]
+                    } else if let Some((_, axis)) =
+                        TRIGGERS.iter().find(|(c, _)| *c == code)
+                    {
+                        vec![PadEvent::Axis {
+                            pad,
+                            axis: *axis,
+                            value: if pressed { 1.0 } else { 0.0 },
+                        }]
+                    } else {
+                        Vec::new()
+                    }
+                }
+                EV_ABS if code == ABS_HAT0X || code == ABS_HAT0Y => {
+                    let i = usize::from(code - ABS_HAT0X);
+                    let value = event.value.signum();
+                    let old = std::mem::replace(&mut self.hat[i], value);
+                    let button = |value: i32| HAT[i][usize::from(value > 0)];
+                    let mut events = Vec::new();
+                    if old != value && old != 0 {
+                        events.push(PadEvent::Button {
+                            pad,
+                            button: button(old),
+                            pressed: false,
+                        });
+                    }
+                    if old != value && value != 0 {
+                        events.push(PadEvent::Button {
+                            pad,
+                            button: button(value),
+                            pressed: true,
+                        });
+                    }
+                    events
+                }
+                EV_ABS => {
+                    let axis = match AXES.iter().find(|(c, _)| *c == code) {
+                        Some((_, axis)) => *axis,
+                        None => return Vec::new(),
+                    };
+                    let info = match self.axes.iter().find(|(c, _)| *c == code)
+                    {
+                        Some((_, info)) => *info,
+                        None => return Vec::new(),
+                    };
+                    let size =
+                        f64::from(info.maximum) - f64::from(info.minimum);
+                    if size <= 0.0 {
+                        return Vec::new();
+                    }
+                    let value = (f64::from(event.value)
+                        - f64::from(info.minimum))
+                        / size;
+                    let value = match axis {
+                        Axis::LeftTrigger | Axis::RightTrigger => value,
+                        _ => value * 2.0 - 1.0,
+                    };
+                    vec![PadEvent::Axis { pad, axis, value }]
+                }
+                _ => Vec::new(),
+            }
+        }
+    }
+
+    pub(super) struct Gamepads(Mutex<Vec<Gamepad>>);
+
+    pub(super) static HANDLER: Gamepads = Gamepads(Mutex::new(Vec::new()));
+
+    impl Handler for Gamepads {
+        fn connect(&self, device: &Device) {
+            let pad = PadId::next();
+            let axes = AXES
+                .iter()
+                .filter_map(|(code, _)| Some((*code, device.abs_info(*code)?)))
+                .collect();
+            lock(&self.0).push(Gamepad {
+                path: device.path().to_path_buf(),
+                pad,
+                axes,
+                hat: [0, 0],
+                effect: -1,
+            });
+            inject(PadEvent::Connect {
+                pad,
+                name: device.name(),
+            });
+        }
+
+        fn event(&self, device: &Device, event: InputEvent) {
+            let events = match lock(&self.0)
+                .iter_mut()
+                .find(|gamepad| gamepad.path == device.path())
+            {
+                Some(gamepad) => gamepad.event(event),
+                None => return,
+            };
+            for event in events {
+                inject(event);
+            }
+        }
+
+        fn disconnect(&self, device: &Device) {
+            let mut gamepads = lock(&self.0);
+            let i = match gamepads.iter().position(|g| g.path == device.path())
+            {
+                Some(i) => i,
+                None => return,
+            };
+            let pad = gamepads.swap_remove(i).pad;
+            drop(gamepads);
+            inject(PadEvent::Disconnect { pad });
+        }
+    }
+
+    pub(super) fn rumble(pad: PadId, rumble: Rumble) {
+        let mut gamepads = lock(&HANDLER.0);
+        let gamepad = match gamepads.iter_mut().find(|g| g.pad == pad) {
+            Some(gamepad) => gamepad,
+            None => return,
+        };
+        let device = match evdev::device(&gamepad.path) {
+            Some(device) => device,
+            None => return,
+        };
+        let strength = |value: f64| (value.clamp(0.0, 1.0) * 65535.0) as u16;
+        let milliseconds = rumble.duration.as_millis().min(65535) as u16;
+        device.rumble(
+            &mut gamepad.effect,
+            strength(rumble.strong),
+            strength(rumble.weak),
+            milliseconds,
+        );
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+#[allow(unsafe_code)]
+mod web {
+    use super::{inject, Axis, Button, PadEvent, PadId, Rumble};
+    use crate::input::lock;
+    use crate::os::web::JsVar;
+    use std::sync::{Mutex, Once};
+
+    #[cfg(feature = "wasm-bindgen")]
+    use wasm_bindgen::prelude::*;
+
+    #[cfg(not(feature = "wasm-bindgen"))]
+    mod ffi {
+        extern "C" {
+            pub(super) fn _cala_pad_listen();
+            pub(super) fn _cala_pad_rumble(
+                index: u32,
+                strong: f64,
+                weak: f64,
+                ms: f64,
+            );
+        }
+    }
+
+    #[cfg(feature = "wasm-bindgen")]
+    mod ffi {
+        use wasm_bindgen::prelude::*;
+
+        #[wasm_bindgen]
+        extern "C" {
+            pub(super) fn _cala_pad_listen();
+            pub(super) fn _cala_pad_rumble(
+                index: u32,
+                strong: f64,
+                weak: f64,
+                ms: f64,
+            );
+        }
+    }
+
+    // Buttons in the standard mapping, by index (6 and 7 are the triggers,
+    // which are sent as axes 4 and 5).
+    const BUTTONS: [Option<Button>; 17] = [
+        Some(Button::South),
+        Some(Button::East),
+        Some(Button::West),
+        Some(Button::North),
+        Some(Button::LeftBumper),
+        Some(Button::RightBumper),
+        None,
+        None,
+        Some(Button::Select),
+        Some(Button::Start),
+        Some(Button::LeftStick),
+        Some(Button::RightStick),
+        Some(Button::Up),
+        Some(Button::Down),
+        Some(Button::Left),
+        Some(Button::Right),
+        Some(Button::Home),
+    ];
+
+    const AXES: [Axis; 6] = [
+        Axis::LeftX,
+        Axis::LeftY,
+        Axis::RightX,
+        Axis::RightY,
+        Axis::LeftTrigger,
+        Axis::RightTrigger,
+    ];
+
+    // Connected gamepads, by their index in `navigator.getGamepads()`.
+    static PADS: Mutex<Vec<(u32, PadId)>> = Mutex::new(Vec::new());
+
+    #[allow(unused_unsafe)]
+    pub(super) fn listen() {
+        static LISTEN: Once = Once::new();
+        LISTEN.call_once(|| unsafe { ffi::_cala_pad_listen() });
+    }
+
+    #[allow(unused_unsafe)]
+    pub(super) fn rumble(pad: PadId, rumble: Rumble) {
+        let index = match lock(&PADS).iter().find(|(_, id)| *id == pad) {
+            Some((index, _)) => *index,
+            None => return,
+        };
+        let ms = rumble.duration.as_secs_f64() * 1000.0;
+        unsafe { ffi::_cala_pad_rumble(index, rumble.strong, rumble.weak, ms) }
+    }
+
+    // Handle a change in a gamepad, stored as `[kind, index, code, value,
+    // name]` in heap slot `event`.  Kinds are 0 for connect (with `name`), 1
+    // for disconnect, 2 for a button (`code` in the standard mapping) and 3
+    // for an axis (`code` in `AXES`).
+    fn event(event: u32) {
+        let event = unsafe { JsVar::from_raw(event) };
+        let (kind, index, code, value, name) =
+            match event.get::<(u8, u32, u8, f64, String)>() {
+                Ok(event) => event,
+                Err(_) => return,
+            };
+        if kind == 0 {
+            let pad = PadId::next();
+            lock(&PADS).push((index, pad));
+            inject(PadEvent::Connect { pad, name });
+            return;
+        }
+        let mut pads = lock(&PADS);
+        let i = match pads.iter().position(|(i, _)| *i == index) {
+            Some(i) => i,
+            None => return,
+        };
+        let pad = pads[i].1;
+        if kind == 1 {
+            pads.swap_remove(i);
+        }
+        drop(pads);
+        inject(match kind {
+            1 => PadEvent::Disconnect { pad },
+            2 => match BUTTONS.get(usize::from(code)).copied().flatten() {
+                Some(button) => PadEvent::Button {
+                    pad,
+                    button,
+                    pressed: value != 0.0,
+                },
+                None => return,
+            },
+            _ => match AXES.get(usize::from(code)) {
+                Some(axis) => PadEvent::Axis {
+                    pad,
+                    axis: *axis,
+                    value,
+                },
+                None => return,
+            },
+        });
+    }
+
+    #[cfg(feature = "wasm-bindgen")]
+    #[allow(unreachable_pub)]
+    /// Called by `runtime.js` when a gamepad connects, disconnects or changes.
+    #[wasm_bindgen]
+    pub fn _cala_pad_event(slot: u32) {
+        event(slot);
+    }
+
+    #[cfg(not(feature = "wasm-bindgen"))]
+    #[no_mangle]
+    extern "C" fn _cala_pad_event(slot: u32) {
+        event(slot);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Get the next event from a virtual gamepad, skipping the other tests'.
+    fn next(pad: &mut Pad, id: PadId) -> Option<PadEvent> {
+        while let Some(event) = pad.try_recv() {
+            let from = match &event {
+                PadEvent::Connect { pad, .. }
+                | PadEvent::Disconnect { pad }
+                | PadEvent::Button { pad, .. }
+                | PadEvent::Axis { pad, .. } => *pad,
+            };
+            if from == id {
+                return Some(event);
+            }
+        }
+        None
+    }
+
+    fn axis(pad: PadId, value: f64) -> Option<PadEvent> {
+        Some(PadEvent::Axis {
+            pad,
+            axis: Axis::LeftX,
+            value,
+        })
+    }
+
+    #[test]
+    fn dead_zone() {
+        let virtual_pad = VirtualPad::new("Dead Zone");
+        let id = virtual_pad.id();
+        let mut pad = Pad::new();
+        let connect = PadEvent::Connect {
+            pad: id,
+            name: "Dead Zone".into(),
+        };
+        assert_eq!(next(&mut pad, id), Some(connect));
+
+        virtual_pad.set_axis(Axis::LeftX, 0.05);
+        assert_eq!(next(&mut pad, id), axis(id, 0.0));
+        // Still in the dead zone, so unchanged.
+        virtual_pad.set_axis(Axis::LeftX, -0.08);
+        assert_eq!(next(&mut pad, id), None);
+        virtual_pad.set_axis(Axis::LeftX, -1.0);
+        assert_eq!(next(&mut pad, id), axis(id, -1.0));
+        // Scaled past the dead zone.
+        pad.set_dead_zone(0.5);
+        virtual_pad.set_axis(Axis::LeftX, 0.75);
+        assert_eq!(next(&mut pad, id), axis(id, 0.5));
+
+        pad.set_dead_zone(0.0);
+        virtual_pad.set_axis(Axis::LeftX, 0.25);
+        assert_eq!(next(&mut pad, id), axis(id, 0.25));
+        pad.set_dead_zone(2.0);
+        virtual_pad.set_axis(Axis::LeftX, 1.0);
+        assert_eq!(next(&mut pad, id), axis(id, 1.0));
+        virtual_pad.set_axis(Axis::LeftX, 0.98);
+        assert_eq!(next(&mut pad, id), axis(id, 0.0));
+
+        virtual_pad.press(Button::South);
+        virtual_pad.release(Button::South);
+        for pressed in [true, false] {
+            let button = PadEvent::Button {
+                pad: id,
+                button: Button::South,
+                pressed,
+            };
+            assert_eq!(next(&mut pad, id), Some(button));
+        }
+        drop(virtual_pad);
+        let disconnect = PadEvent::Disconnect { pad: id };
+        assert_eq!(next(&mut pad, id), Some(disconnect));
+        assert_eq!(next(&mut pad, id), None);
+    }
+
+    #[test]
+    fn connect_once() {
+        let mut before = Pad::new();
+        let virtual_pad = VirtualPad::new("Connect");
+        let id = virtual_pad.id();
+        let mut after = Pad::new();
+        for pad in [&mut before, &mut after] {
+            let connect = PadEvent::Connect {
+                pad: id,
+                name: "Connect".into(),
+            };
+            assert_eq!(next(pad, id), Some(connect));
+            assert_eq!(next(pad, id), None);
+        }
+        drop(virtual_pad);
+        // Pads created after disconnecting don't see it.
+        let mut gone = Pad::new();
+        assert_eq!(next(&mut gone, id), None);
+    }
+
+    #[test]
+    fn virtual_rumble() {
+        let virtual_pad = VirtualPad::new("Rumble");
+        assert_eq!(virtual_pad.rumbled(), None);
+        let vibration = Rumble {
+            strong: 1.0,
+            weak: 0.5,
+            duration: Duration::from_millis(250),
+        };
+        rumble(virtual_pad.id(), vibration);
+        assert_eq!(virtual_pad.rumbled(), Some(vibration));
+    }
+}
@@ -0,0 +1,387 @@
+// cala_core
+//
+// Copyright (c) 2020 Jeron Aldaron Lau
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// https://apache.org/licenses/LICENSE-2.0>, or the Zlib License, <LICENSE-ZLIB
+// or http://opensource.org/licenses/Zlib>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Linux epoll reactors that executors park on, which also wake tasks waiting
+//! on file descriptors.  Each thread has its own, so that executors on
+//! different threads don't take each other's timers or notifications.
+
+#![allow(unsafe_code)]
+
+use crate::os::*;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::task::Waker;
+use std::time::Instant;
+
+mod ffi {
+    use crate::os::*;
+
+    pub(super) const EPOLL_CLOEXEC: i32 = 0o2000000;
+    pub(super) const EPOLL_CTL_ADD: i32 = 1;
+    pub(super) const EPOLL_CTL_DEL: i32 = 2;
+    pub(super) const EPOLLIN: u32 = 0x001;
+    pub(super) const EPOLLOUT: u32 = 0x004;
+    pub(super) const EPOLLERR: u32 = 0x008;
+    pub(super) const EPOLLHUP: u32 = 0x010;
+    pub(super) const EPOLLRDHUP: u32 = 0x2000;
+    pub(super) const EPOLLET: u32 = 1 << 31;
+    pub(super) const F_GETFL: i32 = 3;
+    pub(super) const F_SETFL: i32 = 4;
+    pub(super) const O_NONBLOCK: i32 = 0o4000;
+    pub(super) const EFD_CLOEXEC: i32 = 0o2000000;
+    pub(super) const EFD_NONBLOCK: i32 = 0o4000;
+    pub(super) const CLOCK_MONOTONIC: i32 = 1;
+    pub(super) const TFD_CLOEXEC: i32 = 0o2000000;
+    pub(super) const TFD_NONBLOCK: i32 = 0o4000;
+
+    #[repr(C)]
+    #[cfg_attr(target_arch = "x86_64", repr(packed))]
+    #[derive(Copy, Clone)]
+    pub(super) struct EpollEvent {
+        pub(super) events: u32,
+        pub(super) data: u64,
+    }
+
+    #[repr(C)]
+    pub(super) struct TimeSpec {
+        pub(super) tv_sec: c_slong,
+        pub(super) tv_nsec: c_slong,
+    }
+
+    #[repr(C)]
+    pub(super) struct ITimerSpec {
+        pub(super) it_interval: TimeSpec,
+        pub(super) it_value: TimeSpec,
+    }
+
+    extern "C" {
+        pub(super) fn epoll_create1(flags: c_sint) -> RawFd;
+        pub(super) fn epoll_ctl(
+            epfd: RawFd,
+            op: c_sint,
+            fd: RawFd,
+            event: *mut EpollEvent,
+        ) -> c_sint;
+        pub(super) fn epoll_wait(
+            epfd: RawFd,
+            events: *mut EpollEvent,
+            maxevents: c_sint,
+            timeout: c_sint,
+        ) -> c_sint;
+        pub(super) fn eventfd(initval: c_uint, flags: c_sint) -> RawFd;
+        pub(super) fn timerfd_create(clockid: c_sint, flags: c_sint) -> RawFd;
+        pub(super) fn timerfd_settime(
+            fd: RawFd,
+            flags: c_sint,
+            new_value: *const ITimerSpec,
+            old_value: *mut ITimerSpec,
+        ) -> c_sint;
+        pub(super) fn read(
+            fd: RawFd,
+            buf: *mut c_void,
+            count: c_usize,
+        ) -> c_ssize;
+        pub(super) fn write(
+            fd: RawFd,
+            buf: *const c_void,
+            count: c_usize,
+        ) -> c_ssize;
+        pub(super) fn fcntl(fd: RawFd, cmd: c_sint, ...) -> c_sint;
+        pub(super) fn close(fd: RawFd) -> c_sint;
+    }
+}
+
+// Epoll token for the eventfd used to unpark the executor.
+const NOTIFY: u64 = u64::MAX;
+// Epoll token for the timerfd armed to the next timer deadline.
+const TIMER: u64 = u64::MAX - 1;
+
+// `c_int` is 32 bits on every target with epoll.
+fn int(value: i32) -> c_sint {
+    c_sint::try_from(value).unwrap_or_default()
+}
+
+// Convert a C return value into an `i32`.
+fn ret(value: c_sint) -> i32 {
+    i32::try_from(value).unwrap_or(-1)
+}
+
+/// Which readiness a task is waiting for.
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum Interest {
+    Read,
+    Write,
+}
+
+/// Tasks waiting on a registered file descriptor.
+#[derive(Default)]
+struct Waiters {
+    read: Option<Waker>,
+    write: Option<Waker>,
+}
+
+/// An epoll instance, with an eventfd for waking up from other threads, and a
+/// timerfd for waking up when the thread's next timer expires.
+pub(crate) struct Reactor {
+    epoll: RawFd,
+    notify: RawFd,
+    timer: RawFd,
+    // Registered file descriptors (which are also their epoll tokens).
+    waiters: Mutex<HashMap<RawFd, Waiters>>,
+}
+
+thread_local! {
+    static REACTOR: Arc<Reactor> = {
+        let reactor = Arc::new(Reactor::new());
+        let mut reactors = lock(&REACTORS);
+        reactors.retain(|reactor| reactor.strong_count() != 0);
+        reactors.push(Arc::downgrade(&reactor));
+        reactor
+    };
+}
+
+// Every thread's reactor, for deregistering file descriptors closed on a
+// different thread than they were registered on.
+static REACTORS: Mutex<Vec<Weak<Reactor>>> = Mutex::new(Vec::new());
+
+// A panicking task can't leave a list in an invalid state.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poison| poison.into_inner())
+}
+
+/// Get the reactor of this thread, creating it if it doesn't exist yet.
+pub(crate) fn reactor() -> Arc<Reactor> {
+    REACTOR.with(Arc::clone)
+}
+
+impl Reactor {
+    fn new() -> Self {
+        let (epoll, notify, timer) = unsafe {
+            (
+                ffi::epoll_create1(int(ffi::EPOLL_CLOEXEC)),
+                ffi::eventfd(
+                    0u16.into(),
+                    int(ffi::EFD_NONBLOCK | ffi::EFD_CLOEXEC),
+                ),
+                ffi::timerfd_create(
+                    int(ffi::CLOCK_MONOTONIC),
+                    int(ffi::TFD_NONBLOCK | ffi::TFD_CLOEXEC),
+                ),
+            )
+        };
+        assert!(epoll >= 0, "Failed to create epoll instance");
+        assert!(notify >= 0, "Failed to create eventfd");
+        assert!(timer >= 0, "Failed to create timerfd");
+        let reactor = Reactor {
+            epoll,
+            notify,
+            timer,
+            waiters: Mutex::new(HashMap::new()),
+        };
+        let _ = reactor.add(notify, ffi::EPOLLIN, NOTIFY);
+        let _ = reactor.add(timer, ffi::EPOLLIN, TIMER);
+        reactor
+    }
+
+    // Add a file descriptor to the epoll instance.
+    fn add(&self, fd: RawFd, events: u32, token: u64) -> Result<()> {
+        let mut event = ffi::EpollEvent {
+            events,
+            data: token,
+        };
+        let op = int(ffi::EPOLL_CTL_ADD);
+        if ret(unsafe { ffi::epoll_ctl(self.epoll, op, fd, &mut event) }) < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<RawFd, Waiters>> {
+        lock(&self.waiters)
+    }
+
+    /// Put a file descriptor in non-blocking mode, and start watching it for
+    /// readiness.  Does nothing if it's already registered.
+    pub(crate) fn register(&self, fd: RawFd) -> Result<()> {
+        let mut waiters = self.lock();
+        if waiters.contains_key(&fd) {
+            return Ok(());
+        }
+        let flags = ret(unsafe { ffi::fcntl(fd, int(ffi::F_GETFL)) });
+        if flags < 0 {
+            return Err(Error::last_os_error());
+        }
+        let flags = int(flags | ffi::O_NONBLOCK);
+        if ret(unsafe { ffi::fcntl(fd, int(ffi::F_SETFL), flags) }) < 0 {
+            return Err(Error::last_os_error());
+        }
+        // Edge triggered, so readiness only needs to be registered once.
+        let events =
+            ffi::EPOLLIN | ffi::EPOLLOUT | ffi::EPOLLRDHUP | ffi::EPOLLET;
+        match self.add(fd, events, fd as u64) {
+            Ok(()) => {}
+            // Regular files can't be polled, but never block either.
+            Err(e) if e.kind() == ErrorKind::PermissionDenied => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        waiters.insert(fd, Waiters::default());
+        Ok(())
+    }
+
+    /// Stop watching a file descriptor, before it's closed.
+    pub(crate) fn deregister(&self, fd: RawFd) {
+        if self.lock().remove(&fd).is_some() {
+            let op = int(ffi::EPOLL_CTL_DEL);
+            unsafe {
+                ffi::epoll_ctl(self.epoll, op, fd, std::ptr::null_mut());
+            }
+        }
+    }
+
+    /// Wake the task the next time a registered file descriptor becomes ready
+    /// (only call after finding it not ready, since readiness is edge
+    /// triggered).
+    pub(crate) fn set_waker(
+        &self,
+        fd: RawFd,
+        interest: Interest,
+        waker: &Waker,
+    ) {
+        if let Some(waiters) = self.lock().get_mut(&fd) {
+            let slot = match interest {
+                Interest::Read => &mut waiters.read,
+                Interest::Write => &mut waiters.write,
+            };
+            match slot {
+                Some(old) if old.will_wake(waker) => {}
+                _ => *slot = Some(waker.clone()),
+            }
+        } else {
+            // Not registered (can't be polled), so just try again.
+            waker.wake_by_ref();
+        }
+    }
+
+    /// Wake up the thread blocked in [`wait()`](Reactor::wait).  Safe to call
+    /// from any thread.
+    pub(crate) fn notify(&self) {
+        let one: *const u64 = &1;
+        unsafe {
+            ffi::write(self.notify, one.cast(), 8.into());
+        }
+    }
+
+    /// Block until notified, a registered file descriptor becomes ready, or
+    /// the deadline is reached.  Returns the wakers of tasks that are ready.
+    pub(crate) fn wait(&self, deadline: Option<Instant>) -> Vec<Waker> {
+        self.arm(deadline);
+        let mut events = [ffi::EpollEvent { events: 0, data: 0 }; 16];
+        let count = unsafe {
+            ffi::epoll_wait(
+                self.epoll,
+                events.as_mut_ptr(),
+                int(events.len() as i32),
+                int(-1),
+            )
+        };
+        // Interrupted by a signal, the executor loop handles retrying.
+        let count =
+            usize::try_from(i32::try_from(count).unwrap_or(-1)).unwrap_or(0);
+        let mut wakers = Vec::new();
+        for event in &events[..count] {
+            let (flags, token) = (event.events, event.data);
+            match token {
+                NOTIFY => drain(self.notify),
+                TIMER => drain(self.timer),
+                fd => {
+                    let mut waiters = self.lock();
+                    let waiters = match waiters.get_mut(&(fd as RawFd)) {
+                        Some(waiters) => waiters,
+                        None => continue,
+                    };
+                    let closed = ffi::EPOLLERR | ffi::EPOLLHUP;
+                    let read = ffi::EPOLLIN | ffi::EPOLLRDHUP | closed;
+                    if flags & read != 0 {
+                        wakers.extend(waiters.read.take());
+                    }
+                    if flags & (ffi::EPOLLOUT | closed) != 0 {
+                        wakers.extend(waiters.write.take());
+                    }
+                }
+            }
+        }
+        wakers
+    }
+
+    // Arm the timerfd to expire at the deadline, or disarm it if `None`.
+    fn arm(&self, deadline: Option<Instant>) {
+        let value = match deadline {
+            Some(deadline) => {
+                // Zero disarms the timer, so round up to 1 nanosecond.
+                let wait = deadline.saturating_duration_since(Instant::now());
+                let secs = i32::try_from(wait.as_secs()).unwrap_or(i32::MAX);
+                let nanos = wait.subsec_nanos() as i32;
+                if secs == 0 && nanos == 0 {
+                    (0, 1)
+                } else {
+                    (secs, nanos)
+                }
+            }
+            None => (0, 0),
+        };
+        let spec = ffi::ITimerSpec {
+            it_interval: ffi::TimeSpec {
+                tv_sec: 0.into(),
+                tv_nsec: 0.into(),
+            },
+            it_value: ffi::TimeSpec {
+                tv_sec: value.0.into(),
+                tv_nsec: value.1.into(),
+            },
+        };
+        unsafe {
+            ffi::timerfd_settime(
+                self.timer,
+                int(0),
+                &spec,
+                std::ptr::null_mut(),
+            );
+        }
+    }
+}
+
+impl Drop for Reactor {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::close(self.timer);
+            ffi::close(self.notify);
+            ffi::close(self.epoll);
+        }
+    }
+}
+
+/// Stop watching a file descriptor, on every thread's reactor.
+pub(crate) fn deregister(fd: RawFd) {
+    let reactors: Vec<Arc<Reactor>> =
+        lock(&REACTORS).iter().filter_map(Weak::upgrade).collect();
+    for reactor in reactors {
+        reactor.deregister(fd);
+    }
+}
+
+// Read the counter of an eventfd or timerfd to reset its readiness.
+fn drain(fd: RawFd) {
+    let mut counter = 0u64;
+    let counter: *mut u64 = &mut counter;
+    unsafe {
+        ffi::read(fd, counter.cast(), 8.into());
+    }
+}
@@ -1,35 +1,45 @@
+// cala_core
+//
+// Copyright (c) 2020 Jeron Aldaron Lau
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// https://apache.org/licenses/LICENSE-2.0>, or the Zlib License, <LICENSE-ZLIB
+// or http://opensource.org/licenses/Zlib>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
 #![allow(unsafe_code)]
 
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
 use std::future::Future;
-use std::mem::MaybeUninit;
 use std::pin::Pin;
-use std::task::{Context, RawWaker, RawWakerVTable, Waker};
-
-#[cfg(all(target_arch = "wasm32", feature = "wasm-bindgen"))]
-use wasm_bindgen::prelude::*;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(not(target_os = "linux"))]
+use std::sync::Condvar;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Wake, Waker};
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
 
 type PinFut = Pin<Box<dyn Future<Output = ()>>>;
 
-static mut FUTURE: MaybeUninit<PinFut> = MaybeUninit::uninit();
-static mut WAKER: MaybeUninit<Waker> = MaybeUninit::uninit();
-static mut CONTEXT: MaybeUninit<Context<'_>> = MaybeUninit::uninit();
-
 /// Macro to define task executed at the start of the program.
 #[macro_export]
 macro_rules! start {
     () => {
-        #[allow(unused)]
+        #[cfg(not(any(target_arch = "wasm32", target_os = "android")))]
         fn main() {
-            _cala_core::start();
+            $crate::_macro::start(Box::pin(start()))
         }
 
-        mod _cala_core {
-            #[cfg(not(any(target_arch = "wasm32", target_os = "android")))]
-            pub(super) fn start() {
-                $crate::_macro::start(Box::pin(super::start()))
-            }
+        #[cfg(any(target_arch = "wasm32", target_os = "android"))]
+        #[allow(unused)]
+        fn main() {}
 
-            #[cfg(any(target_arch = "wasm32", target_os = "android"))]
+        #[cfg(any(target_arch = "wasm32", target_os = "android"))]
+        mod _cala_core {
             #[no_mangle]
             pub(super) extern "C" fn start() {
                 $crate::_macro::start(Box::pin(super::start()))
@@ -44,55 +54,436 @@ extern "C" fn android_main() {
     todo!()
 }
 
-pub(crate) unsafe fn start(start: PinFut) {
-    FUTURE = MaybeUninit::new(start);
-    WAKER = MaybeUninit::new(waker());
-    CONTEXT = MaybeUninit::new(Context::from_waker(&*WAKER.as_ptr()));
-    wake();
+/// A spawned task.
+struct Task {
+    future: PinFut,
+    waker: Arc<TaskWaker>,
 }
 
-#[cfg(not(any(target_arch = "wasm32", target_os = "android")))]
-unsafe fn wake() {
-    let _ = (*FUTURE.as_mut_ptr())
-        .as_mut()
-        .poll(&mut *CONTEXT.as_mut_ptr());
-}
-
-#[cfg(all(target_arch = "wasm32", feature = "wasm-bindgen"))]
-#[allow(unreachable_pub)]
-#[doc = ""]
-#[wasm_bindgen]
-pub fn wake() {
-    let _ = unsafe {
-        (*FUTURE.as_mut_ptr())
-            .as_mut()
-            .poll(&mut *CONTEXT.as_mut_ptr())
+/// The task executor, one per thread.
+struct Executor {
+    // Tasks indexed by task ID (`None` when free or being polled).
+    tasks: RefCell<Vec<Option<Task>>>,
+    // Task IDs that can be reused.
+    free: RefCell<Vec<usize>>,
+    // Whether or not tasks are currently being polled.
+    running: Cell<bool>,
+    // Run queue, shared with the wakers of this executor's tasks.
+    queue: Arc<RunQueue>,
+}
+
+thread_local! {
+    static EXECUTOR: Executor = Executor {
+        tasks: RefCell::new(Vec::new()),
+        free: RefCell::new(Vec::new()),
+        running: Cell::new(false),
+        queue: Arc::new(RunQueue {
+            queue: Mutex::new(VecDeque::new()),
+            #[cfg(not(target_os = "linux"))]
+            condvar: Condvar::new(),
+            #[cfg(target_os = "linux")]
+            parked: AtomicBool::new(false),
+            #[cfg(target_os = "linux")]
+            reactor: crate::reactor::reactor(),
+        }),
     };
 }
 
-#[cfg(all(target_arch = "wasm32", not(feature = "wasm-bindgen")))]
-#[no_mangle]
-unsafe extern "C" fn wake() {
-    let _ = (*FUTURE.as_mut_ptr())
-        .as_mut()
-        .poll(&mut *CONTEXT.as_mut_ptr());
+/// Resets `running` when polling stops, even if a task panics.
+struct Running<'a>(&'a Cell<bool>);
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        self.0.set(false);
+    }
+}
+
+/// Wakers that have been woken, and need their tasks polled.  Wakers may be
+/// called from any thread, so each executor shares its queue with its wakers.
+struct RunQueue {
+    queue: Mutex<VecDeque<Arc<TaskWaker>>>,
+    // Notified when a task is pushed onto the queue, to unpark the executor.
+    #[cfg(not(target_os = "linux"))]
+    condvar: Condvar,
+    // Whether or not the executor is parked in the reactor.
+    #[cfg(target_os = "linux")]
+    parked: AtomicBool,
+    // The reactor of the executor's thread.
+    #[cfg(target_os = "linux")]
+    reactor: Arc<crate::reactor::Reactor>,
+}
+
+impl RunQueue {
+    fn push(&self, waker: Arc<TaskWaker>) {
+        self.lock().push_back(waker);
+        #[cfg(not(target_os = "linux"))]
+        self.condvar.notify_one();
+        #[cfg(target_os = "linux")]
+        if self.parked.load(Ordering::Acquire) {
+            self.reactor.notify();
+        }
+    }
+
+    fn pop(&self) -> Option<Arc<TaskWaker>> {
+        self.lock().pop_front()
+    }
+
+    // Block the thread until the queue is non-empty, or the deadline passes.
+    #[cfg(target_os = "linux")]
+    fn park(&self, deadline: Option<Instant>) {
+        {
+            let queue = self.lock();
+            if !queue.is_empty() {
+                return;
+            }
+            // Set while locked, so a push either gets seen or notifies.
+            self.parked.store(true, Ordering::Release);
+        }
+        let wakers = self.reactor.wait(deadline);
+        // Cleared first, so waking doesn't notify the reactor again.
+        self.parked.store(false, Ordering::Release);
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+
+    // Block the thread until the queue is non-empty, or the deadline passes.
+    #[cfg(not(any(
+        target_arch = "wasm32",
+        target_os = "android",
+        target_os = "linux"
+    )))]
+    fn park(&self, deadline: Option<Instant>) {
+        let mut queue = self.lock();
+        while queue.is_empty() {
+            queue = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return;
+                    }
+                    self.condvar
+                        .wait_timeout(queue, deadline - now)
+                        .unwrap_or_else(|poison| poison.into_inner())
+                        .0
+                }
+                None => self
+                    .condvar
+                    .wait(queue)
+                    .unwrap_or_else(|poison| poison.into_inner()),
+            };
+        }
+    }
+
+    // A panicking task can't leave the queue in an invalid state.
+    fn lock(&self) -> MutexGuard<'_, VecDeque<Arc<TaskWaker>>> {
+        self.queue
+            .lock()
+            .unwrap_or_else(|poison| poison.into_inner())
+    }
+}
+
+/// Waker for a task.  Waking only pushes onto the run queue, so it's safe to
+/// wake from any thread; the task is always polled on the executor's thread.
+struct TaskWaker {
+    // Task ID.
+    id: usize,
+    // Set when on the run queue, so that a task is only queued once.
+    scheduled: AtomicBool,
+    // Run queue of the executor that owns the task.
+    queue: Arc<RunQueue>,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        if !self.scheduled.swap(true, Ordering::AcqRel) {
+            self.queue.clone().push(self);
+            // Nothing blocks on WebAssembly, so run tasks as they're woken.
+            #[cfg(target_arch = "wasm32")]
+            run();
+        }
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.clone().wake()
+    }
+}
+
+/// Handle to a task spawned with [`spawn()`](crate::spawn), which can be
+/// `.await`ed to get the task's output.
+///
+/// Dropping a `JoinHandle` detaches the task, letting it run to completion in
+/// the background.
+pub struct JoinHandle<T>(Rc<RefCell<JoinState<T>>>);
+
+struct JoinState<T> {
+    output: Option<T>,
+    waker: Option<Waker>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Check whether or not the task has completed.
+    pub fn is_finished(&self) -> bool {
+        self.0.borrow().output.is_some()
+    }
+}
+
+impl<T> Debug for JoinHandle<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_struct("JoinHandle")
+            .field("finished", &self.is_finished())
+            .finish()
+    }
 }
 
-/// Create a waker for the executor - doesn't need any associated state.
-#[inline(always)]
-unsafe fn waker() -> Waker {
-    unsafe fn clone(data: *const ()) -> RawWaker {
-        RawWaker::new(data, &RawWakerVTable::new(clone, wake_by, wake_by, drop))
+impl<T> Future for JoinHandle<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let mut state = self.0.borrow_mut();
+        if let Some(output) = state.output.take() {
+            Poll::Ready(output)
+        } else {
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
     }
+}
+
+/// Spawn a task onto the executor of the current thread.
+///
+/// The task starts running the next time the executor polls tasks, and only
+/// gets polled again after it's woken.
+pub fn spawn<T, F>(future: F) -> JoinHandle<T>
+where
+    T: 'static,
+    F: Future<Output = T> + 'static,
+{
+    let state = Rc::new(RefCell::new(JoinState {
+        output: None,
+        waker: None,
+    }));
+    let handle = JoinHandle(state.clone());
+    spawn_boxed(Box::pin(async move {
+        let output = future.await;
+        let waker = {
+            let mut state = state.borrow_mut();
+            state.output = Some(output);
+            state.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }));
+    handle
+}
+
+// Add a task to the executor, and schedule it to be polled.
+fn spawn_boxed(future: PinFut) {
+    let waker = EXECUTOR.with(|exec| {
+        let mut tasks = exec.tasks.borrow_mut();
+        let id = exec.free.borrow_mut().pop().unwrap_or_else(|| {
+            tasks.push(None);
+            tasks.len() - 1
+        });
+        let waker = Arc::new(TaskWaker {
+            id,
+            scheduled: AtomicBool::new(false),
+            queue: exec.queue.clone(),
+        });
+        tasks[id] = Some(Task {
+            future,
+            waker: waker.clone(),
+        });
+        waker
+    });
+    waker.wake();
+}
+
+// Poll tasks on the run queue until it's empty.
+fn run() {
+    EXECUTOR.with(|exec| {
+        // Tasks woken while polling are picked up by the outer loop.
+        if exec.running.replace(true) {
+            return;
+        }
+        let _running = Running(&exec.running);
+        while let Some(woken) = exec.queue.pop() {
+            // Tasks are taken out of their slot so they can spawn tasks.
+            let task = {
+                let mut tasks = exec.tasks.borrow_mut();
+                let slot = &mut tasks[woken.id];
+                // Skip wakers left over from a completed task.
+                match slot {
+                    Some(task) if Arc::ptr_eq(&task.waker, &woken) => {
+                        slot.take()
+                    }
+                    _ => None,
+                }
+            };
+            if let Some(mut task) = task {
+                // Clear before polling, so the task can wake itself.
+                task.waker.scheduled.store(false, Ordering::Release);
+                let waker = Waker::from(task.waker.clone());
+                let mut cx = Context::from_waker(&waker);
+                if task.future.as_mut().poll(&mut cx).is_pending() {
+                    exec.tasks.borrow_mut()[woken.id] = Some(task);
+                } else {
+                    exec.free.borrow_mut().push(woken.id);
+                }
+            }
+        }
+    });
+}
 
-    unsafe fn wake_by(_data: *const ()) {
-        wake();
+/// Run the executor until the start task completes, parking the thread while
+/// there's nothing to do.
+#[cfg(not(any(target_arch = "wasm32", target_os = "android")))]
+pub(crate) fn start(start: PinFut) {
+    let handle = spawn(start);
+    loop {
+        run();
+        if handle.is_finished() {
+            break;
+        }
+        EXECUTOR
+            .with(|exec| exec.queue.clone())
+            .park(crate::time::next_deadline());
+        crate::time::fire();
     }
+}
 
-    unsafe fn drop(_data: *const ()) {}
+/// Spawn the start task, which continues running after this function returns
+/// as it gets woken.
+#[cfg(any(target_arch = "wasm32", target_os = "android"))]
+pub(crate) fn start(start: PinFut) {
+    spawn_boxed(start);
+    run();
+}
+
+#[cfg(all(test, not(any(target_arch = "wasm32", target_os = "android"))))]
+mod tests {
+    use super::{spawn, EXECUTOR};
+    use std::cell::Cell;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::rc::Rc;
+    use std::task::{Context, Poll};
+    use std::thread::ThreadId;
+    use std::time::Duration;
+
+    // Return pending once, waking immediately.
+    struct YieldNow(bool);
 
-    Waker::from_raw(RawWaker::new(
-        std::ptr::null(),
-        &RawWakerVTable::new(clone, wake_by, wake_by, drop),
-    ))
+    impl Future for YieldNow {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                return Poll::Ready(());
+            }
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    fn yield_now() -> YieldNow {
+        YieldNow(false)
+    }
+
+    fn slots() -> usize {
+        EXECUTOR.with(|exec| exec.tasks.borrow().len())
+    }
+
+    #[test]
+    fn spawn_many() {
+        super::start(Box::pin(async {
+            let handles: Vec<_> = (0..10)
+                .map(|i| {
+                    spawn(async move {
+                        yield_now().await;
+                        i
+                    })
+                })
+                .collect();
+            let mut sum = 0;
+            for handle in handles {
+                sum += handle.await;
+            }
+            assert_eq!(sum, 45);
+        }));
+    }
+
+    #[test]
+    fn join_handle() {
+        super::start(Box::pin(async {
+            let handle = spawn(async {
+                yield_now().await;
+                "done"
+            });
+            assert!(!handle.is_finished());
+            assert_eq!(handle.await, "done");
+        }));
+    }
+
+    #[test]
+    fn detached_task_completes() {
+        let done = Rc::new(Cell::new(false));
+        let flag = done.clone();
+        super::start(Box::pin(async move {
+            drop(spawn(async move {
+                yield_now().await;
+                yield_now().await;
+                flag.set(true);
+            }));
+            while !done.get() {
+                yield_now().await;
+            }
+        }));
+    }
+
+    #[test]
+    fn wake_from_other_thread() {
+        // Wakes from another thread the first time it's polled.
+        struct WakeFromThread(Vec<ThreadId>);
+
+        impl Future for WakeFromThread {
+            type Output = Vec<ThreadId>;
+
+            fn poll(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+            ) -> Poll<Vec<ThreadId>> {
+                self.0.push(std::thread::current().id());
+                if self.0.len() > 1 {
+                    return Poll::Ready(std::mem::take(&mut self.0));
+                }
+                let waker = cx.waker().clone();
+                std::thread::spawn(move || {
+                    std::thread::sleep(Duration::from_millis(20));
+                    waker.wake();
+                });
+                Poll::Pending
+            }
+        }
+
+        let executor = std::thread::current().id();
+        super::start(Box::pin(async move {
+            let polled_on = WakeFromThread(Vec::new()).await;
+            assert_eq!(polled_on, [executor, executor]);
+        }));
+    }
+
+    #[test]
+    fn reuses_slots() {
+        super::start(Box::pin(async {
+            spawn(yield_now()).await;
+            let count = slots();
+            for _ in 0..10 {
+                spawn(yield_now()).await;
+            }
+            assert_eq!(slots(), count);
+        }));
+    }
 }
@@ -0,0 +1,563 @@
+// cala_core
+//
+// Copyright (c) 2020 Jeron Aldaron Lau
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// https://apache.org/licenses/LICENSE-2.0>, or the Zlib License, <LICENSE-ZLIB
+// or http://opensource.org/licenses/Zlib>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Asynchronous timers.
+//!
+//! Timers must be `.await`ed on the `start!()` executor's thread.
+//!
+//! ```rust,no_run
+//! use cala_core::time;
+//! use std::time::Duration;
+//!
+//! async fn run() {
+//!     time::sleep(Duration::from_millis(750)).await;
+//!
+//!     let mut interval = time::interval(Duration::from_secs(1));
+//!     for _ in 0..3 {
+//!         interval.tick().await;
+//!     }
+//!
+//!     let slow = time::sleep(Duration::from_secs(10));
+//!     assert!(time::timeout(Duration::from_secs(1), slow).await.is_err());
+//! }
+//! ```
+
+#![allow(unsafe_code)]
+
+use std::error::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) use self::native::{fire, next_deadline};
+
+/// Future returned by [`sleep()`], which completes once the duration has
+/// elapsed.
+#[derive(Debug)]
+pub struct Sleep {
+    #[cfg(not(target_arch = "wasm32"))]
+    deadline: std::time::Instant,
+    // Milliseconds since the page loaded (from `performance.now()`).
+    #[cfg(target_arch = "wasm32")]
+    deadline: f64,
+    // ID of the timer once it's been registered.
+    timer: Option<u32>,
+}
+
+/// Wait until `duration` has elapsed.
+pub fn sleep(duration: Duration) -> Sleep {
+    Sleep::new(duration)
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        self.poll_internal(cx)
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(timer) = self.timer.take() {
+            Self::cancel(timer);
+        }
+    }
+}
+
+/// Stream of ticks returned by [`interval()`].
+#[derive(Debug)]
+pub struct Interval {
+    period: Duration,
+    sleep: Sleep,
+}
+
+/// Create an `Interval` that ticks once every `period`, starting one `period`
+/// from now.
+///
+/// # Panics
+/// If `period` is zero.
+pub fn interval(period: Duration) -> Interval {
+    assert!(period > Duration::from_secs(0), "Interval period is zero");
+    Interval {
+        period,
+        sleep: sleep(period),
+    }
+}
+
+impl Interval {
+    /// Wait for the next tick.  Ticks are scheduled relative to the previous
+    /// tick rather than when `tick()` is called, so they don't drift.
+    pub async fn tick(&mut self) {
+        (&mut self.sleep).await;
+        self.sleep = self.sleep.next(self.period);
+    }
+
+    /// Get the period of the interval.
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+}
+
+/// Error returned from [`Timeout`] when the deadline elapses before the future
+/// completes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Elapsed;
+
+impl Display for Elapsed {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.write_str("deadline has elapsed")
+    }
+}
+
+impl Error for Elapsed {}
+
+/// Future returned by [`timeout()`].
+#[derive(Debug)]
+pub struct Timeout<F: Future> {
+    future: F,
+    sleep: Sleep,
+}
+
+/// Require a future to complete before `duration` has elapsed, returning
+/// `Err(Elapsed)` otherwise.  The future is dropped if it times out.
+pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+    Timeout {
+        future,
+        sleep: sleep(duration),
+    }
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Safe because `future` is never moved out of `self`.
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        if let Poll::Ready(output) = future.poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        Pin::new(&mut this.sleep).poll(cx).map(|()| Err(Elapsed))
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+mod native {
+    use super::Sleep;
+    use std::cell::RefCell;
+    use std::task::{Context, Poll, Waker};
+    use std::time::{Duration, Instant};
+
+    // Number of slots in the wheel.
+    const SLOTS: usize = 256;
+    // Length of time between ticks of the wheel.
+    const TICK: Duration = Duration::from_millis(1);
+
+    struct Timer {
+        id: u32,
+        // Tick at which the timer expires (rounded up from the deadline).
+        tick: u64,
+        waker: Waker,
+    }
+
+    /// Hashed timer wheel - timers are put in the slot for the tick they
+    /// expire at, modulo the number of slots.
+    struct Wheel {
+        // Time of tick 0.
+        origin: Instant,
+        // Last tick expired timers have been fired for.
+        tick: u64,
+        slots: Vec<Vec<Timer>>,
+        next_id: u32,
+    }
+
+    impl Wheel {
+        fn new() -> Self {
+            Wheel {
+                origin: Instant::now(),
+                tick: 0,
+                slots: (0..SLOTS).map(|_| Vec::new()).collect(),
+                next_id: 0,
+            }
+        }
+
+        // Convert an instant to a tick, rounding up.
+        fn tick_ceil(&self, instant: Instant) -> u64 {
+            let since = instant.saturating_duration_since(self.origin);
+            since.as_nanos().div_ceil(TICK.as_nanos()) as u64
+        }
+
+        // Convert an instant to a tick, rounding down.
+        fn tick_floor(&self, instant: Instant) -> u64 {
+            let since = instant.saturating_duration_since(self.origin);
+            (since.as_nanos() / TICK.as_nanos()) as u64
+        }
+
+        fn insert(&mut self, deadline: Instant, waker: Waker) -> u32 {
+            let id = self.next_id;
+            self.next_id = self.next_id.wrapping_add(1);
+            let tick = self.tick_ceil(deadline).max(self.tick + 1);
+            self.slots[tick as usize % SLOTS].push(Timer { id, tick, waker });
+            id
+        }
+
+        fn update(&mut self, id: u32, waker: &Waker) {
+            let timers = self.slots.iter_mut().flatten();
+            if let Some(timer) = timers.into_iter().find(|t| t.id == id) {
+                if !timer.waker.will_wake(waker) {
+                    timer.waker = waker.clone();
+                }
+            }
+        }
+
+        fn remove(&mut self, id: u32) {
+            for slot in self.slots.iter_mut() {
+                if let Some(index) = slot.iter().position(|t| t.id == id) {
+                    slot.swap_remove(index);
+                    return;
+                }
+            }
+        }
+
+        // Wake all of the timers that have expired.
+        fn fire(&mut self) -> Vec<Waker> {
+            let now = self.tick_floor(Instant::now());
+            let mut wakers = Vec::new();
+            // Only need to go around the wheel once.
+            let start = self.tick.max(now.saturating_sub(SLOTS as u64));
+            for tick in start + 1..=now {
+                let slot = &mut self.slots[tick as usize % SLOTS];
+                let mut i = 0;
+                while i < slot.len() {
+                    if slot[i].tick <= now {
+                        wakers.push(slot.swap_remove(i).waker);
+                    } else {
+                        i += 1;
+                    }
+                }
+            }
+            self.tick = self.tick.max(now);
+            wakers
+        }
+
+        fn next_deadline(&self) -> Option<Instant> {
+            let tick = self.slots.iter().flatten().map(|t| t.tick).min()?;
+            Some(
+                self.origin
+                    + Duration::from_nanos(TICK.as_nanos() as u64 * tick),
+            )
+        }
+    }
+
+    thread_local! {
+        static WHEEL: RefCell<Wheel> = RefCell::new(Wheel::new());
+    }
+
+    /// Wake all of the timers on this thread that have expired.
+    pub(crate) fn fire() {
+        // Wake after releasing the borrow, in case a task is polled.
+        let wakers = WHEEL.with(|wheel| wheel.borrow_mut().fire());
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+
+    /// Get the deadline of the next timer to expire on this thread.
+    pub(crate) fn next_deadline() -> Option<Instant> {
+        WHEEL.with(|wheel| wheel.borrow().next_deadline())
+    }
+
+    impl Sleep {
+        pub(super) fn new(duration: Duration) -> Self {
+            Sleep {
+                deadline: Instant::now() + duration,
+                timer: None,
+            }
+        }
+
+        pub(super) fn next(&self, period: Duration) -> Self {
+            Sleep {
+                deadline: self.deadline + period,
+                timer: None,
+            }
+        }
+
+        pub(super) fn poll_internal(
+            &mut self,
+            cx: &mut Context<'_>,
+        ) -> Poll<()> {
+            if Instant::now() >= self.deadline {
+                if let Some(timer) = self.timer.take() {
+                    Self::cancel(timer);
+                }
+                return Poll::Ready(());
+            }
+            let (deadline, waker) = (self.deadline, cx.waker());
+            WHEEL.with(|wheel| {
+                let mut wheel = wheel.borrow_mut();
+                match self.timer {
+                    Some(timer) => wheel.update(timer, waker),
+                    None => {
+                        self.timer = Some(wheel.insert(deadline, waker.clone()))
+                    }
+                }
+            });
+            Poll::Pending
+        }
+
+        pub(super) fn cancel(timer: u32) {
+            // Thread local may already be destroyed at thread exit.
+            let _ = WHEEL.try_with(|wheel| wheel.borrow_mut().remove(timer));
+        }
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+mod web {
+    use super::Sleep;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::task::{Context, Poll, Waker};
+    use std::time::Duration;
+
+    #[cfg(feature = "wasm-bindgen")]
+    use wasm_bindgen::prelude::*;
+
+    #[cfg(not(feature = "wasm-bindgen"))]
+    mod ffi {
+        extern "C" {
+            pub(super) fn _cala_now() -> f64;
+            pub(super) fn _cala_set_timeout(id: u32, ms: f64);
+            pub(super) fn _cala_clear_timeout(id: u32);
+        }
+    }
+
+    #[cfg(feature = "wasm-bindgen")]
+    mod ffi {
+        use wasm_bindgen::prelude::*;
+
+        #[wasm_bindgen]
+        extern "C" {
+            pub(super) fn _cala_now() -> f64;
+            pub(super) fn _cala_set_timeout(id: u32, ms: f64);
+            pub(super) fn _cala_clear_timeout(id: u32);
+        }
+    }
+
+    struct Timers {
+        // Registered timers, and the waker if they haven't fired yet.
+        pending: HashMap<u32, Option<Waker>>,
+        next_id: u32,
+    }
+
+    thread_local! {
+        static TIMERS: RefCell<Timers> = RefCell::new(Timers {
+            pending: HashMap::new(),
+            next_id: 0,
+        });
+    }
+
+    #[allow(unused_unsafe)]
+    fn now() -> f64 {
+        unsafe { ffi::_cala_now() }
+    }
+
+    // Called from `setTimeout()` when a timer fires.
+    fn fired(id: u32) {
+        let waker = TIMERS.with(|timers| {
+            timers
+                .borrow_mut()
+                .pending
+                .get_mut(&id)
+                .and_then(Option::take)
+        });
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    #[cfg(feature = "wasm-bindgen")]
+    #[allow(unreachable_pub)]
+    /// Called by `runtime.js` when a timeout fires.
+    #[wasm_bindgen]
+    pub fn _cala_timer_fired(id: u32) {
+        fired(id);
+    }
+
+    #[cfg(not(feature = "wasm-bindgen"))]
+    #[no_mangle]
+    extern "C" fn _cala_timer_fired(id: u32) {
+        fired(id);
+    }
+
+    impl Sleep {
+        pub(super) fn new(duration: Duration) -> Self {
+            Sleep {
+                deadline: now() + duration.as_secs_f64() * 1000.0,
+                timer: None,
+            }
+        }
+
+        pub(super) fn next(&self, period: Duration) -> Self {
+            Sleep {
+                deadline: self.deadline + period.as_secs_f64() * 1000.0,
+                timer: None,
+            }
+        }
+
+        pub(super) fn poll_internal(
+            &mut self,
+            cx: &mut Context<'_>,
+        ) -> Poll<()> {
+            let timer = match self.timer {
+                Some(timer) => timer,
+                None => {
+                    let timer = TIMERS.with(|timers| {
+                        let mut timers = timers.borrow_mut();
+                        let id = timers.next_id;
+                        timers.next_id = id.wrapping_add(1);
+                        timers.pending.insert(id, Some(cx.waker().clone()));
+                        id
+                    });
+                    self.timer = Some(timer);
+                    let ms = (self.deadline - now()).max(0.0);
+                    #[allow(unused_unsafe)]
+                    unsafe {
+                        ffi::_cala_set_timeout(timer, ms)
+                    };
+                    return Poll::Pending;
+                }
+            };
+            TIMERS.with(|timers| {
+                let mut timers = timers.borrow_mut();
+                match timers.pending.get_mut(&timer) {
+                    Some(Some(waker)) => {
+                        *waker = cx.waker().clone();
+                        Poll::Pending
+                    }
+                    _ => {
+                        timers.pending.remove(&timer);
+                        self.timer = None;
+                        Poll::Ready(())
+                    }
+                }
+            })
+        }
+
+        pub(super) fn cancel(timer: u32) {
+            let fired = TIMERS.with(|timers| {
+                let waker = timers.borrow_mut().pending.remove(&timer);
+                matches!(waker, Some(None))
+            });
+            if !fired {
+                #[allow(unused_unsafe)]
+                unsafe {
+                    ffi::_cala_clear_timeout(timer)
+                };
+            }
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn timeout_elapses() {
+        crate::start::start(Box::pin(async {
+            let start = Instant::now();
+            let slow = super::sleep(Duration::from_secs(10));
+            let result = super::timeout(Duration::from_millis(50), slow).await;
+            assert_eq!(result, Err(super::Elapsed));
+            assert!(start.elapsed() >= Duration::from_millis(50));
+            assert!(start.elapsed() < Duration::from_secs(10));
+
+            let fast = async { 7 };
+            let result = super::timeout(Duration::from_millis(50), fast).await;
+            assert_eq!(result, Ok(7));
+        }));
+    }
+
+    #[test]
+    fn interval_does_not_drift() {
+        crate::start::start(Box::pin(async {
+            let start = Instant::now();
+            let mut interval = super::interval(Duration::from_millis(20));
+            for _ in 0..5 {
+                interval.tick().await;
+                // Work between ticks shouldn't push the next tick back.
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            let elapsed = start.elapsed();
+            assert!(elapsed >= Duration::from_millis(100));
+            assert!(elapsed < Duration::from_millis(140), "{:?}", elapsed);
+        }));
+    }
+
+    #[test]
+    fn sleep_cancelled_on_drop() {
+        // Poll a sleep once, so its timer gets registered.
+        struct PollOnce<'a>(Pin<&'a mut super::Sleep>);
+
+        impl Future for PollOnce<'_> {
+            type Output = ();
+
+            fn poll(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+            ) -> Poll<()> {
+                assert!(self.0.as_mut().poll(cx).is_pending());
+                Poll::Ready(())
+            }
+        }
+
+        crate::start::start(Box::pin(async {
+            let mut sleep = Box::pin(super::sleep(Duration::from_secs(10)));
+            PollOnce(sleep.as_mut()).await;
+            assert!(super::next_deadline().is_some());
+            drop(sleep);
+            assert_eq!(super::next_deadline(), None);
+        }));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[allow(unsafe_code)]
+    fn sleep_while_other_thread_parked() {
+        use crate::os::Connection;
+        use std::io::Write;
+        use std::os::unix::io::{FromRawFd, IntoRawFd};
+        use std::os::unix::net::UnixStream;
+
+        let (ours, mut peer) = UnixStream::pair().unwrap();
+        let ours = unsafe { Connection::from_raw_fd(ours.into_raw_fd()) };
+        let parked = std::thread::spawn(move || {
+            // Park after this thread's timer is set, without a deadline.
+            std::thread::sleep(Duration::from_millis(20));
+            crate::start::start(Box::pin(async move {
+                ours.readable().await.unwrap();
+            }));
+        });
+        let start = Instant::now();
+        crate::start::start(Box::pin(async {
+            super::sleep(Duration::from_millis(100)).await;
+        }));
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        peer.write_all(b"x").unwrap();
+        parked.join().unwrap();
+    }
+}
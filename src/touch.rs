@@ -0,0 +1,540 @@
+// cala_core
+//
+// Copyright (c) 2020 Jeron Aldaron Lau
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// https://apache.org/licenses/LICENSE-2.0>, or the Zlib License, <LICENSE-ZLIB
+// or http://opensource.org/licenses/Zlib>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Multi-touch input.
+//!
+//! Touches come from touch pointer events on the web, and from touchscreens
+//! in `/dev/input` on Linux.  Other platforms only get events sent with
+//! [`inject()`].
+//!
+//! On the web, the browser cancels touches that it uses for scrolling or
+//! zooming, unless the page sets the CSS `touch-action: none`.
+//!
+//! ```rust,no_run
+//! use cala_core::touch::{Phase, Touch};
+//!
+//! async fn count_fingers() {
+//!     let mut touch = Touch::new();
+//!     let mut fingers = Vec::new();
+//!     loop {
+//!         let event = touch.recv().await;
+//!         match event.phase {
+//!             Phase::Begin => fingers.push(event.id),
+//!             Phase::Move => continue,
+//!             Phase::End | Phase::Cancel => {
+//!                 fingers.retain(|id| *id != event.id)
+//!             }
+//!         }
+//!         println!("{} fingers", fingers.len());
+//!     }
+//! }
+//! ```
+
+use crate::input::{Channel, Receiver};
+
+/// Which part of a touch an event is.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Phase {
+    /// A finger touched the screen.
+    Begin,
+    /// A finger moved (or pressed harder).
+    Move,
+    /// A finger was lifted.
+    End,
+    /// The touch was interrupted, and should be ignored (undo what it did).
+    Cancel,
+}
+
+/// A finger touching, moving or lifting.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TouchEvent {
+    /// Which finger (unique among fingers touching at the same time, but may
+    /// be reused after the touch ends)
+    pub id: u64,
+    /// Which part of the touch
+    pub phase: Phase,
+    /// Horizontal position
+    pub x: f64,
+    /// Vertical position
+    pub y: f64,
+    /// How hard the finger is pressing, from 0.0 to 1.0 (0.5 if the screen
+    /// can't tell, and 0.0 when the touch ends)
+    pub pressure: f64,
+}
+
+static EVENTS: Channel<TouchEvent> = Channel::new();
+
+/// Stream of touch events, from every touchscreen.
+///
+/// Positions are in CSS pixels from the top left of the page on the web.  On
+/// Linux, they're from 0.0 to 1.0 across the touchscreen.
+#[derive(Debug)]
+pub struct Touch(Receiver<TouchEvent>);
+
+impl Touch {
+    /// Start receiving touch events, from events that happen after this call.
+    pub fn new() -> Self {
+        #[cfg(target_os = "linux")]
+        crate::input::evdev::listen(
+            crate::input::evdev::Class::Touch,
+            &native::HANDLER,
+        );
+        #[cfg(target_arch = "wasm32")]
+        web::listen();
+        Touch(EVENTS.subscribe())
+    }
+
+    /// Wait for the next touch event.
+    pub async fn recv(&mut self) -> TouchEvent {
+        self.0.recv().await
+    }
+
+    /// Get the next touch event, if one has already happened.
+    pub fn try_recv(&mut self) -> Option<TouchEvent> {
+        self.0.try_recv()
+    }
+}
+
+impl Default for Touch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Send a synthetic touch event to every [`Touch`], as if it came from a real
+/// touchscreen (for testing).
+pub fn inject(event: TouchEvent) {
+    EVENTS.send(event);
+}
+
+#[cfg(target_os = "linux")]
+mod native {
+    use super::{inject, Phase, TouchEvent};
+    use crate::input::evdev::{
+        Device, Handler, InputEvent, ABS_MT_POSITION_X, ABS_MT_POSITION_Y,
+        ABS_MT_PRESSURE, ABS_MT_SLOT, ABS_MT_TRACKING_ID, EV_ABS, EV_SYN,
+        SYN_DROPPED, SYN_REPORT,
+    };
+    use std::convert::TryFrom;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Mutex;
+
+    // Slots past this many are ignored (touchscreens have about 10).
+    const MAX_SLOTS: usize = 64;
+
+    // IDs are given out in order, so they're unique across touchscreens.
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+    /// Range of an axis.
+    #[derive(Copy, Clone, Debug)]
+    pub(super) struct Range {
+        pub(super) minimum: i32,
+        pub(super) maximum: i32,
+    }
+
+    impl Range {
+        // Scale a value in the range to 0.0 through 1.0.
+        fn scale(self, value: i32) -> f64 {
+            let size = f64::from(self.maximum) - f64::from(self.minimum);
+            if size <= 0.0 {
+                return 0.0;
+            }
+            (f64::from(value) - f64::from(self.minimum)) / size
+        }
+    }
+
+    // A finger, tracked by the touchscreen (`ABS_MT_SLOT`).  The position
+    // and pressure are kept after the finger is lifted, since the next touch
+    // in the slot only sends the values that changed.
+    #[derive(Copy, Clone, Default)]
+    struct Slot {
+        id: Option<u64>,
+        x: i32,
+        y: i32,
+        pressure: i32,
+        began: bool,
+        moved: bool,
+    }
+
+    /// Decodes multi-touch protocol B into touch events.
+    pub(super) struct Decoder {
+        x: Range,
+        y: Range,
+        pressure: Option<Range>,
+        slot: usize,
+        slots: Vec<Slot>,
+        // Events are dropped until the next `SYN_REPORT` after `SYN_DROPPED`.
+        dropped: bool,
+        // Touches that ended in the current frame.
+        ended: Vec<TouchEvent>,
+    }
+
+    impl Decoder {
+        pub(super) fn new(x: Range, y: Range, pressure: Option<Range>) -> Self {
+            Decoder {
+                x,
+                y,
+                pressure,
+                slot: 0,
+                slots: Vec::new(),
+                dropped: false,
+                ended: Vec::new(),
+            }
+        }
+
+        fn touch(&self, slot: &Slot, id: u64, phase: Phase) -> TouchEvent {
+            let pressure = match phase {
+                Phase::End | Phase::Cancel => 0.0,
+                _ => self.pressure.map_or(0.5, |p| p.scale(slot.pressure)),
+            };
+            TouchEvent {
+                id,
+                phase,
+                x: self.x.scale(slot.x),
+                y: self.y.scale(slot.y),
+                pressure,
+            }
+        }
+
+        /// Decode an event, returning the touch events at the end of each
+        /// frame (`SYN_REPORT`).
+        pub(super) fn event(&mut self, event: InputEvent) -> Vec<TouchEvent> {
+            match (event.kind, event.code) {
+                (EV_SYN, SYN_REPORT) if self.dropped => self.dropped = false,
+                (EV_SYN, SYN_REPORT) => return self.report(),
+                (EV_SYN, SYN_DROPPED) => return self.cancel(),
+                _ if self.dropped => {}
+                (EV_ABS, ABS_MT_SLOT) => {
+                    self.slot = usize::try_from(event.value)
+                        .unwrap_or(MAX_SLOTS)
+                        .min(MAX_SLOTS);
+                }
+                (EV_ABS, code) if self.slot < MAX_SLOTS => {
+                    if self.slots.len() <= self.slot {
+                        self.slots.resize(self.slot + 1, Slot::default());
+                    }
+                    self.axis(code, event.value);
+                }
+                _ => {}
+            }
+            Vec::new()
+        }
+
+        // Update an axis of the current slot.
+        fn axis(&mut self, code: u16, value: i32) {
+            let mut slot = self.slots[self.slot];
+            match code {
+                ABS_MT_TRACKING_ID => {
+                    if let Some(id) = slot.id.take() {
+                        // A touch that ends in the frame it began in still
+                        // gets both events.
+                        if slot.began {
+                            self.ended.push(self.touch(
+                                &slot,
+                                id,
+                                Phase::Begin,
+                            ));
+                        }
+                        self.ended.push(self.touch(&slot, id, Phase::End));
+                    }
+                    // -1 means the finger was lifted.
+                    if value >= 0 {
+                        slot.id = Some(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+                    }
+                    slot.began = slot.id.is_some();
+                    slot.moved = false;
+                }
+                ABS_MT_POSITION_X => {
+                    slot.x = value;
+                    slot.moved = true;
+                }
+                ABS_MT_POSITION_Y => {
+                    slot.y = value;
+                    slot.moved = true;
+                }
+                ABS_MT_PRESSURE => {
+                    slot.pressure = value;
+                    slot.moved = true;
+                }
+                _ => {}
+            }
+            self.slots[self.slot] = slot;
+        }
+
+        // End a frame, returning what happened in it.
+        fn report(&mut self) -> Vec<TouchEvent> {
+            let mut events = std::mem::take(&mut self.ended);
+            for i in 0..self.slots.len() {
+                let slot = self.slots[i];
+                if let Some(id) = slot.id {
+                    if slot.began {
+                        events.push(self.touch(&slot, id, Phase::Begin));
+                    } else if slot.moved {
+                        events.push(self.touch(&slot, id, Phase::Move));
+                    }
+                }
+                self.slots[i].began = false;
+                self.slots[i].moved = false;
+            }
+            events
+        }
+
+        /// Cancel every touch (the events since the last frame were lost, or
+        /// the touchscreen was unplugged).  Fingers still touching are
+        /// ignored until they're lifted.
+        pub(super) fn cancel(&mut self) -> Vec<TouchEvent> {
+            self.dropped = true;
+            // Touches that ended in the lost frame are canceled instead,
+            // unless they also began in it.
+            let ended = std::mem::take(&mut self.ended);
+            let mut events: Vec<TouchEvent> = ended
+                .iter()
+                .filter(|end| {
+                    end.phase == Phase::End
+                        && !ended.iter().any(|begin| {
+                            begin.phase == Phase::Begin && begin.id == end.id
+                        })
+                })
+                .map(|end| TouchEvent {
+                    phase: Phase::Cancel,
+                    ..*end
+                })
+                .collect();
+            for i in 0..self.slots.len() {
+                let slot = self.slots[i];
+                if let Some(id) = slot.id {
+                    // Touches that didn't begin yet weren't sent.
+                    if !slot.began {
+                        events.push(self.touch(&slot, id, Phase::Cancel));
+                    }
+                }
+                self.slots[i] = Slot::default();
+            }
+            events
+        }
+    }
+
+    pub(super) struct Touchscreens(Mutex<Vec<(PathBuf, Decoder)>>);
+
+    pub(super) static HANDLER: Touchscreens =
+        Touchscreens(Mutex::new(Vec::new()));
+
+    impl Touchscreens {
+        fn lock(&self) -> std::sync::MutexGuard<'_, Vec<(PathBuf, Decoder)>> {
+            self.0.lock().unwrap_or_else(|poison| poison.into_inner())
+        }
+    }
+
+    impl Handler for Touchscreens {
+        fn connect(&self, device: &Device) {
+            let range = |axis| {
+                let info = device.abs_info(axis).unwrap_or_default();
+                Range {
+                    minimum: info.minimum,
+                    maximum: info.maximum,
+                }
+            };
+            let pressure = device.abs_info(ABS_MT_PRESSURE).map(|info| Range {
+                minimum: info.minimum,
+                maximum: info.maximum,
+            });
+            let mut decoder = Decoder::new(
+                range(ABS_MT_POSITION_X),
+                range(ABS_MT_POSITION_Y),
+                pressure,
+            );
+            decoder.slot = device
+                .abs_info(ABS_MT_SLOT)
+                .and_then(|info| usize::try_from(info.value).ok())
+                .unwrap_or(0);
+            self.lock().push((device.path().to_path_buf(), decoder));
+        }
+
+        fn event(&self, device: &Device, event: InputEvent) {
+            let events = match self
+                .lock()
+                .iter_mut()
+                .find(|(path, _)| path == device.path())
+            {
+                Some((_, decoder)) => decoder.event(event),
+                None => return,
+            };
+            for event in events {
+                inject(event);
+            }
+        }
+
+        fn disconnect(&self, device: &Device) {
+            let events = {
+                let mut decoders = self.lock();
+                let i = match decoders
+                    .iter()
+                    .position(|(path, _)| path == device.path())
+                {
+                    Some(i) => i,
+                    None => return,
+                };
+                decoders.swap_remove(i).1.cancel()
+            };
+            for event in events {
+                inject(event);
+            }
+        }
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+#[allow(unsafe_code)]
+mod web {
+    use super::{inject, Phase, TouchEvent};
+    use crate::os::web::JsVar;
+    use std::sync::Once;
+
+    #[cfg(feature = "wasm-bindgen")]
+    use wasm_bindgen::prelude::*;
+
+    #[cfg(not(feature = "wasm-bindgen"))]
+    mod ffi {
+        extern "C" {
+            pub(super) fn _cala_touch_listen();
+        }
+    }
+
+    #[cfg(feature = "wasm-bindgen")]
+    mod ffi {
+        use wasm_bindgen::prelude::*;
+
+        #[wasm_bindgen]
+        extern "C" {
+            pub(super) fn _cala_touch_listen();
+        }
+    }
+
+    #[allow(unused_unsafe)]
+    pub(super) fn listen() {
+        static LISTEN: Once = Once::new();
+        LISTEN.call_once(|| unsafe { ffi::_cala_touch_listen() });
+    }
+
+    // Handle a DOM event, stored as `[phase, id, x, y, pressure]` in heap
+    // slot `event`.  Phases are 0 to 3 for begin, move, end and cancel.
+    fn event(event: u32) {
+        let event = unsafe { JsVar::from_raw(event) };
+        let (phase, id, x, y, pressure) =
+            match event.get::<(u8, f64, f64, f64, f64)>() {
+                Ok(event) => event,
+                Err(_) => return,
+            };
+        inject(TouchEvent {
+            id: id as u64,
+            phase: match phase {
+                0 => Phase::Begin,
+                1 => Phase::Move,
+                2 => Phase::End,
+                _ => Phase::Cancel,
+            },
+            x,
+            y,
+            pressure,
+        });
+    }
+
+    #[cfg(feature = "wasm-bindgen")]
+    #[allow(unreachable_pub)]
+    /// Called by `runtime.js` when a touch pointer event happens.
+    #[wasm_bindgen]
+    pub fn _cala_touch_event(slot: u32) {
+        event(slot);
+    }
+
+    #[cfg(not(feature = "wasm-bindgen"))]
+    #[no_mangle]
+    extern "C" fn _cala_touch_event(slot: u32) {
+        event(slot);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inject_reaches_touch() {
+        let mut touch = Touch::new();
+        let event = TouchEvent {
+            id: u64::MAX,
+            phase: Phase::Begin,
+            x: 0.5,
+            y: 0.25,
+            pressure: 1.0,
+        };
+        inject(event);
+        assert_eq!(touch.try_recv(), Some(event));
+        assert_eq!(touch.try_recv(), None);
+    }
+
+    // Decode a recording of a touchscreen (`res/test/touch.bin`, with 64-bit
+    // little endian `struct input_event`s).
+    #[cfg(all(
+        target_os = "linux",
+        target_pointer_width = "64",
+        target_endian = "little"
+    ))]
+    #[test]
+    fn decode_recording() {
+        use crate::input::evdev::InputEvent;
+        use native::{Decoder, Range};
+
+        let recording = include_bytes!("../res/test/touch.bin");
+        let range = |maximum| Range {
+            minimum: 0,
+            maximum,
+        };
+        let mut decoder =
+            Decoder::new(range(1000), range(2000), Some(range(255)));
+        let frames: Vec<Vec<TouchEvent>> = InputEvent::parse(recording)
+            .map(|event| decoder.event(event))
+            .filter(|frame| !frame.is_empty())
+            .collect();
+
+        let touch = |id, phase, x, y, pressure| TouchEvent {
+            id,
+            phase,
+            x,
+            y,
+            pressure,
+        };
+        let first = frames[0][0].id;
+        let (second, tap) = (frames[1][1].id, frames[3][0].id);
+        assert!(first != second && first != tap && second != tap);
+        assert_eq!(
+            frames,
+            [
+                vec![touch(first, Phase::Begin, 0.25, 0.25, 0.2)],
+                vec![
+                    touch(first, Phase::Move, 0.5, 0.25, 0.2),
+                    touch(second, Phase::Begin, 0.75, 0.5, 0.4),
+                ],
+                vec![
+                    touch(first, Phase::End, 0.5, 0.25, 0.0),
+                    touch(second, Phase::Move, 0.75, 0.75, 0.4),
+                ],
+                // Taps that begin and end in one frame still begin.
+                vec![
+                    touch(tap, Phase::Begin, 0.0, 0.0, 0.2),
+                    touch(tap, Phase::End, 0.0, 0.0, 0.0),
+                ],
+                // Events were dropped, so the second finger is canceled,
+                // and ignored until it's lifted.
+                vec![touch(second, Phase::Cancel, 0.75, 0.75, 0.0)],
+            ]
+        );
+    }
+}